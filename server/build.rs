@@ -1,3 +1,10 @@
+use std::{env, path::PathBuf};
+
 fn main() {
-    tonic_build::compile_protos("../proto/bouncer.proto").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("bouncer_descriptor.bin"))
+        .compile(&["../proto/bouncer.proto"], &["../proto"])
+        .unwrap();
 }
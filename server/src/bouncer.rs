@@ -1,51 +1,621 @@
-use futures::{future, select, stream, FutureExt, StreamExt};
-use tokio::io::Result;
+use std::collections::HashMap;
 
+use futures::{select, stream, FutureExt, StreamExt};
+use log::{error, info};
+use tokio::{
+    io::Result,
+    sync::{
+        mpsc::{channel, error::TrySendError, Sender},
+        watch,
+    },
+    task::{spawn, spawn_blocking, JoinHandle},
+    time::interval,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::config::{AutoAway, AutoJoin, Config, Ctcp, Network, Slack, User};
+use crate::control::{self, Control, Controller, NetworkCommand, NetworkState, Reply};
 use crate::grpc;
 use crate::history::History;
-use crate::irc;
-use crate::message::Message;
+use crate::irc::{self, Casemapping, ISupport};
+use crate::message::{Envelope, Message, Origin, Snapshot};
 use crate::sink::Sink;
+use crate::slack;
 use crate::source::Source;
 
+enum NetworkEvent {
+    Connected,
+    Disconnected,
+    Message(Envelope),
+    // autojoin changed by JOIN, PART or KICK
+    Channels(Vec<AutoJoin>),
+    // from ISUPPORT, for comparing autojoin channels
+    Casemapping(Casemapping),
+}
+
+// requests from bouncer to network task
+enum NetworkRequest {
+    Message(Message, Option<Origin>),
+    Snapshot(Reply<control::Result<Snapshot>>),
+    Channels(Vec<AutoJoin>),
+}
+
+struct NetworkHandle {
+    id: u64,
+    state: NetworkState,
+    casemapping: Casemapping,
+    sender: Sender<NetworkRequest>,
+    task: JoinHandle<()>,
+}
+
+impl NetworkHandle {
+    // network task may be waiting for the main loop to take its events, so waiting for it here could stall both
+    fn request(&self, request: NetworkRequest) {
+        // network task is gone if the queue is closed, which will be reported as disconnected event
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(request) {
+            error!("Network queue is full, dropping request");
+        }
+    }
+}
+
 pub struct Bouncer {
-    source: Box<dyn Source>,
-    sinks: Vec<Box<dyn Sink>>,
+    config: Config,
+    config_path: Option<String>,
+    networks: HashMap<String, NetworkHandle>,
+    network_id: u64,
+    events: Sender<(u64, NetworkEvent)>,
+    states: watch::Sender<HashMap<String, NetworkState>>,
+    // number of attached irc clients
+    attached: watch::Receiver<usize>,
 }
 
 impl Bouncer {
-    pub async fn run(host: String, port: u16, server_port: u16) -> Result<()> {
-        let client = Box::new(irc::Client::new(host, port).await.unwrap());
+    pub async fn run(config: Config, config_path: Option<String>) -> Result<()> {
+        let (control_sender, control_receiver) = channel(16);
+        let (events_sender, events_receiver) = channel(64);
+        let (states_sender, states_receiver) = watch::channel(HashMap::new());
+        let (attached_sender, attached_receiver) = watch::channel(0);
+
+        let controller = Controller::new(control_sender);
         let sinks: Vec<Box<dyn Sink>> = vec![
-            Box::new(irc::Server::new(server_port).await.unwrap()),
+            Box::new(
+                irc::Server::new(config.server_port, config.client_queue, attached_sender, controller.clone())
+                    .await
+                    .unwrap(),
+            ),
             Box::new(History::new()),
-            Box::new(grpc::Server::new(12345)),
+            Box::new(grpc::Server::new(config.grpc_port, controller, states_receiver)),
         ];
 
-        let bouncer = Self { source: client, sinks };
+        let mut bouncer = Self {
+            config,
+            config_path,
+            networks: HashMap::new(),
+            network_id: 0,
+            events: events_sender,
+            states: states_sender,
+            attached: attached_receiver,
+        };
+
+        // plaintext passwords of config file aren't kept
+        if bouncer.config.hash_passwords() {
+            if let Err(err) = bouncer.save_config() {
+                error!("Saving hashed passwords failed: {:?}", err);
+            }
+        }
+
+        for network in bouncer.config.networks.clone() {
+            bouncer.connect(&network);
+        }
+        bouncer.publish_states();
 
-        let mut source_stream = bouncer.source.stream().await.fuse();
-        let mut sinks_stream = stream::select_all(bouncer.sinks.iter().map(|x| x.stream())).fuse();
+        let mut events_stream = ReceiverStream::new(events_receiver).fuse();
+        let mut control_stream = ReceiverStream::new(control_receiver).fuse();
+        let mut sinks_stream = stream::select_all(sinks.iter().map(|x| x.stream())).fuse();
 
         loop {
             let res = select! {
-                message = source_stream.next() => bouncer.handle_source_message(message.unwrap()).boxed(),
+                event = events_stream.next() => {
+                    let (id, event) = event.unwrap();
+                    bouncer.handle_network_event(&sinks, id, event).boxed()
+                },
                 message = sinks_stream.next() => bouncer.handle_sink_message(message.unwrap()).boxed(),
+                control = control_stream.next() => bouncer.handle_control(&sinks, control.unwrap()).boxed(),
             };
 
             res.await?;
         }
     }
 
-    async fn handle_source_message(&self, message: Message) -> Result<()> {
-        let futures = self.sinks.iter().map(|x| x.broadcast(&message));
+    async fn handle_network_event(&mut self, sinks: &[Box<dyn Sink>], id: u64, event: NetworkEvent) -> Result<()> {
+        // events from stopped network tasks are stale
+        let (name, network) = match self.networks.iter_mut().find(|(_, x)| x.id == id) {
+            Some(network) => network,
+            None => return Ok(()),
+        };
+
+        match event {
+            NetworkEvent::Connected => {
+                network.state = NetworkState::Connected;
+                self.publish_states();
+            }
+            NetworkEvent::Disconnected => {
+                network.state = NetworkState::Disconnected;
+                self.publish_states();
+            }
+            NetworkEvent::Message(envelope) => {
+                let futures = sinks.iter().map(|x| x.broadcast(&envelope));
+
+                // a failing sink shouldn't keep the message from the others
+                for result in futures::future::join_all(futures).await {
+                    if let Err(err) = result {
+                        error!("Sink broadcast failed: {}", err);
+                    }
+                }
+            }
+            NetworkEvent::Channels(channels) => {
+                if let Some(config) = self.config.networks.iter_mut().find(|x| x.name == *name) {
+                    config.autojoin = channels;
+                }
+
+                if let Err(err) = self.save_config() {
+                    error!("Saving autojoin failed: {:?}", err);
+                }
+            }
+            NetworkEvent::Casemapping(casemapping) => network.casemapping = casemapping,
+        }
+
+        Ok(())
+    }
+
+    // empty network means first connected network
+    fn connected_network(&self, name: &str) -> Option<&NetworkHandle> {
+        if name.is_empty() {
+            self.config
+                .networks
+                .iter()
+                .filter_map(|x| self.networks.get(&x.name))
+                .find(|x| x.state == NetworkState::Connected)
+        } else {
+            self.networks.get(name).filter(|x| x.state == NetworkState::Connected)
+        }
+    }
+
+    async fn handle_sink_message(&self, envelope: Envelope) -> Result<()> {
+        if let Some(network) = self.connected_network(&envelope.network) {
+            network.request(NetworkRequest::Message(envelope.message, envelope.origin));
+        } else {
+            error!("No connected network to send message from {:?}", envelope.origin);
+        }
+
+        Ok(())
+    }
+
+    async fn handle_control(&mut self, sinks: &[Box<dyn Sink>], control: Control) -> Result<()> {
+        // reply failure means requester is gone, so it's safe to ignore
+        match control {
+            Control::Authenticate { name, password, reply } => {
+                let user = self.config.user(&name).cloned();
+
+                // hashing is slow on purpose, so it's kept off the main loop
+                spawn_blocking(move || {
+                    let _ = reply.send(user.filter(|x| x.verify_password(&password)));
+                });
+            }
+            Control::GetUser { name, reply } => {
+                let _ = reply.send(self.config.user(&name).cloned());
+            }
+            Control::ListUsers { reply } => {
+                let _ = reply.send(self.config.users.clone());
+            }
+            Control::CreateUser { user, reply } => {
+                let _ = reply.send(self.create_user(user));
+            }
+            Control::UpdateUser { user, reply } => {
+                let _ = reply.send(self.update_user(user));
+            }
+            Control::DeleteUser { name, reply } => {
+                let _ = reply.send(self.delete_user(&name));
+            }
+            Control::ListNetworks { reply } => {
+                let networks = self.config.networks.iter().map(|x| (x.clone(), self.network_state(&x.name))).collect();
+
+                let _ = reply.send(networks);
+            }
+            Control::CreateNetwork { network, reply } => {
+                let _ = reply.send(self.create_network(network));
+            }
+            Control::UpdateNetwork { network, reply } => {
+                let _ = reply.send(self.update_network(network));
+            }
+            Control::DeleteNetwork { name, reply } => {
+                let _ = reply.send(self.delete_network(&name));
+            }
+            Control::NetworkCommand { name, command, reply } => {
+                let _ = reply.send(self.network_command(&name, command));
+            }
+            Control::ListSessions { reply } => {
+                let mut sessions = Vec::new();
+                for sink in sinks {
+                    sessions.extend(sink.sessions().await);
+                }
+
+                let _ = reply.send(sessions);
+            }
+            Control::KickSession { id, reason, reply } => {
+                let mut kicked = false;
+                for sink in sinks {
+                    kicked |= sink.kick(id, &reason).await;
+                }
+
+                let _ = reply.send(if kicked { Ok(()) } else { Err(control::Error::NotFound) });
+            }
+            Control::ListAutoJoin { network, reply } => {
+                let channels = self.autojoin_network(&network).map(|x| x.autojoin.clone());
+
+                let _ = reply.send(channels);
+            }
+            Control::AddAutoJoin { network, channel, reply } => {
+                let result = self
+                    .update_autojoin(&network, |channels, casemapping| {
+                        channels.retain(|x| !casemapping.eq_ignore_case(&x.channel, &channel.channel));
+                        channels.push(channel);
+
+                        Ok(())
+                    })
+                    .await;
+
+                let _ = reply.send(result);
+            }
+            Control::RemoveAutoJoin { network, channel, reply } => {
+                let result = self
+                    .update_autojoin(&network, |channels, casemapping| {
+                        let len = channels.len();
+                        channels.retain(|x| !casemapping.eq_ignore_case(&x.channel, &channel));
+
+                        if channels.len() == len {
+                            Err(control::Error::NotFound)
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .await;
+
+                let _ = reply.send(result);
+            }
+            Control::Snapshot { network, reply } => match self.connected_network(&network) {
+                // network task replies directly, dropped reply is reported as closed
+                Some(network) => {
+                    network.request(NetworkRequest::Snapshot(reply));
+                }
+                None => {
+                    let _ = reply.send(Err(control::Error::NotFound));
+                }
+            },
+        }
+
+        self.publish_states();
+
+        Ok(())
+    }
+
+    fn create_user(&mut self, mut user: User) -> control::Result<()> {
+        if self.config.user(&user.name).is_some() {
+            return Err(control::Error::AlreadyExists);
+        }
+
+        user.hash_password();
+        self.config.users.push(user);
+
+        self.save_config()
+    }
+
+    fn update_user(&mut self, user: User) -> control::Result<()> {
+        let existing = self
+            .config
+            .users
+            .iter_mut()
+            .find(|x| x.name == user.name)
+            .ok_or(control::Error::NotFound)?;
+
+        // empty password means unchanged
+        if !user.password.is_empty() {
+            existing.password = user.password;
+            existing.hash_password();
+        }
+        existing.admin = user.admin;
+
+        self.save_config()
+    }
+
+    fn delete_user(&mut self, name: &str) -> control::Result<()> {
+        let index = self.config.users.iter().position(|x| x.name == name).ok_or(control::Error::NotFound)?;
+
+        self.config.users.remove(index);
+
+        self.save_config()
+    }
+
+    fn create_network(&mut self, network: Network) -> control::Result<()> {
+        if self.config.network(&network.name).is_some() {
+            return Err(control::Error::AlreadyExists);
+        }
+
+        self.connect(&network);
+        self.config.networks.push(network);
+
+        self.save_config()
+    }
+
+    fn update_network(&mut self, network: Network) -> control::Result<()> {
+        let existing = self
+            .config
+            .networks
+            .iter_mut()
+            .find(|x| x.name == network.name)
+            .ok_or(control::Error::NotFound)?;
+
+        // nickserv password and slack tokens are not exposed over gRPC
+        let mut network = network;
+        if network.nickserv.is_none() {
+            network.nickserv = existing.nickserv.clone();
+        }
+        if network.slack.is_none() {
+            network.slack = existing.slack.clone();
+        }
+
+        *existing = network.clone();
+
+        if self.networks.contains_key(&network.name) {
+            self.disconnect(&network.name);
+            self.connect(&network);
+        }
+
+        self.save_config()
+    }
+
+    fn delete_network(&mut self, name: &str) -> control::Result<()> {
+        let index = self.config.networks.iter().position(|x| x.name == name).ok_or(control::Error::NotFound)?;
+
+        self.disconnect(name);
+        self.config.networks.remove(index);
+
+        self.save_config()
+    }
+
+    fn network_command(&mut self, name: &str, command: NetworkCommand) -> control::Result<()> {
+        let network = self.config.network(name).ok_or(control::Error::NotFound)?.clone();
+
+        match command {
+            // handle of exited task stays around as disconnected
+            NetworkCommand::Connect => {
+                if self.network_state(name) == NetworkState::Disconnected {
+                    self.networks.remove(name);
+                    self.connect(&network);
+                }
+            }
+            NetworkCommand::Disconnect => self.disconnect(name),
+            NetworkCommand::Reconnect => {
+                self.disconnect(name);
+                self.connect(&network);
+            }
+        }
+
+        Ok(())
+    }
+
+    // empty name means first connected network, or first network if none is connected
+    fn autojoin_network(&self, name: &str) -> control::Result<&Network> {
+        let network = if name.is_empty() {
+            let networks = &self.config.networks;
+
+            networks
+                .iter()
+                .find(|x| self.network_state(&x.name) == NetworkState::Connected)
+                .or_else(|| networks.first())
+        } else {
+            self.config.network(name)
+        };
+
+        network.ok_or(control::Error::NotFound)
+    }
+
+    // channel names are compared in casemapping of the network, or the default one if it never connected
+    async fn update_autojoin<F>(&mut self, name: &str, f: F) -> control::Result<()>
+    where
+        F: FnOnce(&mut Vec<AutoJoin>, Casemapping) -> control::Result<()>,
+    {
+        let name = self.autojoin_network(name)?.name.clone();
+        let casemapping = self
+            .networks
+            .get(&name)
+            .map(|x| x.casemapping)
+            .unwrap_or_else(|| ISupport::default().casemapping);
+        let network = self.config.networks.iter_mut().find(|x| x.name == name).ok_or(control::Error::NotFound)?;
+
+        f(&mut network.autojoin, casemapping)?;
+        let channels = network.autojoin.clone();
+
+        if let Some(handle) = self.networks.get(&name) {
+            handle.request(NetworkRequest::Channels(channels));
+        }
+
+        self.save_config()
+    }
+
+    fn publish_states(&self) {
+        let states = self
+            .config
+            .networks
+            .iter()
+            .map(|x| (x.name.clone(), self.network_state(&x.name)))
+            .collect::<HashMap<_, _>>();
+
+        if *self.states.borrow() != states {
+            // no receiver is not an error
+            let _ = self.states.send(states);
+        }
+    }
+
+    fn network_state(&self, name: &str) -> NetworkState {
+        self.networks.get(name).map(|x| x.state).unwrap_or(NetworkState::Disconnected)
+    }
+
+    fn connect(&mut self, network: &Network) {
+        let id = self.network_id;
+        self.network_id += 1;
+
+        let (sender, receiver) = channel(64);
+        let events = self.events.clone();
+        let network_config = network.clone();
+        let ctcp = self.config.ctcp.clone();
+        let auto_away = self.config.auto_away.clone();
+        let attached = self.attached.clone();
 
-        future::try_join_all(futures).await?;
+        let task = spawn(async move {
+            let receiver = ReceiverStream::new(receiver);
+
+            let result = match network_config.slack.clone() {
+                Some(slack) => Self::slack_loop(id, network_config, slack, receiver, &events).await,
+                None => Self::network_loop(id, network_config, ctcp, auto_away, attached, receiver, &events).await,
+            };
+
+            if let Err(err) = result {
+                error!("Network error: {}", err);
+            }
+
+            let _ = events.send((id, NetworkEvent::Disconnected)).await;
+        });
+
+        info!("Connecting to {}", network.name);
+        self.networks.insert(
+            network.name.clone(),
+            NetworkHandle {
+                id,
+                state: NetworkState::Connecting,
+                casemapping: ISupport::default().casemapping,
+                sender,
+                task,
+            },
+        );
+    }
+
+    fn disconnect(&mut self, name: &str) {
+        if let Some(network) = self.networks.remove(name) {
+            info!("Disconnecting from {}", name);
+
+            network.task.abort();
+        }
+    }
+
+    async fn network_loop(
+        id: u64,
+        network: Network,
+        ctcp: Ctcp,
+        auto_away: AutoAway,
+        mut attached: watch::Receiver<usize>,
+        receiver: ReceiverStream<NetworkRequest>,
+        events: &Sender<(u64, NetworkEvent)>,
+    ) -> Result<()> {
+        let client = irc::Client::new(&network, ctcp, auto_away, attached.clone()).await?;
+
+        // bouncer is shutting down if send fails
+        let _ = events.send((id, NetworkEvent::Connected)).await;
+
+        let mut source_stream = client.stream().await.fuse();
+        let mut receiver = receiver.fuse();
+        let mut regain = interval(irc::Client::REGAIN_INTERVAL);
+        let mut casemapping = ISupport::default().casemapping;
+
+        loop {
+            select! {
+                message = source_stream.next() => match message {
+                    Some(message) => {
+                        let _ = events.send((id, NetworkEvent::Message(message))).await;
+
+                        if let Some(channels) = client.take_channels().await {
+                            let _ = events.send((id, NetworkEvent::Channels(channels))).await;
+                        }
+
+                        let current = client.casemapping().await;
+                        if current != casemapping {
+                            casemapping = current;
+                            let _ = events.send((id, NetworkEvent::Casemapping(casemapping))).await;
+                        }
+                    }
+                    None => break,
+                },
+                request = receiver.next() => match request {
+                    Some(NetworkRequest::Message(message, origin)) => {
+                        if let Some(echo) = client.send_message(&message, origin).await? {
+                            let _ = events.send((id, NetworkEvent::Message(echo))).await;
+                        }
+                    }
+                    Some(NetworkRequest::Snapshot(reply)) => {
+                        let _ = reply.send(Ok(client.snapshot().await));
+                    }
+                    Some(NetworkRequest::Channels(channels)) => client.set_channels(channels).await?,
+                    None => break,
+                },
+                changed = attached.changed().fuse() => match changed {
+                    Ok(()) => client.update_away().await?,
+                    // irc server is gone, so is the bouncer
+                    Err(_) => break,
+                },
+                _ = regain.tick().fuse() => client.regain().await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // autojoin and away are irc only, slack keeps them itself
+    async fn slack_loop(
+        id: u64,
+        network: Network,
+        slack: Slack,
+        receiver: ReceiverStream<NetworkRequest>,
+        events: &Sender<(u64, NetworkEvent)>,
+    ) -> Result<()> {
+        let client = slack::Client::new(&network.name, &slack).await?;
+
+        // bouncer is shutting down if send fails
+        let _ = events.send((id, NetworkEvent::Connected)).await;
+
+        let mut source_stream = client.stream().await.fuse();
+        let mut receiver = receiver.fuse();
+
+        loop {
+            select! {
+                message = source_stream.next() => match message {
+                    Some(message) => {
+                        let _ = events.send((id, NetworkEvent::Message(message))).await;
+                    }
+                    None => break,
+                },
+                request = receiver.next() => match request {
+                    Some(NetworkRequest::Message(message, origin)) => {
+                        if let Some(echo) = client.send_message(&message, origin).await? {
+                            let _ = events.send((id, NetworkEvent::Message(echo))).await;
+                        }
+                    }
+                    Some(NetworkRequest::Snapshot(reply)) => {
+                        let _ = reply.send(Ok(client.snapshot().await));
+                    }
+                    Some(NetworkRequest::Channels(_)) => {}
+                    None => break,
+                },
+            }
+        }
 
         Ok(())
     }
 
-    async fn handle_sink_message(&self, message: Message) -> Result<()> {
-        self.source.send_message(&message).await
+    fn save_config(&self) -> control::Result<()> {
+        if let Some(path) = &self.config_path {
+            self.config.save(path).map_err(control::Error::Io)?;
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,571 @@
+use std::{fs, io, path::Path};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub name: String,
+    // argon2 hash, or plaintext until hashed on load
+    pub password: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl User {
+    pub fn is_hashed(&self) -> bool {
+        PasswordHash::new(&self.password).is_ok()
+    }
+
+    pub fn hash_password(&mut self) {
+        let salt = SaltString::generate(&mut OsRng);
+
+        // only fails on invalid parameters, and defaults are valid
+        self.password = Argon2::default().hash_password(self.password.as_bytes(), &salt).unwrap().to_string();
+    }
+
+    // compared in constant time by argon2
+    pub fn verify_password(&self, password: &str) -> bool {
+        PasswordHash::new(&self.password)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Network {
+    pub name: String,
+    // unused by slack networks
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub port: u16,
+    // WHATWG encoding label, e.g. `cp949`, `iso-2022-jp` or `latin1`
+    #[serde(default = "Network::default_encoding")]
+    pub encoding: String,
+    // tries `encoding` first and then this for lines that fail to decode
+    #[serde(default)]
+    pub fallback_encoding: Option<String>,
+    // primary first, then alternates tried when it's taken
+    #[serde(default = "Network::default_nicknames")]
+    pub nicknames: Vec<String>,
+    // sent in USER, primary nickname if unset
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub realname: Option<String>,
+    // raw commands sent after registration, `{nick}` and `{network}` are replaced
+    #[serde(default)]
+    pub perform: Vec<String>,
+    // milliseconds between perform commands
+    #[serde(default)]
+    pub perform_delay: u64,
+    // joined after registration, kept in sync with JOIN, PART and KICK
+    #[serde(default)]
+    pub autojoin: Vec<AutoJoin>,
+    // regains primary nickname held by someone else, e.g. our ghost
+    #[serde(default)]
+    pub nickserv: Option<NickServ>,
+    // flood protection of lines sent upstream
+    #[serde(default)]
+    pub throttle: Throttle,
+    // connects to slack workspace instead of irc server
+    #[serde(default)]
+    pub slack: Option<Slack>,
+}
+
+impl Network {
+    pub fn new(name: String, host: String, port: u16) -> Self {
+        Self {
+            name,
+            host,
+            port,
+            encoding: Self::default_encoding(),
+            fallback_encoding: None,
+            nicknames: Self::default_nicknames(),
+            username: None,
+            realname: None,
+            perform: Vec::new(),
+            perform_delay: 0,
+            autojoin: Vec::new(),
+            nickserv: None,
+            throttle: Throttle::default(),
+            slack: None,
+        }
+    }
+
+    fn default_encoding() -> String {
+        "utf-8".into()
+    }
+
+    fn default_nicknames() -> Vec<String> {
+        vec!["testtest".into()]
+    }
+}
+
+// token bucket of lines sent to upstream
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Throttle {
+    // lines sent at once before throttling
+    #[serde(default = "Throttle::default_burst")]
+    pub burst: u32,
+    // lines per second after burst, zero disables throttling
+    #[serde(default = "Throttle::default_rate")]
+    pub rate: f64,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            burst: Self::default_burst(),
+            rate: Self::default_rate(),
+        }
+    }
+}
+
+impl Throttle {
+    fn default_burst() -> u32 {
+        4
+    }
+
+    fn default_rate() -> f64 {
+        1.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct AutoJoin {
+    pub channel: String,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum NickServCommand {
+    // takes the nickname over in one go
+    Regain,
+    // disconnects the holder, nickname is taken once it's free
+    Ghost,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NickServ {
+    pub password: String,
+    #[serde(default = "NickServ::default_command")]
+    pub command: NickServCommand,
+}
+
+impl NickServ {
+    fn default_command() -> NickServCommand {
+        NickServCommand::Regain
+    }
+}
+
+// slack app with socket mode enabled
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Slack {
+    // `xapp-`, for socket mode
+    pub app_token: String,
+    // `xoxb-` or `xoxp-`, for web api
+    pub bot_token: String,
+    #[serde(default = "Slack::default_api_url")]
+    pub api_url: String,
+}
+
+impl Slack {
+    fn default_api_url() -> String {
+        "https://slack.com/api/".into()
+    }
+}
+
+// what to do when a downstream client can't keep up with its outbound queue
+// TODO spill-to-history, once history sink stores messages to replay from
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    DropOldest,
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ClientQueue {
+    #[serde(default = "ClientQueue::default_size")]
+    pub size: usize,
+    #[serde(default = "ClientQueue::default_policy")]
+    pub policy: QueuePolicy,
+}
+
+impl Default for ClientQueue {
+    fn default() -> Self {
+        Self {
+            size: Self::default_size(),
+            policy: Self::default_policy(),
+        }
+    }
+}
+
+impl ClientQueue {
+    fn default_size() -> usize {
+        1024
+    }
+
+    fn default_policy() -> QueuePolicy {
+        QueuePolicy::DropOldest
+    }
+}
+
+// CTCP replies sent on behalf of the user while no client is attached
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ctcp {
+    // empty disables the reply
+    #[serde(default = "Ctcp::default_version")]
+    pub version: String,
+    #[serde(default)]
+    pub source: String,
+    // at most `limit` replies per `period` seconds on each network
+    #[serde(default = "Ctcp::default_limit")]
+    pub limit: usize,
+    #[serde(default = "Ctcp::default_period")]
+    pub period: u64,
+}
+
+impl Default for Ctcp {
+    fn default() -> Self {
+        Self {
+            version: Self::default_version(),
+            source: String::new(),
+            limit: Self::default_limit(),
+            period: Self::default_period(),
+        }
+    }
+}
+
+impl Ctcp {
+    fn default_version() -> String {
+        format!("bouncer {}", env!("CARGO_PKG_VERSION"))
+    }
+
+    fn default_limit() -> usize {
+        3
+    }
+
+    fn default_period() -> u64 {
+        10
+    }
+}
+
+// AWAY set upstream while no client is attached
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AutoAway {
+    // empty disables
+    #[serde(default = "AutoAway::default_message")]
+    pub message: String,
+}
+
+impl Default for AutoAway {
+    fn default() -> Self {
+        Self {
+            message: Self::default_message(),
+        }
+    }
+}
+
+impl AutoAway {
+    fn default_message() -> String {
+        "Detached from bouncer".into()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    #[serde(default = "Config::default_server_port")]
+    pub server_port: u16,
+    #[serde(default = "Config::default_grpc_port")]
+    pub grpc_port: u16,
+    #[serde(default)]
+    pub client_queue: ClientQueue,
+    #[serde(default)]
+    pub ctcp: Ctcp,
+    #[serde(default)]
+    pub auto_away: AutoAway,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub networks: Vec<Network>,
+}
+
+impl Config {
+    pub fn new(server_port: u16) -> Self {
+        Self {
+            server_port,
+            grpc_port: Self::default_grpc_port(),
+            client_queue: ClientQueue::default(),
+            ctcp: Ctcp::default(),
+            auto_away: AutoAway::default(),
+            users: Vec::new(),
+            networks: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let raw = fs::read_to_string(path)?;
+
+        toml::from_str(&raw).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let raw = toml::to_string_pretty(self).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+
+        fs::write(path, raw)
+    }
+
+    // true if any password was plaintext
+    pub fn hash_passwords(&mut self) -> bool {
+        let mut changed = false;
+
+        for user in self.users.iter_mut().filter(|x| !x.is_hashed()) {
+            user.hash_password();
+            changed = true;
+        }
+
+        changed
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|x| x.name == name)
+    }
+
+    pub fn network(&self, name: &str) -> Option<&Network> {
+        self.networks.iter().find(|x| x.name == name)
+    }
+
+    fn default_server_port() -> u16 {
+        6667
+    }
+
+    fn default_grpc_port() -> u16 {
+        12345
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    const NETWORK: &str = r#"
+        [[networks]]
+        name = "test"
+        host = "irc.test.com"
+        port = 6667
+    "#;
+
+    #[test]
+    fn test_defaults() {
+        let config = parse(NETWORK);
+
+        assert_eq!(config.server_port, 6667);
+        assert_eq!(config.grpc_port, 12345);
+        assert_eq!(config.client_queue.size, 1024);
+        assert_eq!(config.ctcp.limit, 3);
+
+        let network = config.network("test").unwrap();
+        assert_eq!(network.host, "irc.test.com");
+        assert_eq!(network.encoding, "utf-8");
+        assert_eq!(network.nicknames, vec!["testtest"]);
+        assert!(network.username.is_none());
+        assert_eq!(network.throttle.rate, 1.0);
+        assert!(network.slack.is_none());
+    }
+
+    #[test]
+    fn test_client_queue() {
+        let config = parse(
+            r#"
+            [client_queue]
+            policy = "disconnect"
+            "#,
+        );
+
+        assert_eq!(config.client_queue.policy, QueuePolicy::Disconnect);
+    }
+
+    #[test]
+    fn test_ctcp() {
+        let config = parse(
+            r#"
+            [ctcp]
+            version = "my client"
+            "#,
+        );
+
+        assert_eq!(config.ctcp.version, "my client");
+    }
+
+    #[test]
+    fn test_auto_away() {
+        let config = parse(
+            r#"
+            [auto_away]
+            message = ""
+            "#,
+        );
+
+        assert!(config.auto_away.message.is_empty());
+    }
+
+    #[test]
+    fn test_users() {
+        let config = parse(
+            r#"
+            [[users]]
+            name = "admin"
+            password = "test"
+            admin = true
+            "#,
+        );
+
+        assert!(config.user("admin").unwrap().admin);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let config = parse(
+            r#"
+            [[networks]]
+            name = "hanirc"
+            host = "irc.hanirc.org"
+            port = 6667
+            encoding = "utf-8"
+            fallback_encoding = "cp949"
+            "#,
+        );
+
+        assert_eq!(config.network("hanirc").unwrap().fallback_encoding.as_deref(), Some("cp949"));
+    }
+
+    #[test]
+    fn test_identity() {
+        let config = parse(
+            r#"
+            [[networks]]
+            name = "hanirc"
+            host = "irc.hanirc.org"
+            port = 6667
+            nicknames = ["bouncer", "bouncer2"]
+            username = "ident"
+            realname = "Bouncer User"
+            nickserv = { password = "secret", command = "ghost" }
+            "#,
+        );
+
+        let network = config.network("hanirc").unwrap();
+        assert_eq!(network.nicknames, vec!["bouncer", "bouncer2"]);
+        assert_eq!(network.username.as_deref(), Some("ident"));
+        assert_eq!(network.realname.as_deref(), Some("Bouncer User"));
+        assert_eq!(network.nickserv.as_ref().map(|x| x.command), Some(NickServCommand::Ghost));
+    }
+
+    #[test]
+    fn test_perform() {
+        let config = parse(
+            r#"
+            [[networks]]
+            name = "hanirc"
+            host = "irc.hanirc.org"
+            port = 6667
+            perform = ["MODE {nick} +x"]
+            perform_delay = 1000
+            autojoin = [{ channel = '#test' }, { channel = '#secret', key = "key" }]
+            "#,
+        );
+
+        let network = config.network("hanirc").unwrap();
+        assert_eq!(network.perform, vec!["MODE {nick} +x"]);
+        assert_eq!(network.perform_delay, 1000);
+        assert_eq!(network.autojoin[1].key.as_deref(), Some("key"));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let config = parse(
+            r#"
+            [[networks]]
+            name = "hanirc"
+            host = "irc.hanirc.org"
+            port = 6667
+            throttle = { rate = 2.0 }
+            "#,
+        );
+
+        let throttle = &config.network("hanirc").unwrap().throttle;
+        assert_eq!(throttle.burst, 4);
+        assert_eq!(throttle.rate, 2.0);
+    }
+
+    #[test]
+    fn test_slack() {
+        let config = parse(
+            r#"
+            [[networks]]
+            name = "work"
+            slack = { app_token = "xapp-1", bot_token = "xoxb-1" }
+            "#,
+        );
+
+        assert_eq!(config.network("work").unwrap().slack.as_ref().unwrap().api_url, "https://slack.com/api/");
+    }
+
+    #[test]
+    fn test_serialize() {
+        let config = parse(
+            r#"
+            [client_queue]
+            policy = "disconnect"
+
+            [[users]]
+            name = "admin"
+            password = "test"
+
+            [[networks]]
+            name = "hanirc"
+            host = "irc.hanirc.org"
+            port = 6667
+            autojoin = [{ channel = '#test' }]
+            throttle = { rate = 2.0 }
+
+            [[networks]]
+            name = "work"
+            slack = { app_token = "xapp-1", bot_token = "xoxb-1" }
+            "#,
+        );
+
+        // tables have to come after plain values
+        assert!(toml::to_string_pretty(&config).is_ok());
+    }
+
+    #[test]
+    fn test_password() {
+        let mut config = Config::new(6667);
+        config.users.push(User {
+            name: "admin".into(),
+            password: "secret".into(),
+            admin: true,
+        });
+
+        assert!(config.hash_passwords());
+        assert!(!config.hash_passwords());
+
+        let user = config.user("admin").unwrap();
+        assert!(user.password.starts_with("$argon2"));
+        assert!(user.verify_password("secret"));
+        assert!(!user.verify_password("wrong"));
+    }
+}
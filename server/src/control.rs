@@ -0,0 +1,197 @@
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{AutoJoin, Network, User};
+use crate::message::Snapshot;
+use crate::sink::Session;
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    AlreadyExists,
+    Io(std::io::Error),
+    Closed,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub type Reply<T> = oneshot::Sender<T>;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum NetworkState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+#[derive(Clone, Copy)]
+pub enum NetworkCommand {
+    Connect,
+    Disconnect,
+    Reconnect,
+}
+
+// requests from sinks to running bouncer
+pub enum Control {
+    Authenticate {
+        name: String,
+        password: String,
+        reply: Reply<Option<User>>,
+    },
+    GetUser {
+        name: String,
+        reply: Reply<Option<User>>,
+    },
+    ListUsers {
+        reply: Reply<Vec<User>>,
+    },
+    CreateUser {
+        user: User,
+        reply: Reply<Result<()>>,
+    },
+    UpdateUser {
+        user: User,
+        reply: Reply<Result<()>>,
+    },
+    DeleteUser {
+        name: String,
+        reply: Reply<Result<()>>,
+    },
+    ListNetworks {
+        reply: Reply<Vec<(Network, NetworkState)>>,
+    },
+    CreateNetwork {
+        network: Network,
+        reply: Reply<Result<()>>,
+    },
+    UpdateNetwork {
+        network: Network,
+        reply: Reply<Result<()>>,
+    },
+    DeleteNetwork {
+        name: String,
+        reply: Reply<Result<()>>,
+    },
+    NetworkCommand {
+        name: String,
+        command: NetworkCommand,
+        reply: Reply<Result<()>>,
+    },
+    ListSessions {
+        reply: Reply<Vec<Session>>,
+    },
+    KickSession {
+        id: u32,
+        reason: String,
+        reply: Reply<Result<()>>,
+    },
+    // empty network means first connected network, or first network if none is connected
+    ListAutoJoin {
+        network: String,
+        reply: Reply<Result<Vec<AutoJoin>>>,
+    },
+    AddAutoJoin {
+        network: String,
+        channel: AutoJoin,
+        reply: Reply<Result<()>>,
+    },
+    RemoveAutoJoin {
+        network: String,
+        channel: String,
+        reply: Reply<Result<()>>,
+    },
+    // empty network means first connected network
+    Snapshot {
+        network: String,
+        reply: Reply<Result<Snapshot>>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Controller {
+    sender: mpsc::Sender<Control>,
+}
+
+impl Controller {
+    pub fn new(sender: mpsc::Sender<Control>) -> Self {
+        Self { sender }
+    }
+
+    pub async fn authenticate(&self, name: String, password: String) -> Result<Option<User>> {
+        self.request(|reply| Control::Authenticate { name, password, reply }).await
+    }
+
+    pub async fn get_user(&self, name: String) -> Result<Option<User>> {
+        self.request(|reply| Control::GetUser { name, reply }).await
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>> {
+        self.request(|reply| Control::ListUsers { reply }).await
+    }
+
+    pub async fn create_user(&self, user: User) -> Result<()> {
+        self.request(|reply| Control::CreateUser { user, reply }).await?
+    }
+
+    pub async fn update_user(&self, user: User) -> Result<()> {
+        self.request(|reply| Control::UpdateUser { user, reply }).await?
+    }
+
+    pub async fn delete_user(&self, name: String) -> Result<()> {
+        self.request(|reply| Control::DeleteUser { name, reply }).await?
+    }
+
+    pub async fn list_networks(&self) -> Result<Vec<(Network, NetworkState)>> {
+        self.request(|reply| Control::ListNetworks { reply }).await
+    }
+
+    pub async fn create_network(&self, network: Network) -> Result<()> {
+        self.request(|reply| Control::CreateNetwork { network, reply }).await?
+    }
+
+    pub async fn update_network(&self, network: Network) -> Result<()> {
+        self.request(|reply| Control::UpdateNetwork { network, reply }).await?
+    }
+
+    pub async fn delete_network(&self, name: String) -> Result<()> {
+        self.request(|reply| Control::DeleteNetwork { name, reply }).await?
+    }
+
+    pub async fn network_command(&self, name: String, command: NetworkCommand) -> Result<()> {
+        self.request(|reply| Control::NetworkCommand { name, command, reply }).await?
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<Session>> {
+        self.request(|reply| Control::ListSessions { reply }).await
+    }
+
+    pub async fn kick_session(&self, id: u32, reason: String) -> Result<()> {
+        self.request(|reply| Control::KickSession { id, reason, reply }).await?
+    }
+
+    pub async fn list_autojoin(&self, network: String) -> Result<Vec<AutoJoin>> {
+        self.request(|reply| Control::ListAutoJoin { network, reply }).await?
+    }
+
+    pub async fn add_autojoin(&self, network: String, channel: AutoJoin) -> Result<()> {
+        self.request(|reply| Control::AddAutoJoin { network, channel, reply }).await?
+    }
+
+    pub async fn remove_autojoin(&self, network: String, channel: String) -> Result<()> {
+        self.request(|reply| Control::RemoveAutoJoin { network, channel, reply }).await?
+    }
+
+    pub async fn snapshot(&self, network: String) -> Result<Snapshot> {
+        self.request(|reply| Control::Snapshot { network, reply }).await?
+    }
+
+    async fn request<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(Reply<T>) -> Control,
+    {
+        let (reply, receiver) = oneshot::channel();
+
+        self.sender.send(f(reply)).await.map_err(|_| Error::Closed)?;
+
+        receiver.await.map_err(|_| Error::Closed)
+    }
+}
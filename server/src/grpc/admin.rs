@@ -0,0 +1,275 @@
+use std::convert::TryFrom;
+
+use async_trait::async_trait;
+use tonic::{Request, Response, Status};
+
+use super::{
+    authenticate,
+    pb::{
+        self, network::State, network_command_request::Command, CreateNetworkRequest, CreateNetworkResponse, CreateUserRequest, CreateUserResponse,
+        DeleteNetworkRequest, DeleteNetworkResponse, DeleteUserRequest, DeleteUserResponse, KickSessionRequest, KickSessionResponse,
+        ListNetworksRequest, ListNetworksResponse, ListSessionsRequest, ListSessionsResponse, ListUsersRequest, ListUsersResponse,
+        NetworkCommandRequest, NetworkCommandResponse, UpdateNetworkRequest, UpdateNetworkResponse, UpdateUserRequest, UpdateUserResponse,
+    },
+    Tokens,
+};
+use crate::config::{AutoJoin, Network, User};
+use crate::control::{self, Controller, NetworkCommand, NetworkState};
+use crate::irc::Encoding;
+
+pub struct AdminServer {
+    controller: Controller,
+    tokens: Tokens,
+}
+
+impl AdminServer {
+    pub fn new(controller: Controller, tokens: Tokens) -> Self {
+        Self { controller, tokens }
+    }
+
+    async fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let name = authenticate(&self.tokens, request).await?;
+
+        match self.controller.get_user(name).await.map_err(Self::convert_error)? {
+            Some(user) if user.admin => Ok(()),
+            Some(_) => Err(Status::permission_denied("Admin only")),
+            None => Err(Status::unauthenticated("No valid auth token")),
+        }
+    }
+
+    fn convert_error(error: control::Error) -> Status {
+        match error {
+            control::Error::NotFound => Status::not_found("Not found"),
+            control::Error::AlreadyExists => Status::already_exists("Already exists"),
+            control::Error::Io(x) => Status::internal(x.to_string()),
+            control::Error::Closed => Status::unavailable("Bouncer is not running"),
+        }
+    }
+
+    fn convert_user(user: Option<pb::User>) -> Result<User, Status> {
+        let user = user.ok_or_else(|| Status::invalid_argument("User is required"))?;
+        if user.name.is_empty() {
+            return Err(Status::invalid_argument("User name is required"));
+        }
+
+        Ok(User {
+            name: user.name,
+            password: user.password,
+            admin: user.admin,
+        })
+    }
+
+    fn convert_network(network: Option<pb::Network>) -> Result<Network, Status> {
+        let network = network.ok_or_else(|| Status::invalid_argument("Network is required"))?;
+        if network.name.is_empty() || network.host.is_empty() {
+            return Err(Status::invalid_argument("Network name and host are required"));
+        }
+
+        let port = u16::try_from(network.port).map_err(|_| Status::invalid_argument("Invalid port"))?;
+
+        let mut result = Network::new(network.name, network.host, port);
+        if !network.encoding.is_empty() {
+            result.encoding = network.encoding;
+        }
+        if !network.fallback_encoding.is_empty() {
+            result.fallback_encoding = Some(network.fallback_encoding);
+        }
+        if !network.nicknames.is_empty() {
+            result.nicknames = network.nicknames;
+        }
+        result.username = Some(network.username).filter(|x| !x.is_empty());
+        result.realname = Some(network.realname).filter(|x| !x.is_empty());
+        result.perform = network.perform;
+        result.perform_delay = network.perform_delay;
+        result.autojoin = network
+            .autojoin
+            .into_iter()
+            .map(|x| AutoJoin {
+                channel: x.channel,
+                key: x.key,
+            })
+            .collect();
+
+        // validated here so that bad labels don't surface only as connection failures
+        Encoding::new(&result.encoding, result.fallback_encoding.as_deref()).map_err(|x| Status::invalid_argument(x.to_string()))?;
+
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl pb::admin_server::Admin for AdminServer {
+    async fn list_users(&self, request: Request<ListUsersRequest>) -> Result<Response<ListUsersResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let users = self.controller.list_users().await.map_err(Self::convert_error)?;
+        let users = users
+            .into_iter()
+            .map(|x| pb::User {
+                name: x.name,
+                password: String::new(),
+                admin: x.admin,
+            })
+            .collect();
+
+        Ok(Response::new(ListUsersResponse { users }))
+    }
+
+    async fn create_user(&self, request: Request<CreateUserRequest>) -> Result<Response<CreateUserResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let user = Self::convert_user(request.into_inner().user)?;
+        if user.password.is_empty() {
+            return Err(Status::invalid_argument("Password is required"));
+        }
+
+        self.controller.create_user(user).await.map_err(Self::convert_error)?;
+
+        Ok(Response::new(CreateUserResponse {}))
+    }
+
+    async fn update_user(&self, request: Request<UpdateUserRequest>) -> Result<Response<UpdateUserResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let user = Self::convert_user(request.into_inner().user)?;
+        let (name, revoke) = (user.name.clone(), !user.password.is_empty());
+        self.controller.update_user(user).await.map_err(Self::convert_error)?;
+
+        // sessions of old password end with it
+        if revoke {
+            self.tokens.lock().await.revoke(&name);
+        }
+
+        Ok(Response::new(UpdateUserResponse {}))
+    }
+
+    async fn delete_user(&self, request: Request<DeleteUserRequest>) -> Result<Response<DeleteUserResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let name = request.into_inner().name;
+        self.controller.delete_user(name.clone()).await.map_err(Self::convert_error)?;
+
+        self.tokens.lock().await.revoke(&name);
+
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn list_networks(&self, request: Request<ListNetworksRequest>) -> Result<Response<ListNetworksResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let mut networks = Vec::new();
+        for (network, state) in self.controller.list_networks().await.map_err(Self::convert_error)? {
+            // network may have gone in between, which isn't worth failing for
+            let queued = match state {
+                NetworkState::Connected => self.controller.snapshot(network.name.clone()).await.map(|x| x.queued).unwrap_or(0),
+                _ => 0,
+            };
+            let state = match state {
+                NetworkState::Disconnected => State::Disconnected,
+                NetworkState::Connecting => State::Connecting,
+                NetworkState::Connected => State::Connected,
+            };
+
+            networks.push(pb::Network {
+                name: network.name,
+                host: network.host,
+                port: network.port as u32,
+                state: state as i32,
+                encoding: network.encoding,
+                fallback_encoding: network.fallback_encoding.unwrap_or_default(),
+                nicknames: network.nicknames,
+                perform: network.perform,
+                perform_delay: network.perform_delay,
+                autojoin: network
+                    .autojoin
+                    .into_iter()
+                    .map(|x| pb::AutoJoin {
+                        channel: x.channel,
+                        key: x.key,
+                    })
+                    .collect(),
+                queued: queued as u32,
+                username: network.username.unwrap_or_default(),
+                realname: network.realname.unwrap_or_default(),
+            });
+        }
+
+        Ok(Response::new(ListNetworksResponse { networks }))
+    }
+
+    async fn create_network(&self, request: Request<CreateNetworkRequest>) -> Result<Response<CreateNetworkResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let network = Self::convert_network(request.into_inner().network)?;
+        self.controller.create_network(network).await.map_err(Self::convert_error)?;
+
+        Ok(Response::new(CreateNetworkResponse {}))
+    }
+
+    async fn update_network(&self, request: Request<UpdateNetworkRequest>) -> Result<Response<UpdateNetworkResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let network = Self::convert_network(request.into_inner().network)?;
+        self.controller.update_network(network).await.map_err(Self::convert_error)?;
+
+        Ok(Response::new(UpdateNetworkResponse {}))
+    }
+
+    async fn delete_network(&self, request: Request<DeleteNetworkRequest>) -> Result<Response<DeleteNetworkResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let name = request.into_inner().name;
+        self.controller.delete_network(name).await.map_err(Self::convert_error)?;
+
+        Ok(Response::new(DeleteNetworkResponse {}))
+    }
+
+    async fn network_command(&self, request: Request<NetworkCommandRequest>) -> Result<Response<NetworkCommandResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let request = request.into_inner();
+        let command = match Command::from_i32(request.command) {
+            Some(Command::Connect) => NetworkCommand::Connect,
+            Some(Command::Disconnect) => NetworkCommand::Disconnect,
+            Some(Command::Reconnect) => NetworkCommand::Reconnect,
+            None => return Err(Status::invalid_argument("Invalid command")),
+        };
+
+        self.controller
+            .network_command(request.name, command)
+            .await
+            .map_err(Self::convert_error)?;
+
+        Ok(Response::new(NetworkCommandResponse {}))
+    }
+
+    async fn list_sessions(&self, request: Request<ListSessionsRequest>) -> Result<Response<ListSessionsResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let sessions = self.controller.list_sessions().await.map_err(Self::convert_error)?;
+        let sessions = sessions
+            .into_iter()
+            .map(|x| pb::Session {
+                id: x.id,
+                address: x.address,
+            })
+            .collect();
+
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn kick_session(&self, request: Request<KickSessionRequest>) -> Result<Response<KickSessionResponse>, Status> {
+        self.authorize(&request).await?;
+
+        let request = request.into_inner();
+        let reason = if request.reason.is_empty() {
+            "Kicked by admin".into()
+        } else {
+            request.reason
+        };
+
+        self.controller.kick_session(request.id, reason).await.map_err(Self::convert_error)?;
+
+        Ok(Response::new(KickSessionResponse {}))
+    }
+}
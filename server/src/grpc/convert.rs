@@ -0,0 +1,176 @@
+use std::convert::TryFrom;
+
+use tonic::Status;
+
+use super::pb::{self, envelope, message};
+use crate::irc::format::{self, Color, Span, Style};
+use crate::message::{Direction, Envelope, Message};
+
+impl From<&Envelope> for pb::Envelope {
+    fn from(envelope: &Envelope) -> Self {
+        let direction = match envelope.direction {
+            Direction::Incoming => envelope::Direction::Incoming,
+            Direction::Outgoing => envelope::Direction::Outgoing,
+        };
+
+        Self {
+            network: envelope.network.clone(),
+            time: envelope.time.timestamp_millis(),
+            id: envelope.id.clone(),
+            direction: direction as i32,
+            message: Some((&envelope.message).into()),
+        }
+    }
+}
+
+impl From<&Message> for pb::Message {
+    fn from(message: &Message) -> Self {
+        let message = match message.clone() {
+            Message::Chat { sender, channel, content } => message::Message::Chat(message::Chat {
+                sender,
+                channel,
+                spans: spans(&content),
+                content,
+            }),
+            Message::Action { sender, channel, content } => message::Message::Action(message::Action {
+                sender,
+                channel,
+                spans: spans(&content),
+                content,
+            }),
+            Message::PrivateChat { sender, peer, content } => message::Message::PrivateChat(message::PrivateChat {
+                sender,
+                peer,
+                spans: spans(&content),
+                content,
+            }),
+            Message::PrivateAction { sender, peer, content } => message::Message::PrivateAction(message::PrivateAction {
+                sender,
+                peer,
+                spans: spans(&content),
+                content,
+            }),
+            Message::Notice { sender, target, content } => message::Message::Notice(message::Notice {
+                sender,
+                target,
+                spans: spans(&content),
+                content,
+            }),
+            Message::Topic { sender, channel, topic } => message::Message::Topic(message::Topic { sender, channel, topic }),
+            Message::Mode { sender, target, modes } => message::Message::Mode(message::Mode { sender, target, modes }),
+            Message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            } => message::Message::Kick(message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            }),
+            Message::Invite { sender, user, channel } => message::Message::Invite(message::Invite { sender, user, channel }),
+            Message::Away { sender, message } => message::Message::Away(message::Away { sender, message }),
+            Message::JoinedChannel { sender, channel } => message::Message::JoinedChannel(message::JoinedChannel { sender, channel }),
+            Message::PartedChannel { sender, channel, reason } => message::Message::PartedChannel(message::PartedChannel { sender, channel, reason }),
+            Message::Quit { sender, reason } => message::Message::Quit(message::Quit { sender, reason }),
+            Message::NickChanged { sender, nickname } => message::Message::NickChanged(message::NickChanged { sender, nickname }),
+            Message::UsersList { channel, users } => message::Message::UsersList(message::UsersList { channel, users }),
+            Message::Error { message } => message::Message::Error(message::Error { message }),
+            Message::Numeric { sender, code, args } => message::Message::Numeric(message::Numeric { sender, code, args }),
+            Message::JoinChannel { channel, key } => message::Message::JoinChannel(message::JoinChannel { channel, key }),
+            Message::PartChannel { channel, reason } => message::Message::PartChannel(message::PartChannel { channel, reason }),
+            Message::ChangeNick { nickname } => message::Message::ChangeNick(message::ChangeNick { nickname }),
+            Message::Raw { sender, command, args } => message::Message::Raw(message::Raw { sender, command, args }),
+        };
+
+        Self { message: Some(message) }
+    }
+}
+
+impl TryFrom<pb::Message> for Message {
+    type Error = Status;
+
+    fn try_from(message: pb::Message) -> Result<Self, Status> {
+        let result = match message.message.ok_or_else(|| Status::invalid_argument("Message is empty"))? {
+            message::Message::Chat(message::Chat {
+                sender, channel, content, ..
+            }) => Message::Chat { sender, channel, content },
+            message::Message::Action(message::Action {
+                sender, channel, content, ..
+            }) => Message::Action { sender, channel, content },
+            message::Message::PrivateChat(message::PrivateChat { sender, peer, content, .. }) => Message::PrivateChat { sender, peer, content },
+            message::Message::PrivateAction(message::PrivateAction { sender, peer, content, .. }) => Message::PrivateAction { sender, peer, content },
+            message::Message::Notice(message::Notice { sender, target, content, .. }) => Message::Notice { sender, target, content },
+            message::Message::Topic(message::Topic { sender, channel, topic }) => Message::Topic { sender, channel, topic },
+            message::Message::Mode(message::Mode { sender, target, modes }) => Message::Mode { sender, target, modes },
+            message::Message::Kick(message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            }) => Message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            },
+            message::Message::Invite(message::Invite { sender, user, channel }) => Message::Invite { sender, user, channel },
+            message::Message::Away(message::Away { sender, message }) => Message::Away { sender, message },
+            message::Message::JoinChannel(message::JoinChannel { channel, key }) => Message::JoinChannel { channel, key },
+            message::Message::PartChannel(message::PartChannel { channel, reason }) => Message::PartChannel { channel, reason },
+            message::Message::ChangeNick(message::ChangeNick { nickname }) => Message::ChangeNick { nickname },
+            message::Message::Raw(message::Raw { sender, command, args }) => Message::Raw { sender, command, args },
+            _ => return Err(Status::invalid_argument("Message can't be sent")),
+        };
+
+        Ok(result)
+    }
+}
+
+impl From<&Span> for pb::Span {
+    fn from(span: &Span) -> Self {
+        let style = &span.style;
+
+        Self {
+            text: span.text.clone(),
+            bold: style.bold,
+            italic: style.italic,
+            underline: style.underline,
+            strikethrough: style.strikethrough,
+            monospace: style.monospace,
+            reverse: style.reverse,
+            foreground: style.foreground.map(|x| x.hex()),
+            background: style.background.map(|x| x.hex()),
+        }
+    }
+}
+
+impl TryFrom<pb::Span> for Span {
+    type Error = Status;
+
+    fn try_from(span: pb::Span) -> Result<Self, Status> {
+        let color = |x: Option<String>| match x {
+            Some(x) => Color::from_hex(&x).map(Some).ok_or_else(|| Status::invalid_argument("Invalid color")),
+            None => Ok(None),
+        };
+
+        Ok(Self {
+            style: Style {
+                bold: span.bold,
+                italic: span.italic,
+                underline: span.underline,
+                strikethrough: span.strikethrough,
+                monospace: span.monospace,
+                reverse: span.reverse,
+                foreground: color(span.foreground)?,
+                background: color(span.background)?,
+            },
+            text: span.text,
+        })
+    }
+}
+
+fn spans(content: &str) -> Vec<pb::Span> {
+    format::parse(content).iter().map(Into::into).collect()
+}
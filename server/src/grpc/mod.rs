@@ -1,3 +1,133 @@
+// tonic::Status is large, but it's the error type tonic requires everywhere
+#![allow(clippy::result_large_err)]
+
+mod admin;
+mod convert;
 mod server;
+mod state;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tokio::sync::Mutex;
+use tonic::{Request, Status};
 
 pub use server::Server;
+
+mod pb {
+    tonic::include_proto!("bouncer");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("bouncer_descriptor");
+}
+
+const TOKEN_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// oldest token of the user is dropped beyond this
+const TOKENS_PER_USER: usize = 16;
+
+struct Token {
+    user: String,
+    issued: Instant,
+}
+
+// bearer tokens issued by `Login`
+#[derive(Default)]
+struct TokenStore {
+    tokens: HashMap<String, Token>,
+}
+
+impl TokenStore {
+    fn issue(&mut self, user: String) -> String {
+        let now = Instant::now();
+        self.tokens.retain(|_, x| now.duration_since(x.issued) < TOKEN_LIFETIME);
+
+        let mut issued = self
+            .tokens
+            .iter()
+            .filter(|(_, x)| x.user == user)
+            .map(|(token, x)| (x.issued, token.clone()))
+            .collect::<Vec<_>>();
+        if issued.len() >= TOKENS_PER_USER {
+            issued.sort();
+
+            for (_, token) in &issued[..=issued.len() - TOKENS_PER_USER] {
+                self.tokens.remove(token);
+            }
+        }
+
+        let token = thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>();
+        self.tokens.insert(token.clone(), Token { user, issued: now });
+
+        token
+    }
+
+    fn user(&self, token: &str) -> Option<String> {
+        self.tokens
+            .get(token)
+            .filter(|x| x.issued.elapsed() < TOKEN_LIFETIME)
+            .map(|x| x.user.clone())
+    }
+
+    // on deletion or password change
+    fn revoke(&mut self, user: &str) {
+        self.tokens.retain(|_, x| x.user != user);
+    }
+}
+
+type Tokens = Arc<Mutex<TokenStore>>;
+
+// user name of bearer token from `Login`
+async fn authenticate<T>(tokens: &Tokens, request: &Request<T>) -> Result<String, Status> {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("No valid auth token"))?;
+
+    tokens
+        .lock()
+        .await
+        .user(token)
+        .ok_or_else(|| Status::unauthenticated("No valid auth token"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let mut tokens = TokenStore::default();
+
+        let first = tokens.issue("user".into());
+        let other = tokens.issue("other".into());
+        assert_eq!(tokens.user(&first).as_deref(), Some("user"));
+
+        // oldest goes over the cap
+        for _ in 0..TOKENS_PER_USER {
+            tokens.issue("user".into());
+        }
+        assert_eq!(tokens.user(&first), None);
+        assert_eq!(tokens.user(&other).as_deref(), Some("other"));
+
+        tokens.revoke("other");
+        assert_eq!(tokens.user(&other), None);
+        assert_eq!(tokens.tokens.len(), TOKENS_PER_USER);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let mut tokens = TokenStore::default();
+
+        let token = tokens.issue("user".into());
+        if let Some(issued) = Instant::now().checked_sub(TOKEN_LIFETIME) {
+            tokens.tokens.get_mut(&token).unwrap().issued = issued;
+
+            assert_eq!(tokens.user(&token), None);
+        }
+    }
+}
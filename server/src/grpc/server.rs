@@ -1,64 +1,620 @@
-use std::net::Ipv4Addr;
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    net::Ipv4Addr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use futures::{
     stream::{self, BoxStream},
-    StreamExt,
+    Stream, StreamExt,
+};
+use log::error;
+use tokio::{
+    io,
+    net::TcpListener,
+    sync::{
+        broadcast::{channel, Sender},
+        mpsc, watch, Mutex,
+    },
+    task::spawn,
+    time::timeout,
 };
-use tokio::{io, task::spawn};
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tonic_health::{server::HealthReporter, ServingStatus};
 
-use crate::message::Message;
+use super::{admin::AdminServer, authenticate, pb, state::State, Tokens};
+use crate::control::{self, Controller, NetworkState};
+use crate::irc::{
+    format::{self, Span},
+    ISupport,
+};
+use crate::message::{DeltaKind, Direction, Envelope, MemberSnapshot, MembershipDelta as Delta, Origin, Snapshot, TopicSnapshot};
 use crate::sink::Sink;
 
-use tonic::{metadata::MetadataValue, transport, Request, Response, Status};
+use tonic::{transport, Request, Response, Status};
+
+use super::pb::{
+    membership_delta::Kind, Channel, ListChannelsRequest, ListChannelsResponse, ListMembersRequest, ListMembersResponse, ListQueriesRequest,
+    ListQueriesResponse, LoginRequest, LoginResponse, MembershipDelta, Query, RenderTextRequest, RenderTextResponse, SendMessageRequest,
+    SendMessageResponse, WatchMembersRequest, WatchMessagesRequest,
+};
+
+// success of most commands is silent, so replies are collected until this at most
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+const REPLY_QUEUE_SIZE: usize = 256;
 
-mod pb {
-    tonic::include_proto!("bouncer");
+// reply queue of SendMessage calls in progress
+type Calls = Arc<Mutex<HashMap<u64, mpsc::Sender<Envelope>>>>;
+
+struct GrpcServer {
+    state: Arc<Mutex<State>>,
+    deltas: Sender<MembershipDelta>,
+    messages: Sender<pb::Envelope>,
+    outgoing: mpsc::Sender<Envelope>,
+    calls: Calls,
+    next_call: AtomicU64,
+    controller: Controller,
+    tokens: Tokens,
 }
-use pb::{LoginRequest, LoginResponse};
 
-struct GrpcServer {}
+impl GrpcServer {
+    fn convert_member_snapshot(member: MemberSnapshot) -> pb::Member {
+        pb::Member {
+            nickname: member.nickname,
+            prefixes: member.prefixes,
+            away: member.away.is_some(),
+            hostmask: member.hostmask.unwrap_or_default(),
+            away_message: member.away,
+        }
+    }
+
+    async fn snapshot(&self, network: String) -> Result<Snapshot, Status> {
+        self.controller.snapshot(network).await.map_err(|x| match x {
+            control::Error::NotFound => Status::not_found("No connected network"),
+            _ => Status::unavailable("Bouncer is not running"),
+        })
+    }
+
+    fn convert_delta(network: &str, delta: &Delta) -> MembershipDelta {
+        let kind = match delta.kind {
+            DeltaKind::Reset => Kind::Reset,
+            DeltaKind::Joined => Kind::Joined,
+            DeltaKind::Left => Kind::Left,
+            DeltaKind::Updated => Kind::Updated,
+        };
+
+        MembershipDelta {
+            channel: delta.channel.clone(),
+            kind: kind as i32,
+            members: delta.members.iter().cloned().map(Self::convert_member_snapshot).collect(),
+            network: network.into(),
+        }
+    }
+}
 
 #[async_trait]
 impl pb::bouncer_server::Bouncer for GrpcServer {
+    type WatchMembersStream = Pin<Box<dyn Stream<Item = Result<MembershipDelta, Status>> + Send>>;
+    type WatchMessagesStream = Pin<Box<dyn Stream<Item = Result<pb::Envelope, Status>> + Send>>;
+
     async fn login(&self, request: Request<LoginRequest>) -> Result<Response<LoginResponse>, Status> {
-        let username = request.into_inner().username;
+        let request = request.into_inner();
+
+        let user = self
+            .controller
+            .authenticate(request.username, request.password)
+            .await
+            .map_err(|_| Status::unavailable("Bouncer is not running"))?
+            .ok_or_else(|| Status::unauthenticated("Invalid username or password"))?;
+
+        let token = self.tokens.lock().await.issue(user.name);
+
+        Ok(Response::new(LoginResponse { token }))
+    }
+
+    async fn list_channels(&self, request: Request<ListChannelsRequest>) -> Result<Response<ListChannelsResponse>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let snapshot = self.snapshot(request.into_inner().network).await?;
+        let network = snapshot.network;
+        let state = self.state.lock().await;
+
+        let channels = snapshot
+            .channels
+            .into_iter()
+            .map(|channel| {
+                let topic = channel.topic.unwrap_or_else(|| TopicSnapshot {
+                    text: String::new(),
+                    setter: None,
+                    time: None,
+                });
+
+                Channel {
+                    unread_count: state.channel(&network, &channel.name).map(|x| x.unread_count).unwrap_or(0),
+                    name: channel.name,
+                    topic: topic.text,
+                    modes: channel.modes,
+                    topic_setter: topic.setter.unwrap_or_default(),
+                    topic_time: topic.time.map(|x| x.timestamp_millis()).unwrap_or(0),
+                }
+            })
+            .collect();
+
+        Ok(Response::new(ListChannelsResponse { channels }))
+    }
+
+    async fn list_members(&self, request: Request<ListMembersRequest>) -> Result<Response<ListMembersResponse>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let ListMembersRequest { channel, network } = request.into_inner();
+        let snapshot = self.snapshot(network).await?;
+        let mut isupport = ISupport::default();
+        isupport.apply(&snapshot.isupport);
+
+        let channel = snapshot
+            .channels
+            .into_iter()
+            .find(|x| isupport.casemapping.eq_ignore_case(&x.name, &channel))
+            .ok_or_else(|| Status::not_found("No such channel"))?;
+        let members = channel.members.into_iter().map(Self::convert_member_snapshot).collect();
+
+        Ok(Response::new(ListMembersResponse { members }))
+    }
+
+    async fn list_queries(&self, request: Request<ListQueriesRequest>) -> Result<Response<ListQueriesResponse>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let network = request.into_inner().network;
+        let queries = self
+            .state
+            .lock()
+            .await
+            .queries(&network)
+            .map(|(network, query)| Query {
+                peer: query.peer.clone(),
+                unread_count: query.unread_count,
+                network: network.clone(),
+            })
+            .collect();
+
+        Ok(Response::new(ListQueriesResponse { queries }))
+    }
+
+    async fn watch_members(&self, request: Request<WatchMembersRequest>) -> Result<Response<Self::WatchMembersStream>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let WatchMembersRequest { channel: filter, network } = request.into_inner();
+
+        // subscribe before taking snapshot so that no delta is lost in between
+        let receiver = self.deltas.subscribe();
+        let snapshots = if network.is_empty() {
+            let networks = self
+                .controller
+                .list_networks()
+                .await
+                .map_err(|_| Status::unavailable("Bouncer is not running"))?;
+
+            let mut snapshots = Vec::new();
+            for (network, _) in networks.into_iter().filter(|(_, state)| *state == NetworkState::Connected) {
+                // network may have gone in between
+                if let Ok(snapshot) = self.snapshot(network.name).await {
+                    snapshots.push(snapshot);
+                }
+            }
+
+            snapshots
+        } else {
+            vec![self.snapshot(network.clone()).await?]
+        };
+
+        // channel filter goes through casemapping of its network
+        let casemappings = snapshots
+            .iter()
+            .map(|x| {
+                let mut isupport = ISupport::default();
+                isupport.apply(&x.isupport);
+
+                (x.network.clone(), isupport.casemapping)
+            })
+            .collect::<HashMap<_, _>>();
+        let matches = move |delta: &MembershipDelta| {
+            let casemapping = casemappings.get(&delta.network).copied().unwrap_or(ISupport::default().casemapping);
+
+            filter.is_empty() || casemapping.eq_ignore_case(&delta.channel, &filter)
+        };
+
+        let snapshot = snapshots
+            .into_iter()
+            .flat_map(|snapshot| {
+                let network = snapshot.network;
+
+                snapshot.channels.into_iter().map(move |channel| {
+                    Self::convert_delta(
+                        &network,
+                        &Delta {
+                            channel: channel.name,
+                            kind: DeltaKind::Reset,
+                            members: channel.members,
+                        },
+                    )
+                })
+            })
+            .filter(matches.clone())
+            .collect::<Vec<_>>();
+
+        let deltas = BroadcastStream::new(receiver).filter_map(move |x| {
+            let result = match x {
+                Ok(delta) if (network.is_empty() || delta.network == network) && matches(&delta) => Some(Ok(delta)),
+                Ok(_) => None,
+                Err(_) => Some(Err(Status::data_loss("Membership stream lagged"))),
+            };
+
+            async move { result }
+        });
+
+        let stream = stream::iter(snapshot).map(Ok).chain(deltas);
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn watch_messages(&self, request: Request<WatchMessagesRequest>) -> Result<Response<Self::WatchMessagesStream>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let filter = request.into_inner().network;
+
+        let stream = BroadcastStream::new(self.messages.subscribe()).filter_map(move |x| {
+            let result = match x {
+                Ok(envelope) if filter.is_empty() || envelope.network == filter => Some(Ok(envelope)),
+                Ok(_) => None,
+                Err(_) => Some(Err(Status::data_loss("Message stream lagged"))),
+            };
+
+            async move { result }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn send_message(&self, request: Request<SendMessageRequest>) -> Result<Response<SendMessageResponse>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let SendMessageRequest { network, message } = request.into_inner();
+        let message = message.ok_or_else(|| Status::invalid_argument("Message is empty"))?.try_into()?;
+
+        let id = self.next_call.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+        self.calls.lock().await.insert(id, sender);
+
+        let mut envelope = Envelope::new(&network, Direction::Outgoing, message);
+        envelope.origin = Some(Origin::Grpc(id));
 
-        Ok(Response::new(LoginResponse { token: username }))
+        let mut replies = Vec::new();
+        let sent = self.outgoing.send(envelope).await;
+
+        if sent.is_ok() {
+            let collect = async {
+                while let Some(envelope) = receiver.recv().await {
+                    replies.push((&envelope).into());
+
+                    if envelope.reply_end {
+                        break;
+                    }
+                }
+            };
+
+            // timing out is the normal end of requests without reply
+            let _ = timeout(REPLY_TIMEOUT, collect).await;
+        }
+        self.calls.lock().await.remove(&id);
+
+        sent.map_err(|_| Status::unavailable("Bouncer is not running"))?;
+
+        Ok(Response::new(SendMessageResponse { replies }))
+    }
+
+    async fn render_text(&self, request: Request<RenderTextRequest>) -> Result<Response<RenderTextResponse>, Status> {
+        authenticate(&self.tokens, &request).await?;
+
+        let RenderTextRequest { content, spans } = request.into_inner();
+
+        let spans = if content.is_empty() {
+            spans.into_iter().map(Span::try_from).collect::<Result<Vec<_>, _>>()?
+        } else {
+            format::parse(&content)
+        };
+
+        Ok(Response::new(RenderTextResponse {
+            content: format::to_irc(&spans),
+            spans: spans.iter().map(Into::into).collect(),
+            plain: format::to_plain(&spans),
+            html: format::to_html(&spans),
+        }))
     }
 }
 
-pub struct Server {}
+pub struct Server {
+    state: Arc<Mutex<State>>,
+    deltas: Sender<MembershipDelta>,
+    messages: Sender<pb::Envelope>,
+    // messages from SendMessage calls
+    outgoing: Mutex<mpsc::Receiver<Envelope>>,
+    calls: Calls,
+}
 
 impl Server {
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, controller: Controller, states: watch::Receiver<HashMap<String, NetworkState>>) -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (deltas, _) = channel(256);
+        let (messages, _) = channel(256);
+        let (outgoing, outgoing_receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+        let calls = Calls::default();
+        let tokens = Tokens::default();
+
+        let grpc_server = GrpcServer {
+            state: state.clone(),
+            deltas: deltas.clone(),
+            messages: messages.clone(),
+            outgoing,
+            calls: calls.clone(),
+            next_call: AtomicU64::new(0),
+            controller: controller.clone(),
+            tokens: tokens.clone(),
+        };
+        let admin_server = AdminServer::new(controller, tokens);
+
         spawn(async move {
-            let addr = (Ipv4Addr::new(0, 0, 0, 0), port);
+            let (mut health_reporter, health_server) = tonic_health::server::health_reporter();
+            // overall status is serving from the start otherwise
+            health_reporter.set_service_status("", ServingStatus::NotServing).await;
+            spawn(Self::health_loop(health_reporter.clone(), states));
+
+            let reflection_server = tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(pb::FILE_DESCRIPTOR_SET)
+                .register_encoded_file_descriptor_set(tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET)
+                .build()
+                .unwrap();
+
+            let listener = match TcpListener::bind((Ipv4Addr::new(0, 0, 0, 0), port)).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("gRPC server failed to listen: {}", err);
 
-            let server = pb::bouncer_server::BouncerServer::with_interceptor(GrpcServer {}, Server::check_auth);
-            transport::Server::builder().add_service(server).serve(addr.into()).await.unwrap();
+                    return;
+                }
+            };
+
+            // services are ready once listening, requests wait in backlog until served
+            health_reporter.set_service_status("", ServingStatus::Serving).await;
+            health_reporter.set_serving::<pb::bouncer_server::BouncerServer<GrpcServer>>().await;
+            health_reporter.set_serving::<pb::admin_server::AdminServer<AdminServer>>().await;
+
+            let result = transport::Server::builder()
+                .add_service(pb::bouncer_server::BouncerServer::new(grpc_server))
+                .add_service(pb::admin_server::AdminServer::new(admin_server))
+                .add_service(health_server)
+                .add_service(reflection_server)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await;
+
+            if let Err(err) = result {
+                error!("gRPC server failed: {}", err);
+            }
         });
 
-        Self {}
+        Self {
+            state,
+            deltas,
+            messages,
+            outgoing: Mutex::new(outgoing_receiver),
+            calls,
+        }
     }
 
-    fn check_auth(req: Request<()>) -> Result<Request<()>, Status> {
-        let token = MetadataValue::from_str("Bearer some-secret-token").unwrap();
+    // reports upstream connectivity of each network as `bouncer.network.<name>`
+    async fn health_loop(mut reporter: HealthReporter, mut states: watch::Receiver<HashMap<String, NetworkState>>) {
+        let mut reported = HashMap::<String, NetworkState>::new();
+
+        loop {
+            let current = states.borrow().clone();
+
+            for name in reported.keys().filter(|x| !current.contains_key(*x)) {
+                reporter.clear_service_status(&Self::health_service_name(name)).await;
+            }
+
+            for (name, state) in &current {
+                if reported.get(name) != Some(state) {
+                    let status = match state {
+                        NetworkState::Connected => ServingStatus::Serving,
+                        NetworkState::Connecting | NetworkState::Disconnected => ServingStatus::NotServing,
+                    };
+
+                    reporter.set_service_status(Self::health_service_name(name), status).await;
+                }
+            }
 
-        match req.metadata().get("authorization") {
-            Some(t) if token == t => Ok(req),
-            _ => Err(Status::unauthenticated("No valid auth token")),
+            reported = current;
+
+            if states.changed().await.is_err() {
+                break;
+            }
         }
     }
+
+    fn health_service_name(network: &str) -> String {
+        format!("bouncer.network.{}", network)
+    }
 }
 
 #[async_trait]
 impl Sink for Server {
-    fn stream(&self) -> BoxStream<Message> {
-        stream::empty().boxed()
+    fn stream(&self) -> BoxStream<'_, Envelope> {
+        stream::unfold(self, |server| async move {
+            let envelope = server.outgoing.lock().await.recv().await;
+
+            envelope.map(|x| (x, server))
+        })
+        .boxed()
     }
 
-    async fn broadcast(&self, _: &Message) -> io::Result<()> {
+    async fn broadcast(&self, envelope: &Envelope) -> io::Result<()> {
+        // our own messages aren't unread, and mark what they reply to as read
+        match envelope.direction {
+            Direction::Incoming => self.state.lock().await.apply(&envelope.network, &envelope.message),
+            Direction::Outgoing => self.state.lock().await.sent(&envelope.network, &envelope.message),
+        }
+
+        // no receiver is not an error
+        for delta in &envelope.deltas {
+            let _ = self.deltas.send(GrpcServer::convert_delta(&envelope.network, delta));
+        }
+
+        if let Some(Origin::Grpc(id)) = envelope.origin {
+            // call may have timed out already
+            if let Some(call) = self.calls.lock().await.get(&id) {
+                let _ = call.try_send(envelope.clone());
+            }
+        }
+
+        // replies are for their requester only, while echoes are seen by everyone
+        if envelope.origin.is_none() || envelope.direction == Direction::Outgoing {
+            let _ = self.messages.send(envelope.into());
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::User;
+    use crate::control::Control;
+    use crate::message::ChannelSnapshot;
+    use pb::bouncer_server::Bouncer;
+
+    fn server() -> GrpcServer {
+        let (sender, mut receiver) = mpsc::channel(16);
+        spawn(async move {
+            while let Some(control) = receiver.recv().await {
+                match control {
+                    Control::Authenticate { name, password, reply } => {
+                        let user = Some(User {
+                            name,
+                            password: String::new(),
+                            admin: false,
+                        })
+                        .filter(|_| password == "secret");
+                        let _ = reply.send(user);
+                    }
+                    // `#Test` with `op` on network `a`
+                    Control::Snapshot { network, reply } => {
+                        let _ = reply.send(Ok(Snapshot {
+                            network,
+                            channels: vec![ChannelSnapshot {
+                                name: "#Test".into(),
+                                topic: None,
+                                modes: String::new(),
+                                members: vec![member("@op")],
+                            }],
+                            ..Default::default()
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        GrpcServer {
+            state: Arc::new(Mutex::new(State::new())),
+            deltas: channel(16).0,
+            messages: channel(16).0,
+            outgoing: mpsc::channel(16).0,
+            calls: Calls::default(),
+            next_call: AtomicU64::new(0),
+            controller: Controller::new(sender),
+            tokens: Tokens::default(),
+        }
+    }
+
+    fn member(name: &str) -> MemberSnapshot {
+        MemberSnapshot {
+            nickname: name.trim_start_matches('@').into(),
+            prefixes: name[..name.len() - name.trim_start_matches('@').len()].into(),
+            hostmask: None,
+            away: None,
+        }
+    }
+
+    fn authorized<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {}", token).parse().unwrap());
+
+        request
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let server = server();
+
+        let denied = server.list_queries(Request::new(ListQueriesRequest::default())).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let denied = server.list_queries(authorized(ListQueriesRequest::default(), "some-secret-token")).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        let wrong = LoginRequest {
+            username: "user".into(),
+            password: "wrong".into(),
+        };
+        assert_eq!(server.login(Request::new(wrong)).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        let login = LoginRequest {
+            username: "user".into(),
+            password: "secret".into(),
+        };
+        let token = server.login(Request::new(login)).await.unwrap().into_inner().token;
+
+        let queries = server.list_queries(authorized(ListQueriesRequest::default(), &token)).await.unwrap();
+        assert!(queries.into_inner().queries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_members() {
+        let server = server();
+        let token = server.tokens.lock().await.issue("user".into());
+
+        let request = WatchMembersRequest {
+            channel: "#TEST".into(),
+            network: "a".into(),
+        };
+        let mut stream = server.watch_members(authorized(request, &token)).await.unwrap().into_inner();
+
+        let reset = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            (reset.network.as_ref(), reset.channel.as_ref(), reset.kind),
+            ("a", "#Test", Kind::Reset as i32)
+        );
+        assert_eq!(reset.members[0].prefixes, "@");
+
+        // other channels and networks are filtered out
+        for &(network, channel) in &[("a", "#other"), ("b", "#test"), ("a", "#test")] {
+            let delta = Delta {
+                channel: channel.into(),
+                kind: DeltaKind::Joined,
+                members: vec![member(network)],
+            };
+            server.deltas.send(GrpcServer::convert_delta(network, &delta)).unwrap();
+        }
+
+        let joined = stream.next().await.unwrap().unwrap();
+        assert_eq!((joined.network.as_ref(), joined.channel.as_ref()), ("a", "#test"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::irc::ISupport;
+use crate::message::Message;
+
+#[derive(Default)]
+pub struct Channel {
+    pub unread_count: u32,
+}
+
+#[derive(Default)]
+pub struct Query {
+    pub peer: String,
+    pub unread_count: u32,
+}
+
+// channels and queries of one network, keyed by its casemapping
+#[derive(Default)]
+struct Network {
+    isupport: ISupport,
+    channels: BTreeMap<String, Channel>,
+    queries: BTreeMap<String, Query>,
+}
+
+// unread counts built from messages broadcasted to sinks,
+// members and the rest of channel state are taken from upstream
+#[derive(Default)]
+pub struct State {
+    networks: HashMap<String, Network>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn channel(&self, network: &str, name: &str) -> Option<&Channel> {
+        let network = self.networks.get(network)?;
+
+        network.channels.get(&network.isupport.fold(name))
+    }
+
+    // queries of network, or of every network if empty
+    pub fn queries<'a>(&'a self, network: &'a str) -> impl Iterator<Item = (&'a String, &'a Query)> {
+        self.networks
+            .iter()
+            .filter(move |(name, _)| network.is_empty() || *name == network)
+            .flat_map(|(name, x)| x.queries.values().map(move |query| (name, query)))
+    }
+
+    // what the user sent to a channel or query has been read up to it
+    pub fn sent(&mut self, name: &str, message: &Message) {
+        if let Some(network) = self.networks.get_mut(name) {
+            network.sent(message);
+        }
+    }
+
+    pub fn apply(&mut self, name: &str, message: &Message) {
+        self.networks.entry(name.to_owned()).or_default().apply(message);
+    }
+}
+
+impl Network {
+    fn sent(&mut self, message: &Message) {
+        match message {
+            Message::Chat { channel, .. } | Message::Action { channel, .. } => {
+                if let Some(channel) = self.channels.get_mut(&self.isupport.fold(channel)) {
+                    channel.unread_count = 0;
+                }
+            }
+            Message::PrivateChat { peer, .. } | Message::PrivateAction { peer, .. } => {
+                if let Some(query) = self.queries.get_mut(&self.isupport.fold(peer)) {
+                    query.unread_count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply(&mut self, message: &Message) {
+        match message {
+            Message::Chat { channel, .. } | Message::Action { channel, .. } => {
+                self.channels.entry(self.isupport.fold(channel)).or_default().unread_count += 1;
+            }
+            Message::PrivateChat { peer, .. } | Message::PrivateAction { peer, .. } => {
+                let query = self.queries.entry(self.isupport.fold(peer)).or_insert_with(|| Query {
+                    peer: peer.clone(),
+                    unread_count: 0,
+                });
+                query.unread_count += 1;
+            }
+            // new connection, whose ISUPPORT follows
+            Message::Numeric { code, .. } if code == "001" => self.isupport = ISupport::default(),
+            Message::Numeric { code, args, .. } if code == "005" => {
+                // without client and trailing text
+                if let [_, tokens @ .., _] = args.as_slice() {
+                    self.isupport.apply(tokens);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NETWORK: &str = "test";
+
+    fn chat(channel: &str) -> Message {
+        Message::Chat {
+            sender: "other!other@other".into(),
+            channel: channel.into(),
+            content: "test".into(),
+        }
+    }
+
+    #[test]
+    fn test_unread() {
+        let mut state = State::new();
+
+        state.apply(NETWORK, &chat("#test"));
+
+        assert_eq!(state.channel(NETWORK, "#test").unwrap().unread_count, 1);
+
+        state.sent(NETWORK, &chat("#TEST"));
+        assert_eq!(state.channel(NETWORK, "#test").unwrap().unread_count, 0);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut state = State::new();
+
+        state.apply(
+            NETWORK,
+            &Message::PrivateChat {
+                sender: "Other!other@other".into(),
+                peer: "Other".into(),
+                content: "test".into(),
+            },
+        );
+
+        assert_eq!(
+            state
+                .queries("")
+                .next()
+                .map(|(network, query)| (network.as_ref(), query.peer.as_ref(), query.unread_count)),
+            Some((NETWORK, "Other", 1))
+        );
+
+        state.sent(
+            NETWORK,
+            &Message::PrivateChat {
+                sender: "me!me@me".into(),
+                peer: "other".into(),
+                content: "test".into(),
+            },
+        );
+        assert_eq!(state.queries(NETWORK).next().unwrap().1.unread_count, 0);
+    }
+
+    #[test]
+    fn test_isupport() {
+        let mut state = State::new();
+
+        state.apply(
+            NETWORK,
+            &Message::Numeric {
+                sender: "server".into(),
+                code: "005".into(),
+                args: vec!["me".into(), "CASEMAPPING=ascii".into(), "are supported by this server".into()],
+            },
+        );
+        state.apply(NETWORK, &chat("#Test"));
+        state.apply(NETWORK, &chat("#TEST"));
+
+        assert_eq!(state.channel(NETWORK, "#test").unwrap().unread_count, 2);
+
+        // `[` and `{` differ in ascii casemapping
+        state.apply(NETWORK, &chat("#a[b"));
+        assert!(state.channel(NETWORK, "#a{b").is_none());
+    }
+
+    #[test]
+    fn test_networks() {
+        let mut state = State::new();
+
+        state.apply("a", &chat("#TEST"));
+        state.apply("b", &chat("#other"));
+
+        assert_eq!(state.channel("a", "#test").unwrap().unread_count, 1);
+        assert!(state.channel("b", "#test").is_none());
+    }
+}
@@ -5,7 +5,7 @@ use futures::{
 };
 use tokio::io::Result;
 
-use crate::message::Message;
+use crate::message::Envelope;
 use crate::sink::Sink;
 
 pub struct History {}
@@ -18,11 +18,11 @@ impl History {
 
 #[async_trait]
 impl Sink for History {
-    fn stream(&self) -> BoxStream<Message> {
+    fn stream(&self) -> BoxStream<'_, Envelope> {
         stream::empty().boxed()
     }
 
-    async fn broadcast(&self, _: &Message) -> Result<()> {
+    async fn broadcast(&self, _: &Envelope) -> Result<()> {
         Ok(())
     }
 }
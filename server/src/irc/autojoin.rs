@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use super::{command::Command, isupport::Casemapping, message::Message};
+use crate::config::AutoJoin;
+
+// room for channels and keys in a JOIN line, without `JOIN ` and CRLF
+const LINE_LIMIT: usize = 512 - "JOIN ".len() - "\r\n".len();
+
+// channels of one upstream connection to join again after reconnecting
+pub struct JoinList {
+    channels: Vec<AutoJoin>,
+    // keys of JOINs not confirmed yet, by casefolded channel
+    pending: HashMap<String, String>,
+    // not saved to config yet
+    changed: bool,
+}
+
+impl JoinList {
+    pub fn new(channels: Vec<AutoJoin>) -> Self {
+        Self {
+            channels,
+            pending: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    pub fn channels(&self) -> &[AutoJoin] {
+        &self.channels
+    }
+
+    // edited in config, nothing to save back
+    pub fn set(&mut self, channels: Vec<AutoJoin>) {
+        self.channels = channels;
+    }
+
+    pub fn expect_key(&mut self, channel: &str, key: String, casemapping: Casemapping) {
+        self.pending.insert(casemapping.fold(channel), key);
+    }
+
+    pub fn joined(&mut self, channel: &str, casemapping: Casemapping) {
+        let key = self.pending.remove(&casemapping.fold(channel));
+
+        match self.channels.iter_mut().find(|x| casemapping.eq_ignore_case(&x.channel, channel)) {
+            // rejoined, key is kept unless a new one was given
+            Some(existing) => {
+                if key.is_some() && existing.key != key {
+                    existing.key = key;
+                    self.changed = true;
+                }
+            }
+            None => {
+                self.channels.push(AutoJoin {
+                    channel: channel.to_owned(),
+                    key,
+                });
+                self.changed = true;
+            }
+        }
+    }
+
+    pub fn parted(&mut self, channel: &str, casemapping: Casemapping) {
+        let len = self.channels.len();
+        self.channels.retain(|x| !casemapping.eq_ignore_case(&x.channel, channel));
+
+        self.changed |= self.channels.len() != len;
+    }
+
+    pub fn take_changed(&mut self) -> Option<Vec<AutoJoin>> {
+        if std::mem::take(&mut self.changed) {
+            Some(self.channels.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// JOIN lines for channels, at most `targmax` channels and 512 bytes each
+pub fn join_messages(channels: &[AutoJoin], targmax: Option<usize>) -> Vec<Message> {
+    // keys are positional, so keyed channels go first
+    let mut channels = channels.iter().collect::<Vec<_>>();
+    channels.sort_by_key(|x| x.key.is_none());
+
+    let mut result = Vec::new();
+    let mut names = Vec::<String>::new();
+    let mut keys = Vec::<String>::new();
+
+    for channel in channels {
+        let mut len = names.iter().chain(&keys).map(|x| x.len() + 1).sum::<usize>() + channel.channel.len();
+        if let Some(key) = &channel.key {
+            len += key.len() + 1;
+        }
+
+        if !names.is_empty() && (len > LINE_LIMIT || targmax.map(|x| names.len() >= x).unwrap_or(false)) {
+            result.push(join(std::mem::take(&mut names), std::mem::take(&mut keys)));
+        }
+
+        names.push(channel.channel.clone());
+        keys.extend(channel.key.clone());
+    }
+
+    if !names.is_empty() {
+        result.push(join(names, keys));
+    }
+
+    result
+}
+
+fn join(channels: Vec<String>, keys: Vec<String>) -> Message {
+    Message::from_command(None, Command::Join { channels, keys })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn autojoin(channel: &str, key: Option<&str>) -> AutoJoin {
+        AutoJoin {
+            channel: channel.into(),
+            key: key.map(|x| x.into()),
+        }
+    }
+
+    #[test]
+    fn test_track() {
+        let mut list = JoinList::new(vec![autojoin("#Rust", None)]);
+
+        list.joined("#rust", Casemapping::Rfc1459);
+
+        assert_eq!(list.take_changed(), None);
+
+        list.expect_key("#secret", "key".into(), Casemapping::Rfc1459);
+        list.joined("#Secret", Casemapping::Rfc1459);
+        list.parted("#RUST", Casemapping::Rfc1459);
+
+        assert_eq!(list.take_changed(), Some(vec![autojoin("#Secret", Some("key"))]));
+        assert_eq!(list.take_changed(), None);
+    }
+
+    #[test]
+    fn test_join_messages() {
+        let channels = vec![autojoin("#a", None), autojoin("#b", Some("key")), autojoin("#c", None)];
+
+        let messages = join_messages(&channels, None);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].args, vec!["#b,#a,#c", "key"]);
+
+        let messages = join_messages(&channels, Some(2));
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].args, vec!["#b,#a", "key"]);
+        assert_eq!(messages[1].args, vec!["#c"]);
+
+        let channels = (0..100).map(|x| autojoin(&format!("#channel{}", x), None)).collect::<Vec<_>>();
+
+        assert!(join_messages(&channels, None).iter().all(|x| x.args[0].len() <= LINE_LIMIT));
+    }
+}
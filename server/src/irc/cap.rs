@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+// capabilities requested from upstream when offered
+const WANTED: &[&str] = &[
+    "away-notify",
+    "batch",
+    "draft/multiline",
+    "echo-message",
+    "labeled-response",
+    "message-tags",
+    "server-time",
+];
+
+// IRCv3 capability negotiation state of one upstream connection
+#[derive(Default)]
+pub struct Caps {
+    // collected from multiline CAP LS
+    offered: BTreeSet<String>,
+    // like `max-bytes=4096` of `draft/multiline`
+    values: BTreeMap<String, String>,
+    enabled: BTreeSet<String>,
+}
+
+impl Caps {
+    // args of CAP after client, returns caps to request once LS is complete
+    pub fn ls(&mut self, args: &[String]) -> Option<Vec<String>> {
+        let (more, list) = match args {
+            [_, more, list] if more == "*" => (true, list),
+            [_, list, ..] => (false, list),
+            _ => return None,
+        };
+
+        for cap in list.split(' ').filter(|x| !x.is_empty()) {
+            let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+
+            self.offered.insert(name.to_owned());
+            if !value.is_empty() {
+                self.values.insert(name.to_owned(), value.to_owned());
+            }
+        }
+
+        if more {
+            None
+        } else {
+            Some(WANTED.iter().filter(|x| self.offered.contains(**x)).map(|x| (*x).to_owned()).collect())
+        }
+    }
+
+    pub fn ack(&mut self, list: &str) {
+        for cap in list.split(' ').filter(|x| !x.is_empty()) {
+            match cap.strip_prefix('-') {
+                Some(cap) => self.enabled.remove(cap),
+                None => self.enabled.insert(cap.to_owned()),
+            };
+        }
+    }
+
+    // cap-notify removal
+    pub fn del(&mut self, list: &str) {
+        for cap in list.split(' ') {
+            self.offered.remove(cap);
+            self.values.remove(cap);
+            self.enabled.remove(cap);
+        }
+    }
+
+    pub fn enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    // value of enabled cap
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.values.get(cap).filter(|_| self.enabled(cap)).map(|x| x.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|x| (*x).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut caps = Caps::default();
+
+        assert_eq!(caps.ls(&args(&["LS", "*", "batch sasl=PLAIN,EXTERNAL"])), None);
+        assert_eq!(
+            caps.ls(&args(&["LS", "labeled-response away-notify"])),
+            Some(args(&["away-notify", "batch", "labeled-response"]))
+        );
+
+        caps.ack("batch labeled-response");
+
+        assert!(caps.enabled("labeled-response"));
+        assert!(!caps.enabled("away-notify"));
+        assert_eq!(caps.value("sasl"), None);
+
+        caps.del("labeled-response");
+
+        assert!(!caps.enabled("labeled-response"));
+    }
+}
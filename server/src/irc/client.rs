@@ -1,133 +1,1021 @@
+use std::{
+    convert::TryFrom,
+    iter,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
 use async_trait::async_trait;
-use futures::{stream::BoxStream, FutureExt, StreamExt};
+use chrono::{DateTime, Utc};
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
 use log::{debug, error};
-use tokio::{io::Result, net::TcpStream, sync::Mutex};
+use tokio::{
+    io::{Error, ErrorKind, Result},
+    net::TcpStream,
+    sync::{watch, Mutex},
+    task,
+    time::sleep,
+};
 
 use super::{
-    message::{Message as IRCMessage, Reply as IRCReply},
+    autojoin::{self, JoinList},
+    cap::Caps,
+    command::Command,
+    correlate::{Correlator, Route},
+    ctcp,
+    encoding::Encoding,
+    isupport::Casemapping,
+    message::Message as IRCMessage,
+    nick::Nicks,
+    perform,
+    reply::Reply as IRCReply,
+    split::{self, Line},
+    state::State,
     transport::Transport,
 };
-use crate::message::Message;
+use crate::config::{AutoAway, AutoJoin, Ctcp, Network, NickServ, NickServCommand};
+use crate::message::{Direction, Envelope, Message, Origin, Snapshot};
 use crate::source::Source;
 
-// TODO lazy name
-struct Context {
-    names: Vec<String>,
+// away state managed on behalf of the user
+struct Away {
+    // empty disables auto away
+    message: String,
+    registered: bool,
+    // set by a client, which auto away leaves alone
+    explicit: bool,
+    auto: bool,
 }
 
 pub struct Client {
+    network: String,
     transport: Transport,
-    context: Mutex<Context>,
+    state: Mutex<State>,
+    caps: Mutex<Caps>,
+    correlator: Mutex<Correlator>,
+    ctcp: Mutex<ctcp::Responder>,
+    // number of attached clients
+    attached: watch::Receiver<usize>,
+    away: Mutex<Away>,
+    nicks: Mutex<Nicks>,
+    nickserv: Option<NickServ>,
+    joins: Mutex<JoinList>,
+    perform: Vec<String>,
+    perform_delay: Duration,
+    // reference of next multiline batch
+    next_batch: AtomicU64,
 }
 
 impl Client {
-    pub async fn new(host: String, port: u16) -> Result<Self> {
-        let stream = TcpStream::connect((host.as_ref(), port)).await?;
+    // ISON polling of primary nickname where MONITOR isn't supported
+    pub const REGAIN_INTERVAL: Duration = Duration::from_secs(60);
 
-        let transport = Transport::new(stream);
+    pub async fn new(network: &Network, ctcp: Ctcp, auto_away: AutoAway, attached: watch::Receiver<usize>) -> Result<Self> {
+        let nickname = network
+            .nicknames
+            .first()
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No nickname configured"))?;
+        let encoding = Encoding::new(&network.encoding, network.fallback_encoding.as_deref())?;
+        let stream = TcpStream::connect((network.host.as_ref(), network.port)).await?;
+
+        let transport = Transport::with_encoding(stream, encoding, network.throttle);
         let result = Self {
+            network: network.name.clone(),
             transport,
-            context: Mutex::new(Context { names: Vec::new() }),
+            state: Mutex::new(State::new(&nickname)),
+            caps: Mutex::new(Caps::default()),
+            correlator: Mutex::new(Correlator::default()),
+            ctcp: Mutex::new(ctcp::Responder::new(ctcp)),
+            attached,
+            away: Mutex::new(Away {
+                message: auto_away.message,
+                registered: false,
+                explicit: false,
+                auto: false,
+            }),
+            nicks: Mutex::new(Nicks::new(network.nicknames.clone())),
+            nickserv: network.nickserv.clone(),
+            joins: Mutex::new(JoinList::new(network.autojoin.clone())),
+            perform: network.perform.clone(),
+            perform_delay: Duration::from_millis(network.perform_delay),
+            next_batch: AtomicU64::new(0),
         };
 
+        // registration is held until CAP END
+        result.send_cap(&["LS", "302"]).await?;
         result
             .transport
-            .send_message(&IRCMessage::new(None, "USER", vec!["test", "0", "*", "test"]))
+            .send_message(&IRCMessage::from_command(
+                None,
+                Command::User {
+                    username: network.username.clone().unwrap_or_else(|| nickname.clone()),
+                    realname: network.realname.clone().unwrap_or_else(|| nickname.clone()),
+                },
+            ))
             .await?;
-        result.transport.send_message(&IRCMessage::new(None, "NICK", vec!["testtest"])).await?;
+        result.send_nick(nickname).await?;
 
         Ok(result)
     }
 
-    fn on_connected(&self) {}
+    async fn on_connected(&self) -> Result<()> {
+        self.away.lock().await.registered = true;
+
+        self.update_away().await?;
+        self.watch_primary().await?;
+
+        let nickname = self.state.lock().await.nickname.clone();
+        let commands = perform::messages(&self.perform, &nickname, &self.network);
+        let joins = self.join_messages().await;
+
+        // in background, so that delays don't hold up reading
+        task::spawn(Self::perform(self.transport.clone(), commands, self.perform_delay, joins));
+
+        Ok(())
+    }
+
+    // channels are joined after perform, which may identify or oper up first
+    async fn perform(transport: Transport, commands: Vec<IRCMessage>, delay: Duration, joins: Vec<IRCMessage>) {
+        for (i, message) in commands.iter().enumerate() {
+            if i > 0 {
+                sleep(delay).await;
+            }
+            debug!("Perform: {}", message);
+
+            if transport.send_message(message).await.is_err() {
+                return;
+            }
+        }
+
+        if !commands.is_empty() {
+            sleep(delay).await;
+        }
+
+        for message in joins {
+            if transport.send_message(&message).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // joins autojoin channels we're not in
+    async fn rejoin(&self) -> Result<()> {
+        for message in self.join_messages().await {
+            self.transport.send_message(&message).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn join_messages(&self) -> Vec<IRCMessage> {
+        let state = self.state.lock().await;
+        let joins = self.joins.lock().await;
+
+        let missing = joins
+            .channels()
+            .iter()
+            .filter(|x| state.channel(&x.channel).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        autojoin::join_messages(&missing, state.targmax("JOIN"))
+    }
+
+    // autojoin edited outside, joined right away if registered
+    pub async fn set_channels(&self, channels: Vec<AutoJoin>) -> Result<()> {
+        self.joins.lock().await.set(channels);
+
+        if self.nicks.lock().await.registered {
+            self.rejoin().await?;
+        }
+
+        Ok(())
+    }
+
+    // autojoin changed by our JOIN, PART or KICK since last call
+    pub async fn take_channels(&self) -> Option<Vec<AutoJoin>> {
+        self.joins.lock().await.take_changed()
+    }
+
+    pub async fn casemapping(&self) -> Casemapping {
+        self.state.lock().await.casemapping()
+    }
+
+    async fn track_channels(&self, command: &Command, sender: &str) {
+        let state = self.state.lock().await;
+        let casemapping = state.casemapping();
+        let me = state.is_me(sender.split('!').next().unwrap_or_default());
+        let mut joins = self.joins.lock().await;
+
+        match command {
+            Command::Join { channels, .. } if me => channels.iter().for_each(|x| joins.joined(x, casemapping)),
+            Command::Part { channels, .. } if me => channels.iter().for_each(|x| joins.parted(x, casemapping)),
+            Command::Kick { channel, user, .. } if state.is_me(user) => joins.parted(channel, casemapping),
+            _ => {}
+        }
+    }
+
+    // regains primary with NickServ and watches it with MONITOR where supported
+    async fn watch_primary(&self) -> Result<()> {
+        let mut nicks = self.nicks.lock().await;
+        let primary = nicks.primary().to_owned();
+        let (taken, monitor) = {
+            let state = self.state.lock().await;
+
+            (!state.is_me(&primary), state.supports("MONITOR"))
+        };
+
+        if monitor {
+            nicks.monitoring = true;
+            // RPL_MONOFFLINE right away takes primary if it's free
+            self.send_command("MONITOR", vec!["+".into(), primary.clone()]).await?;
+        }
+
+        if let (true, Some(nickserv)) = (taken, &self.nickserv) {
+            let command = match nickserv.command {
+                NickServCommand::Regain => "REGAIN",
+                NickServCommand::Ghost => "GHOST",
+            };
+            debug!("{} {} with NickServ", command, primary);
+
+            let message = IRCMessage::from_command(
+                None,
+                Command::Privmsg {
+                    target: "NickServ".into(),
+                    text: format!("{} {} {}", command, primary, nickserv.password),
+                },
+            );
+            self.transport.send_message(&message).await?;
+        }
+
+        Ok(())
+    }
+
+    // after NICK from a client, so that regaining doesn't undo it
+    async fn change_primary(&self, nickname: &str) -> Result<()> {
+        let mut nicks = self.nicks.lock().await;
+        let old = nicks.primary().to_owned();
+        nicks.set_primary(nickname.into());
+
+        // sent after NICK, so the new one is reported online if it went through
+        if nicks.monitoring {
+            self.send_command("MONITOR", vec!["-".into(), old]).await?;
+            self.send_command("MONITOR", vec!["+".into(), nickname.into()]).await?;
+        }
+
+        Ok(())
+    }
+
+    // polls primary with ISON when it's taken and not monitored
+    pub async fn regain(&self) -> Result<()> {
+        let mut nicks = self.nicks.lock().await;
+        let primary = nicks.primary().to_owned();
 
-    async fn handle_message(&self, message: &IRCMessage) -> Result<Option<Message>> {
+        if !nicks.registered || nicks.monitoring || self.state.lock().await.is_me(&primary) {
+            return Ok(());
+        }
+        nicks.polls += 1;
+
+        self.send_command("ISON", vec![primary]).await
+    }
+
+    // registration fallbacks and regain replies, true if the message was ours only
+    async fn handle_nick(&self, command: &Command) -> Result<bool> {
+        let (reply, args) = match command {
+            Command::Numeric { reply, args } => (*reply, args.as_slice()),
+            _ => return Ok(false),
+        };
+        let mut nicks = self.nicks.lock().await;
+        let casemapping = self.state.lock().await.casemapping();
+
+        match (reply, args) {
+            (IRCReply::RPL_WELCOME, _) => {
+                nicks.registered = true;
+
+                Ok(false)
+            }
+            (IRCReply::ERR_ERRONEUSNICKNAME | IRCReply::ERR_NICKNAMEINUSE | IRCReply::ERR_NICKCOLLISION | IRCReply::ERR_UNAVAILRESOURCE, _)
+                if !nicks.registered =>
+            {
+                let nickname = nicks.next();
+                debug!("Nickname rejected, trying {}", nickname);
+
+                self.send_nick(nickname).await?;
+
+                Ok(true)
+            }
+            (IRCReply::ERR_NICKNAMEINUSE | IRCReply::ERR_UNAVAILRESOURCE, [_, nickname, ..])
+                if nicks.regaining && nicks.is_primary(nickname, casemapping) =>
+            {
+                nicks.regaining = false;
+
+                Ok(true)
+            }
+            (IRCReply::RPL_MONONLINE | IRCReply::RPL_MONOFFLINE, [_, targets])
+                if nicks.monitoring && targets.split(',').all(|x| nicks.is_primary(x, casemapping)) =>
+            {
+                if reply == IRCReply::RPL_MONOFFLINE {
+                    self.take_primary(&mut nicks).await?;
+                }
+
+                Ok(true)
+            }
+            (IRCReply::RPL_ISON, [_, online])
+                if nicks.polls > 0 && online.split(' ').filter(|x| !x.is_empty()).all(|x| nicks.is_primary(x, casemapping)) =>
+            {
+                nicks.polls -= 1;
+
+                if online.trim().is_empty() {
+                    self.take_primary(&mut nicks).await?;
+                }
+
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // primary went offline
+    async fn take_primary(&self, nicks: &mut Nicks) -> Result<()> {
+        let primary = nicks.primary().to_owned();
+        if self.state.lock().await.is_me(&primary) {
+            return Ok(());
+        }
+        nicks.regaining = true;
+        debug!("Regaining {}", primary);
+
+        self.send_nick(primary).await
+    }
+
+    async fn send_nick(&self, nickname: String) -> Result<()> {
+        self.transport
+            .send_message(&IRCMessage::from_command(None, Command::Nick { nickname }))
+            .await
+    }
+
+    async fn send_command(&self, command: &str, args: Vec<String>) -> Result<()> {
+        let command = Command::Other {
+            command: command.into(),
+            args,
+        };
+
+        self.transport.send_message(&IRCMessage::from_command(None, command)).await
+    }
+
+    // marks us away when the last client detaches, and back when one attaches
+    pub async fn update_away(&self) -> Result<()> {
+        let detached = *self.attached.borrow() == 0;
+        let mut away = self.away.lock().await;
+
+        let wanted = detached && away.registered && !away.explicit && !away.message.is_empty();
+        if wanted == away.auto {
+            return Ok(());
+        }
+        away.auto = wanted;
+
+        let message = if wanted { Some(away.message.clone()) } else { None };
+        debug!("Auto away: {:?}", message);
+
+        self.transport
+            .send_message(&IRCMessage::from_command(None, Command::Away { message }))
+            .await
+    }
+
+    async fn send_cap(&self, args: &[&str]) -> Result<()> {
+        let args = args.iter().map(|x| (*x).to_owned()).collect();
+
+        self.transport.send_message(&IRCMessage::from_command(None, Command::Cap { args })).await
+    }
+
+    async fn handle_cap(&self, args: &[String]) -> Result<()> {
+        // first arg is our nickname or `*`
+        let (subcommand, list) = match args {
+            [_, subcommand, .., list] => (subcommand.as_ref(), list.as_ref()),
+            _ => return Ok(()),
+        };
+
+        match subcommand {
+            "LS" => {
+                let wanted = self.caps.lock().await.ls(&args[1..]);
+
+                match wanted {
+                    Some(wanted) if !wanted.is_empty() => self.send_cap(&["REQ", &wanted.join(" ")]).await?,
+                    Some(_) => self.send_cap(&["END"]).await?,
+                    None => {}
+                }
+            }
+            "ACK" => {
+                self.caps.lock().await.ack(list);
+                self.send_cap(&["END"]).await?;
+            }
+            "NAK" => self.send_cap(&["END"]).await?,
+            "DEL" => self.caps.lock().await.del(list),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // replies to a client's request are relayed as they come, state is applied already
+    async fn handle_message(&self, message: &IRCMessage, routed: bool) -> Result<Vec<Message>> {
         debug!("From Origin: {}", message);
 
-        Ok(match message.command.as_ref() {
-            "PING" => {
-                let response = IRCMessage::new(None, "PONG", vec![message.args[0].as_ref()]);
+        let command = match Command::try_from(message) {
+            Ok(command) => command,
+            Err(err) => {
+                error!("Invalid message from origin: {}", err);
 
-                self.transport.send_message(&response).await?;
+                return Ok(Vec::new());
+            }
+        };
+        let sender = Self::sender(message);
 
-                None
+        if self.handle_nick(&command).await? {
+            return Ok(Vec::new());
+        }
+        self.track_channels(&command, &sender).await;
+
+        // names of joined channels are collected in state, and sent to clients as a whole
+        let state = self.state.lock().await;
+        let collected = !routed
+            && match &command {
+                Command::Numeric {
+                    reply: IRCReply::RPL_NAMREPLY,
+                    args,
+                } => args.get(2),
+                Command::Numeric {
+                    reply: IRCReply::RPL_ENDOFNAMES,
+                    args,
+                } => args.get(1),
+                _ => None,
             }
-            IRCReply::RPL_ENDOFMOTD | IRCReply::ERR_NOMOTD => {
-                // RPL_ENDOFMOTD | ERR_NOMOTD
-                self.on_connected();
+            .map(|x| state.channel(x).is_some())
+            .unwrap_or(false);
+        drop(state);
+
+        Ok(match command {
+            Command::Ping { token } => {
+                self.transport
+                    .send_message(&IRCMessage::from_command(None, Command::Pong { token }))
+                    .await?;
 
-                None
+                Vec::new()
             }
-            "PRIVMSG" => Some(Message::Chat {
-                channel: message.args[0].clone(),
-                content: message.args[1].clone(),
-                sender: message.prefix.as_ref().unwrap().raw().into(),
-            }),
-            "JOIN" => Some(Message::JoinedChannel {
-                channel: message.args[0].clone(),
-                sender: message.prefix.as_ref().unwrap().raw().into(),
-            }),
-            IRCReply::RPL_NAMREPLY => {
-                if let [_client, _symbol, _channel, items] = message.args.as_slice() {
-                    let mut context = self.context.lock().await;
-                    context
-                        .names
-                        .extend(items.split(' ').filter_map(|x| if !x.is_empty() { Some(x.to_owned()) } else { None }));
-
-                    None
+            Command::Numeric {
+                reply: IRCReply::RPL_ENDOFMOTD,
+                ..
+            }
+            | Command::Numeric {
+                reply: IRCReply::ERR_NOMOTD, ..
+            } => {
+                self.on_connected().await?;
+
+                Vec::new()
+            }
+            Command::Cap { args } => {
+                self.handle_cap(&args).await?;
+
+                Vec::new()
+            }
+            // labeled-response framing, routed by correlator
+            Command::Other { command, .. } if command == "BATCH" || command == "ACK" => Vec::new(),
+            // queries other than ACTION are relayed as is, so that they don't look like chat
+            Command::Privmsg { target, text } if ctcp::parse(&text).map(|(x, _)| x != "ACTION").unwrap_or(false) => {
+                self.handle_ctcp(&sender, &text).await?;
+
+                vec![Message::Raw {
+                    sender,
+                    command: "PRIVMSG".into(),
+                    args: vec![target, text],
+                }]
+            }
+            Command::Notice { target, text } if ctcp::parse(&text).is_some() => vec![Message::Raw {
+                sender,
+                command: "NOTICE".into(),
+                args: vec![target, text],
+            }],
+            Command::Privmsg { target, text } => {
+                let state = self.state.lock().await;
+                let action = ctcp::parse_action(&text).map(|x| x.to_owned());
+
+                vec![if state.is_channel(&target) {
+                    match action {
+                        Some(content) => Message::Action {
+                            sender,
+                            channel: target,
+                            content,
+                        },
+                        None => Message::Chat {
+                            sender,
+                            channel: target,
+                            content: text,
+                        },
+                    }
                 } else {
-                    panic!()
+                    // our own echo is addressed to peer, others are addressed to us
+                    let nickname = sender.split('!').next().unwrap_or_default();
+                    let peer = if state.is_me(nickname) { target } else { nickname.to_owned() };
+
+                    match action {
+                        Some(content) => Message::PrivateAction { sender, peer, content },
+                        None => Message::PrivateChat { sender, peer, content: text },
+                    }
+                }]
+            }
+            Command::Notice { target, text } => vec![Message::Notice {
+                sender,
+                target,
+                content: text,
+            }],
+            Command::Join { channels, .. } => channels
+                .into_iter()
+                .map(|channel| Message::JoinedChannel {
+                    sender: sender.clone(),
+                    channel,
+                })
+                .collect(),
+            Command::Part { channels, reason } => channels
+                .into_iter()
+                .map(|channel| Message::PartedChannel {
+                    sender: sender.clone(),
+                    channel,
+                    reason: reason.clone(),
+                })
+                .collect(),
+            Command::Quit { reason } => vec![Message::Quit { sender, reason }],
+            Command::Kick { channel, user, reason } => vec![Message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            }],
+            Command::Nick { nickname } => vec![Message::NickChanged { sender, nickname }],
+            Command::Topic { channel, topic: Some(topic) } => vec![Message::Topic { sender, channel, topic }],
+            Command::Mode { target, modes } if !modes.is_empty() => vec![Message::Mode { sender, target, modes }],
+            Command::Invite { nickname, channel } => vec![Message::Invite {
+                sender,
+                user: nickname,
+                channel,
+            }],
+            Command::Away { message } => vec![Message::Away { sender, message }],
+            Command::Error { message } => vec![Message::Error { message }],
+            Command::Numeric {
+                reply: IRCReply::RPL_NAMREPLY,
+                ..
+            } if collected => Vec::new(),
+            Command::Numeric {
+                reply: IRCReply::RPL_ENDOFNAMES,
+                args,
+            } if collected => match args.as_slice() {
+                [_, channel, ..] => {
+                    let state = self.state.lock().await;
+                    let users = state
+                        .channel(channel)
+                        .map(|x| x.members.values().map(|x| format!("{}{}", x.prefixes, x.nickname)).collect())
+                        .unwrap_or_default();
+
+                    vec![Message::UsersList {
+                        channel: channel.clone(),
+                        users,
+                    }]
                 }
+                _ => Vec::new(),
+            },
+            Command::Numeric { reply, args } => vec![Message::Numeric {
+                sender,
+                code: reply.to_string(),
+                args,
+            }],
+            command => vec![Message::Raw {
+                sender,
+                command: command.name(),
+                args: command.args(),
+            }],
+        })
+    }
+
+    fn envelope(&self, raw: &IRCMessage, message: Message, direction: Direction, route: Option<Route>) -> Envelope {
+        let mut envelope = Envelope::new(&self.network, direction, message);
+
+        if let Some(route) = route {
+            envelope.origin = Some(route.origin);
+            envelope.reply_end = route.last;
+        }
+
+        if let Some(time) = raw.tag("time").and_then(|x| DateTime::parse_from_rfc3339(x).ok()) {
+            envelope.time = time.with_timezone(&Utc);
+        }
+        if let Some(id) = raw.tag("msgid") {
+            envelope.id = id.into();
+        }
+
+        envelope
+    }
+
+    // answers on behalf of the user when no client is attached to do it
+    async fn handle_ctcp(&self, sender: &str, text: &str) -> Result<()> {
+        if *self.attached.borrow() > 0 {
+            return Ok(());
+        }
+
+        let nickname = sender.split('!').next().unwrap_or_default();
+        // our own query echoed back
+        if nickname.is_empty() || self.state.lock().await.is_me(nickname) {
+            return Ok(());
+        }
+
+        let reply = match ctcp::parse(text) {
+            Some((command, params)) => self.ctcp.lock().await.respond(command, params),
+            None => None,
+        };
+
+        if let Some(reply) = reply {
+            debug!("CTCP reply to {}: {}", nickname, reply);
+
+            let message = IRCMessage::from_command(
+                None,
+                Command::Notice {
+                    target: nickname.into(),
+                    text: reply,
+                },
+            );
+            self.transport.send_message(&message).await?;
+        }
+
+        Ok(())
+    }
+
+    // our message sent back by `echo-message`
+    async fn is_echo(&self, message: &IRCMessage) -> bool {
+        let nickname = match (message.command.as_ref(), &message.prefix) {
+            ("PRIVMSG" | "NOTICE", Some(prefix)) => prefix.raw().split('!').next().unwrap_or_default(),
+            _ => return false,
+        };
+
+        self.state.lock().await.is_me(nickname)
+    }
+
+    // sent message as other clients should see it
+    fn local_echo(message: &Message, sender: String) -> Option<Message> {
+        Some(match message.clone() {
+            Message::Chat { channel, content, .. } => Message::Chat { sender, channel, content },
+            Message::Action { channel, content, .. } => Message::Action { sender, channel, content },
+            Message::PrivateChat { peer, content, .. } => Message::PrivateChat { sender, peer, content },
+            Message::PrivateAction { peer, content, .. } => Message::PrivateAction { sender, peer, content },
+            Message::Notice { target, content, .. } => Message::Notice { sender, target, content },
+            Message::Away { message, .. } => Message::Away { sender, message },
+            _ => return None,
+        })
+    }
+
+    fn sender(message: &IRCMessage) -> String {
+        message.prefix.as_ref().map(|x| x.raw().to_owned()).unwrap_or_default()
+    }
+
+    // text split to fit upstream line limit, as multiline batches where supported
+    async fn split_message(&self, message: &Message) -> Option<Vec<IRCMessage>> {
+        let (notice, target, text, action) = match message {
+            Message::Chat { channel, content, .. } => (false, channel, content, false),
+            Message::Action { channel, content, .. } => (false, channel, content, true),
+            Message::PrivateChat { peer, content, .. } => (false, peer, content, false),
+            Message::PrivateAction { peer, content, .. } => (false, peer, content, true),
+            Message::Notice { target, content, .. } => (true, target, content, false),
+            _ => return None,
+        };
+        let command = |text: String| {
+            let target = target.clone();
+            let command = if notice {
+                Command::Notice { target, text }
+            } else {
+                Command::Privmsg { target, text }
+            };
+
+            IRCMessage::from_command(None, command)
+        };
+
+        let hostmask = self.state.lock().await.hostmask();
+        let mut budget = split::budget(&hostmask, if notice { "NOTICE" } else { "PRIVMSG" }, target);
+        if action {
+            budget = budget.saturating_sub(ctcp::action("").len());
+        }
+        let lines = split::split(text, budget);
+
+        // each line of an action is an action of its own
+        let limits = self.caps.lock().await.value("draft/multiline").and_then(split::multiline_limits);
+        let (max_bytes, max_lines) = match limits {
+            Some(limits) if !action && lines.len() > 1 => limits,
+            _ => {
+                return Some(
+                    lines
+                        .into_iter()
+                        .filter(|x| !x.text.is_empty())
+                        .map(|x| command(if action { ctcp::action(&x.text) } else { x.text }))
+                        .collect(),
+                )
             }
-            IRCReply::RPL_ENDOFNAMES => {
-                let mut context = self.context.lock().await;
+        };
 
-                let channel = message.args[1].to_owned();
-                let names = std::mem::take(&mut context.names);
+        let mut result = Vec::new();
+        for batch in split::batches(lines, max_bytes, max_lines) {
+            if let [Line { text, .. }] = batch.as_slice() {
+                result.push(command(text.clone()));
 
-                Some(Message::UsersList { channel, users: names })
+                continue;
             }
-            _ => {
-                error!("Unhandled {}", message.command);
 
-                None
+            let reference = format!("ml{}", self.next_batch.fetch_add(1, Ordering::Relaxed));
+            let frame = |sign: char, args: Vec<String>| {
+                let args = iter::once(format!("{}{}", sign, reference)).chain(args).collect();
+
+                IRCMessage::from_command(
+                    None,
+                    Command::Other {
+                        command: "BATCH".into(),
+                        args,
+                    },
+                )
+            };
+
+            result.push(frame('+', vec!["draft/multiline".into(), target.clone()]));
+            for line in batch {
+                let mut message = command(line.text);
+                message.tags.push(("batch".into(), reference.clone()));
+                if line.concat {
+                    message.tags.push(("draft/multiline-concat".into(), String::new()));
+                }
+
+                result.push(message);
             }
-        })
+            result.push(frame('-', Vec::new()));
+        }
+
+        Some(result)
     }
 
-    fn convert_message(&self, message: &Message) -> IRCMessage {
-        match message {
-            Message::Chat { channel, content, .. } => IRCMessage {
-                prefix: None,
-                command: "PRIVMSG".into(),
-                args: vec![channel.into(), content.into()],
+    fn convert_message(&self, message: &Message) -> Option<IRCMessage> {
+        let command = match message.clone() {
+            Message::Chat { channel, content, .. } => Command::Privmsg {
+                target: channel,
+                text: content,
             },
-            Message::JoinChannel { channel } => IRCMessage {
-                prefix: None,
-                command: "JOIN".into(),
-                args: vec![channel.into()],
+            Message::Action { channel, content, .. } => Command::Privmsg {
+                target: channel,
+                text: ctcp::action(&content),
             },
-            _ => unreachable!(),
-        }
+            Message::PrivateChat { peer, content, .. } => Command::Privmsg { target: peer, text: content },
+            Message::PrivateAction { peer, content, .. } => Command::Privmsg {
+                target: peer,
+                text: ctcp::action(&content),
+            },
+            Message::Notice { target, content, .. } => Command::Notice { target, text: content },
+            Message::Topic { channel, topic, .. } => Command::Topic { channel, topic: Some(topic) },
+            Message::Mode { target, modes, .. } => Command::Mode { target, modes },
+            Message::Kick { channel, user, reason, .. } => Command::Kick { channel, user, reason },
+            Message::Invite { user, channel, .. } => Command::Invite { nickname: user, channel },
+            Message::Away { message, .. } => Command::Away { message },
+            Message::JoinChannel { channel, key } => Command::Join {
+                channels: vec![channel],
+                keys: key.into_iter().collect(),
+            },
+            Message::PartChannel { channel, reason } => Command::Part {
+                channels: vec![channel],
+                reason,
+            },
+            Message::ChangeNick { nickname } => Command::Nick { nickname },
+            Message::Raw { command, args, .. } => Command::Other { command, args },
+            Message::JoinedChannel { .. }
+            | Message::PartedChannel { .. }
+            | Message::Quit { .. }
+            | Message::NickChanged { .. }
+            | Message::UsersList { .. }
+            | Message::Error { .. }
+            | Message::Numeric { .. } => return None,
+        };
+
+        Some(IRCMessage::from_command(None, command))
     }
 }
 
 #[async_trait]
 impl Source for Client {
-    async fn stream(&self) -> BoxStream<Message> {
+    async fn stream<'a>(&'a self) -> BoxStream<'a, Envelope> {
         self.transport
             .stream()
-            .await
-            .filter_map(move |message| async move { self.handle_message(&message).await.unwrap() }.boxed())
+            .then(move |message| {
+                async move {
+                    let casemapping = self.casemapping().await;
+                    let mut route = self.correlator.lock().await.reply(&message, casemapping);
+                    let echo = self.is_echo(&message).await;
+
+                    // unlabeled echo goes to everyone but its sender
+                    if echo && route.is_none() {
+                        route = self.correlator.lock().await.echo(&message).map(|origin| Route { origin, last: true });
+                    }
+
+                    let direction = if echo { Direction::Outgoing } else { Direction::Incoming };
+                    let deltas = self.state.lock().await.apply(&message);
+                    let messages = self.handle_message(&message, route.is_some()).await?;
+
+                    let mut envelopes = messages
+                        .into_iter()
+                        .map(|x| self.envelope(&message, x, direction, route))
+                        .collect::<Vec<_>>();
+                    // once per line
+                    if let Some(envelope) = envelopes.first_mut() {
+                        envelope.deltas = deltas;
+                    }
+
+                    Ok(envelopes)
+                }
+                .boxed()
+            })
+            // connection is unusable after write failure, so the stream ends to let it reconnect
+            .take_while(|result: &Result<Vec<Envelope>>| {
+                if let Err(err) = result {
+                    error!("Upstream connection failed: {}", err);
+                }
+
+                future::ready(result.is_ok())
+            })
+            .flat_map(|result| stream::iter(result.unwrap_or_default()))
             .boxed()
     }
 
-    async fn send_message(&self, message: &Message) -> Result<()> {
-        let message = self.convert_message(message);
-        debug!("To Origin: {}", message);
+    async fn send_message(&self, message: &Message, origin: Option<Origin>) -> Result<Option<Envelope>> {
+        let mut raws = match self.split_message(message).await {
+            Some(raws) => raws,
+            None => match self.convert_message(message) {
+                Some(raw) => vec![raw],
+                None => {
+                    error!("Message can't be sent to origin");
 
-        self.transport.send_message(&message).await?;
+                    return Ok(None);
+                }
+            },
+        };
 
-        Ok(())
+        let (labeled, echoed) = {
+            let caps = self.caps.lock().await;
+
+            (caps.enabled("labeled-response"), caps.enabled("echo-message"))
+        };
+
+        if let Some(origin) = origin {
+            let mut correlator = self.correlator.lock().await;
+
+            for raw in raws.iter_mut() {
+                match raw.command.as_ref() {
+                    // a batch is labeled as a whole, and its lines are tracked as echoes otherwise
+                    "BATCH" if labeled && raw.args.first().map(|x| x.starts_with('+')).unwrap_or(false) => {
+                        correlator.request(raw, origin, true, echoed)
+                    }
+                    "BATCH" => {}
+                    _ if labeled && raw.tag("batch").is_some() => {}
+                    _ => correlator.request(raw, origin, labeled, echoed),
+                }
+            }
+        }
+
+        // key is saved to autojoin once the JOIN is confirmed
+        if let Message::JoinChannel { channel, key: Some(key) } = message {
+            let casemapping = self.state.lock().await.casemapping();
+            self.joins.lock().await.expect_key(channel, key.clone(), casemapping);
+        }
+
+        // explicit away replaces auto away
+        if let Message::Away { message, .. } = message {
+            let mut away = self.away.lock().await;
+            away.explicit = message.is_some();
+            away.auto = false;
+        }
+
+        for raw in &raws {
+            debug!("To Origin: {}", raw);
+
+            self.transport.send_message(raw).await?;
+        }
+
+        if let Message::ChangeNick { nickname } = message {
+            self.change_primary(nickname).await?;
+        }
+
+        // AWAY is not covered by echo-message, but other clients should know it
+        if echoed && !matches!(message, Message::Away { .. }) {
+            return Ok(None);
+        }
+
+        let sender = self.state.lock().await.hostmask();
+
+        Ok(Self::local_echo(message, sender).map(|x| {
+            let mut envelope = Envelope::new(&self.network, Direction::Outgoing, x);
+            envelope.origin = origin;
+
+            envelope
+        }))
+    }
+
+    async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = self.state.lock().await.snapshot();
+        snapshot.network = self.network.clone();
+        snapshot.queued = self.transport.queued();
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    // client registering to a fake upstream, which is returned to write lines with
+    async fn client() -> (Client, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut network = Network::new("test".into(), "127.0.0.1".into(), listener.local_addr().unwrap().port());
+        network.nicknames = vec!["me".into()];
+        let (_, attached) = watch::channel(0);
+
+        let client = Client::new(&network, Ctcp::default(), AutoAway::default(), attached).await.unwrap();
+        let (upstream, _) = listener.accept().await.unwrap();
+
+        (client, upstream)
+    }
+
+    #[tokio::test]
+    async fn test_join_part() {
+        let (client, mut upstream) = client().await;
+
+        upstream.write_all(b":me!u@h JOIN #a,#b\r\n:other!u@h PART #a,#b :bye\r\n").await.unwrap();
+
+        let messages = client
+            .stream()
+            .await
+            .take(4)
+            .map(|x| match x.message {
+                Message::JoinedChannel { sender, channel } => (sender, channel, None),
+                Message::PartedChannel { sender, channel, reason } => (sender, channel, reason),
+                _ => panic!("not a join or part"),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            messages,
+            vec![
+                ("me!u@h".into(), "#a".into(), None),
+                ("me!u@h".into(), "#b".into(), None),
+                ("other!u@h".into(), "#a".into(), Some("bye".into())),
+                ("other!u@h".into(), "#b".into(), Some("bye".into())),
+            ]
+        );
+        assert!(client.state.lock().await.channel("#b").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_names() {
+        let (client, mut upstream) = client().await;
+        let mut stream = client.stream().await;
+
+        upstream
+            .write_all(b":me!u@h JOIN #a\r\n:server 353 me = #a :@me other\r\n:server 366 me #a :End of /NAMES list.\r\n")
+            .await
+            .unwrap();
+        assert!(matches!(stream.next().await.unwrap().message, Message::JoinedChannel { .. }));
+        assert!(matches!(
+            stream.next().await.unwrap().message,
+            Message::UsersList { channel, users } if channel == "#a" && users == vec!["@me", "other"]
+        ));
+
+        // channel we're not in
+        upstream
+            .write_all(b":server 353 me = #b :someone\r\n:server 366 me #b :End of /NAMES list.\r\n")
+            .await
+            .unwrap();
+        assert!(matches!(stream.next().await.unwrap().message, Message::Numeric { code, args, .. } if code == "353" && args[3] == "someone"));
+        assert!(matches!(stream.next().await.unwrap().message, Message::Numeric { code, .. } if code == "366"));
+
+        // asked by a client
+        let names = Message::Raw {
+            sender: String::new(),
+            command: "NAMES".into(),
+            args: vec!["#a".into()],
+        };
+        client.send_message(&names, Some(Origin::Irc(1))).await.unwrap();
+        upstream
+            .write_all(b":server 353 me = #a :@me other\r\n:server 366 me #a :End of /NAMES list.\r\n")
+            .await
+            .unwrap();
+
+        let reply = stream.next().await.unwrap();
+        assert_eq!(reply.origin, Some(Origin::Irc(1)));
+        assert!(matches!(reply.message, Message::Numeric { code, .. } if code == "353"));
+        let reply = stream.next().await.unwrap();
+        assert!(reply.reply_end);
+        assert!(matches!(reply.message, Message::Numeric { code, .. } if code == "366"));
     }
 }
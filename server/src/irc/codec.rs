@@ -0,0 +1,172 @@
+use std::io;
+
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{encoding::Encoding, message::Message};
+
+// 8191 bytes of tags and 512 bytes of message
+pub const MAX_LINE_LENGTH: usize = 8191 + 512;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Overflow {
+    // keeps the first part of the line and discards the rest
+    Truncate,
+    // fails the stream, closing the connection
+    Disconnect,
+}
+
+pub struct LineCodec {
+    encoding: Encoding,
+    max_length: usize,
+    overflow: Overflow,
+    // position to resume searching newline from
+    next_index: usize,
+    // rest of truncated line is being dropped
+    discarding: bool,
+}
+
+impl LineCodec {
+    pub fn new(encoding: Encoding, max_length: usize, overflow: Overflow) -> Self {
+        Self {
+            encoding,
+            max_length,
+            overflow,
+            next_index: 0,
+            discarding: false,
+        }
+    }
+
+    fn parse(&self, line: &[u8]) -> Option<Message> {
+        let message = Message::from_raw(self.encoding.decode(line));
+
+        // empty lines are allowed and ignored
+        if message.command.is_empty() {
+            None
+        } else {
+            Some(message)
+        }
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Message>> {
+        loop {
+            let newline = buf[self.next_index..].iter().position(|x| *x == b'\n').map(|x| self.next_index + x);
+            let length = newline.unwrap_or_else(|| buf.len());
+
+            if length > self.max_length && self.overflow == Overflow::Disconnect {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+            }
+
+            let line = match newline {
+                Some(newline) => {
+                    let mut line = buf.split_to(newline + 1);
+                    line.truncate(self.max_length);
+
+                    // rest of truncated line ends here
+                    (!std::mem::take(&mut self.discarding)).then_some(line)
+                }
+                None if length > self.max_length => {
+                    let line = buf.split_to(self.max_length);
+
+                    // keep discarding until newline
+                    (!std::mem::replace(&mut self.discarding, true)).then_some(line)
+                }
+                None => {
+                    self.next_index = buf.len();
+
+                    return Ok(None);
+                }
+            };
+            self.next_index = 0;
+
+            if let Some(message) = line.and_then(|x| self.parse(&x)) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Message>> {
+        if let Some(message) = self.decode(buf)? {
+            return Ok(Some(message));
+        }
+
+        // unterminated last line
+        let line = buf.split();
+        self.next_index = 0;
+
+        if line.is_empty() || std::mem::take(&mut self.discarding) {
+            Ok(None)
+        } else {
+            Ok(self.parse(&line))
+        }
+    }
+}
+
+impl Encoder<Message> for LineCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, buf: &mut BytesMut) -> io::Result<()> {
+        buf.put_slice(&self.encoding.encode(&message.raw()));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(codec: &mut LineCodec, raw: &[u8]) -> io::Result<Vec<String>> {
+        let mut buf = BytesMut::from(raw);
+        let mut result = Vec::new();
+
+        while let Some(message) = codec.decode(&mut buf)? {
+            result.push(message.to_string());
+        }
+        while let Some(message) = codec.decode_eof(&mut buf)? {
+            result.push(message.to_string());
+        }
+
+        Ok(result)
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut codec = LineCodec::new(Encoding::default(), MAX_LINE_LENGTH, Overflow::Truncate);
+
+        let lines = decode_all(&mut codec, b"PING a\r\n\r\nPING b\nPING c").unwrap();
+
+        assert_eq!(lines, vec!["PING a", "PING b", "PING c"]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut codec = LineCodec::new(Encoding::default(), 10, Overflow::Truncate);
+
+        let lines = decode_all(&mut codec, b"PRIVMSG #a :0123456789012345\r\nPING a\r\n").unwrap();
+
+        assert_eq!(lines, vec!["PRIVMSG #a", "PING a"]);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut codec = LineCodec::new(Encoding::default(), 10, Overflow::Disconnect);
+
+        assert!(decode_all(&mut codec, b"PRIVMSG #a :0123456789012345\r\n").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = LineCodec::new(Encoding::new("cp949", None).unwrap(), MAX_LINE_LENGTH, Overflow::Truncate);
+        let mut buf = BytesMut::new();
+
+        codec.encode(Message::new(None, "PRIVMSG", vec!["#a", "안녕"]), &mut buf).unwrap();
+
+        assert_eq!(&buf[..], b"PRIVMSG #a \xbe\xc8\xb3\xe7\r\n");
+    }
+}
@@ -0,0 +1,269 @@
+use std::{convert::TryFrom, fmt, iter};
+
+use super::{
+    message::{Message, Prefix},
+    reply::Reply,
+};
+
+#[derive(Debug)]
+pub struct Error {
+    pub command: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not enough parameters for {}", self.command)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Command {
+    Pass { password: String },
+    Nick { nickname: String },
+    User { username: String, realname: String },
+    Ping { token: String },
+    Pong { token: String },
+    Cap { args: Vec<String> },
+    Privmsg { target: String, text: String },
+    Notice { target: String, text: String },
+    // keys are extended-join account and realname when sent by server
+    Join { channels: Vec<String>, keys: Vec<String> },
+    Part { channels: Vec<String>, reason: Option<String> },
+    Quit { reason: Option<String> },
+    Kick { channel: String, user: String, reason: Option<String> },
+    // topic is None for query
+    Topic { channel: String, topic: Option<String> },
+    // modes is mode string followed by its arguments, empty for query
+    Mode { target: String, modes: Vec<String> },
+    Invite { nickname: String, channel: String },
+    Away { message: Option<String> },
+    Chghost { user: String, host: String },
+    Error { message: String },
+    Numeric { reply: Reply, args: Vec<String> },
+    Other { command: String, args: Vec<String> },
+}
+
+impl Command {
+    pub fn name(&self) -> String {
+        match self {
+            Self::Pass { .. } => "PASS".into(),
+            Self::Nick { .. } => "NICK".into(),
+            Self::User { .. } => "USER".into(),
+            Self::Ping { .. } => "PING".into(),
+            Self::Pong { .. } => "PONG".into(),
+            Self::Cap { .. } => "CAP".into(),
+            Self::Privmsg { .. } => "PRIVMSG".into(),
+            Self::Notice { .. } => "NOTICE".into(),
+            Self::Join { .. } => "JOIN".into(),
+            Self::Part { .. } => "PART".into(),
+            Self::Quit { .. } => "QUIT".into(),
+            Self::Kick { .. } => "KICK".into(),
+            Self::Topic { .. } => "TOPIC".into(),
+            Self::Mode { .. } => "MODE".into(),
+            Self::Invite { .. } => "INVITE".into(),
+            Self::Away { .. } => "AWAY".into(),
+            Self::Chghost { .. } => "CHGHOST".into(),
+            Self::Error { .. } => "ERROR".into(),
+            Self::Numeric { reply, .. } => reply.to_string(),
+            Self::Other { command, .. } => command.clone(),
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        match self.clone() {
+            Self::Pass { password } => vec![password],
+            Self::Nick { nickname } => vec![nickname],
+            Self::User { username, realname } => vec![username, "0".into(), "*".into(), realname],
+            Self::Ping { token } | Self::Pong { token } => vec![token],
+            Self::Cap { args } | Self::Numeric { args, .. } | Self::Other { args, .. } => args,
+            Self::Privmsg { target, text } | Self::Notice { target, text } => vec![target, text],
+            Self::Join { channels, keys } => iter::once(channels.join(","))
+                .chain(if keys.is_empty() { None } else { Some(keys.join(",")) })
+                .collect(),
+            Self::Part { channels, reason } => iter::once(channels.join(",")).chain(reason).collect(),
+            Self::Quit { reason } => reason.into_iter().collect(),
+            Self::Kick { channel, user, reason } => vec![channel, user].into_iter().chain(reason).collect(),
+            Self::Topic { channel, topic } => iter::once(channel).chain(topic).collect(),
+            Self::Mode { target, modes } => iter::once(target).chain(modes).collect(),
+            Self::Invite { nickname, channel } => vec![nickname, channel],
+            Self::Away { message } => message.into_iter().collect(),
+            Self::Chghost { user, host } => vec![user, host],
+            Self::Error { message } => vec![message],
+        }
+    }
+
+    fn split_list(list: &str) -> Vec<String> {
+        list.split(',').filter(|x| !x.is_empty()).map(|x| x.to_owned()).collect()
+    }
+}
+
+impl TryFrom<&Message> for Command {
+    type Error = Error;
+
+    fn try_from(message: &Message) -> Result<Self, Error> {
+        if let Some(reply) = Reply::from_command(&message.command) {
+            return Ok(Self::Numeric {
+                reply,
+                args: message.args.clone(),
+            });
+        }
+
+        let command = message.command.to_ascii_uppercase();
+        let args = message.args.as_slice();
+
+        let result = match (command.as_ref(), args) {
+            ("PASS", [password, ..]) => Self::Pass { password: password.clone() },
+            ("NICK", [nickname, ..]) => Self::Nick { nickname: nickname.clone() },
+            ("USER", [username, _, _, realname, ..]) => Self::User {
+                username: username.clone(),
+                realname: realname.clone(),
+            },
+            ("PING", [.., token]) => Self::Ping { token: token.clone() },
+            // server may send `PONG <server> <token>`
+            ("PONG", [.., token]) => Self::Pong { token: token.clone() },
+            ("CAP", [_, ..]) => Self::Cap { args: args.to_vec() },
+            ("PRIVMSG", [target, text, ..]) => Self::Privmsg {
+                target: target.clone(),
+                text: text.clone(),
+            },
+            ("NOTICE", [target, text, ..]) => Self::Notice {
+                target: target.clone(),
+                text: text.clone(),
+            },
+            ("JOIN", [channels, rest @ ..]) => Self::Join {
+                channels: Self::split_list(channels),
+                keys: rest.first().map(|x| Self::split_list(x)).unwrap_or_default(),
+            },
+            ("PART", [channels, rest @ ..]) => Self::Part {
+                channels: Self::split_list(channels),
+                reason: rest.first().cloned(),
+            },
+            ("QUIT", _) => Self::Quit {
+                reason: args.first().cloned(),
+            },
+            ("KICK", [channel, user, rest @ ..]) => Self::Kick {
+                channel: channel.clone(),
+                user: user.clone(),
+                reason: rest.first().cloned(),
+            },
+            ("TOPIC", [channel, rest @ ..]) => Self::Topic {
+                channel: channel.clone(),
+                topic: rest.first().cloned(),
+            },
+            ("MODE", [target, modes @ ..]) => Self::Mode {
+                target: target.clone(),
+                modes: modes.to_vec(),
+            },
+            ("INVITE", [nickname, channel, ..]) => Self::Invite {
+                nickname: nickname.clone(),
+                channel: channel.clone(),
+            },
+            ("AWAY", _) => Self::Away {
+                message: args.first().filter(|x| !x.is_empty()).cloned(),
+            },
+            ("CHGHOST", [user, host, ..]) => Self::Chghost {
+                user: user.clone(),
+                host: host.clone(),
+            },
+            ("ERROR", _) => Self::Error {
+                message: args.first().cloned().unwrap_or_default(),
+            },
+            (
+                "PASS" | "NICK" | "USER" | "PING" | "PONG" | "CAP" | "PRIVMSG" | "NOTICE" | "JOIN" | "PART" | "KICK" | "TOPIC" | "MODE" | "INVITE"
+                | "CHGHOST",
+                _,
+            ) => return Err(Error { command }),
+            _ => Self::Other {
+                command: message.command.clone(),
+                args: args.to_vec(),
+            },
+        };
+
+        Ok(result)
+    }
+}
+
+impl Message {
+    pub fn from_command(prefix: Option<Prefix>, command: Command) -> Self {
+        Self {
+            tags: Vec::new(),
+            prefix,
+            command: command.name(),
+            args: command.args(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Command, Error> {
+        Command::try_from(&Message::from_raw(raw.into()))
+    }
+
+    #[test]
+    fn test_parse_privmsg() {
+        assert_eq!(
+            parse(":a!a@a PRIVMSG #test :hello world").unwrap(),
+            Command::Privmsg {
+                target: "#test".into(),
+                text: "hello world".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_join() {
+        assert_eq!(
+            parse("JOIN #a,#b key").unwrap(),
+            Command::Join {
+                channels: vec!["#a".into(), "#b".into()],
+                keys: vec!["key".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_numeric() {
+        assert_eq!(
+            parse(":server.com 433 * test :Nickname is already in use").unwrap(),
+            Command::Numeric {
+                reply: Reply::ERR_NICKNAMEINUSE,
+                args: vec!["*".into(), "test".into(), "Nickname is already in use".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_short() {
+        assert!(parse("PRIVMSG #test").is_err());
+        assert!(parse("KICK #test").is_err());
+        assert!(parse("NICK").is_err());
+        assert!(matches!(parse("FOO").unwrap(), Command::Other { .. }));
+    }
+
+    #[test]
+    fn test_raw() {
+        let message = Message::from_command(
+            None,
+            Command::Kick {
+                channel: "#test".into(),
+                user: "a".into(),
+                reason: Some("bye bye".into()),
+            },
+        );
+        assert_eq!(message.raw(), "KICK #test a :bye bye\r\n");
+
+        let message = Message::from_command(
+            None,
+            Command::Numeric {
+                reply: Reply::RPL_WELCOME,
+                args: vec!["test".into(), "Welcome".into()],
+            },
+        );
+        assert_eq!(message.raw(), "001 test Welcome\r\n");
+    }
+}
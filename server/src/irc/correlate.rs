@@ -0,0 +1,526 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use super::{isupport::Casemapping, message::Message, reply::Reply};
+use crate::message::Origin;
+
+// unanswered requests are forgotten after this, success of most commands is silent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// downstream connection a reply belongs to
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Route {
+    pub origin: Origin,
+    // no more replies are expected for the request
+    pub last: bool,
+}
+
+struct Pending {
+    origin: Origin,
+    command: String,
+    // channels and nicknames the request is about, which its replies name
+    targets: Vec<String>,
+    sent: Instant,
+}
+
+struct Echo {
+    origin: Origin,
+    target: String,
+    text: String,
+    sent: Instant,
+}
+
+// matches upstream replies to the requests of downstream connections,
+// by `labeled-response` if enabled and by numerics each command may cause otherwise
+#[derive(Default)]
+pub struct Correlator {
+    next_label: u64,
+    labels: HashMap<String, Origin>,
+    // labeled-response batch references
+    batches: HashMap<String, Origin>,
+    // unlabeled requests in send order
+    pending: VecDeque<Pending>,
+    // unlabeled messages to be echoed by `echo-message`
+    echoes: VecDeque<Echo>,
+}
+
+impl Correlator {
+    // labels the request if `labeled`, commands without known replies aren't tracked otherwise
+    pub fn request(&mut self, message: &mut Message, origin: Origin, labeled: bool, echoed: bool) {
+        if echoed && !labeled {
+            if let ("PRIVMSG" | "NOTICE", [target, text]) = (message.command.to_ascii_uppercase().as_ref(), message.args.as_slice()) {
+                self.echoes.push_back(Echo {
+                    origin,
+                    target: target.clone(),
+                    text: text.clone(),
+                    sent: Instant::now(),
+                });
+            }
+        }
+
+        if labeled {
+            let label = self.next_label.to_string();
+            self.next_label += 1;
+
+            message.tags.push(("label".into(), label.clone()));
+            self.labels.insert(label, origin);
+        } else if Self::known(message) {
+            let command = message.command.to_ascii_uppercase();
+
+            self.pending.push_back(Pending {
+                origin,
+                targets: Self::targets(&command, &message.args),
+                command,
+                sent: Instant::now(),
+            });
+        }
+    }
+
+    pub fn reply(&mut self, message: &Message, casemapping: Casemapping) -> Option<Route> {
+        if let Some(origin) = message.tag("batch").and_then(|x| self.batches.get(x)) {
+            return Some(Route {
+                origin: *origin,
+                last: false,
+            });
+        }
+
+        if let Some(label) = message.tag("label") {
+            let origin = self.labels.remove(label)?;
+
+            if message.command == "BATCH" {
+                if let [reference, ..] = message.args.as_slice() {
+                    if let Some(reference) = reference.strip_prefix('+') {
+                        self.batches.insert(reference.into(), origin);
+                    }
+                }
+
+                return Some(Route { origin, last: false });
+            }
+
+            return Some(Route { origin, last: true });
+        }
+
+        if message.command == "BATCH" {
+            let reference = message.args.first()?.strip_prefix('-')?;
+
+            return self.batches.remove(reference).map(|origin| Route { origin, last: true });
+        }
+
+        self.match_numeric(message, casemapping)
+    }
+
+    // origin of our own message echoed back without label
+    pub fn echo(&mut self, message: &Message) -> Option<Origin> {
+        let now = Instant::now();
+        self.echoes.retain(|x| now.duration_since(x.sent) < REQUEST_TIMEOUT);
+
+        let (target, text) = match message.args.as_slice() {
+            [target, text] => (target, text),
+            _ => return None,
+        };
+        let index = self.echoes.iter().position(|x| x.target == *target && x.text == *text)?;
+        let origin = self.echoes.remove(index)?.origin;
+
+        // delivered, so no error will answer it
+        let command = message.command.to_ascii_uppercase();
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|x| x.origin == origin && x.command == command && x.targets.first() == Some(target))
+        {
+            self.pending.remove(index);
+        }
+
+        Some(origin)
+    }
+
+    fn match_numeric(&mut self, message: &Message, casemapping: Casemapping) -> Option<Route> {
+        let reply = Reply::from_command(&message.command)?;
+        let args = &message.args;
+
+        let now = Instant::now();
+        self.pending.retain(|x| now.duration_since(x.sent) < REQUEST_TIMEOUT);
+
+        // replies naming something else belong to another request
+        let named = Self::named(reply, args);
+        let index = self.pending.iter().position(|x| match reply {
+            // name the failed command
+            Reply::ERR_UNKNOWNCOMMAND | Reply::ERR_NEEDMOREPARAMS => args.get(1).map(|y| y.eq_ignore_ascii_case(&x.command)).unwrap_or(false),
+            _ => {
+                Self::replies(&x.command, reply).is_some()
+                    && (x.targets.is_empty() || named.map(|y| x.targets.iter().any(|z| casemapping.eq_ignore_case(y, z))).unwrap_or(true))
+            }
+        })?;
+
+        let last = match reply {
+            Reply::ERR_UNKNOWNCOMMAND | Reply::ERR_NEEDMOREPARAMS => true,
+            _ => Self::replies(&self.pending[index].command, reply).unwrap(),
+        };
+
+        let origin = if last {
+            self.pending.remove(index).unwrap().origin
+        } else {
+            self.pending[index].origin
+        };
+
+        Some(Route { origin, last })
+    }
+
+    // setting topic or modes is answered by broadcast, and their replies come after JOIN too
+    fn known(message: &Message) -> bool {
+        match (message.command.to_ascii_uppercase().as_ref(), message.args.as_slice()) {
+            ("TOPIC", [_]) | ("MODE", [_]) => true,
+            ("MODE", [_, modes]) => modes.trim_start_matches('+').chars().all(|x| "beI".contains(x)),
+            ("TOPIC" | "MODE", _) => false,
+            (command, _) => matches!(
+                command,
+                "WHOIS"
+                    | "WHOWAS"
+                    | "WHO"
+                    | "LIST"
+                    | "NAMES"
+                    | "AWAY"
+                    | "INVITE"
+                    | "PRIVMSG"
+                    | "NOTICE"
+                    | "JOIN"
+                    | "PART"
+                    | "KICK"
+                    | "NICK"
+                    | "ISON"
+                    | "USERHOST"
+                    | "MOTD"
+                    | "VERSION"
+                    | "TIME"
+                    | "ADMIN"
+                    | "INFO"
+                    | "LINKS"
+                    | "STATS"
+            ),
+        }
+    }
+
+    // names replies of command are about, as a list of targets is answered one by one
+    fn targets(command: &str, args: &[String]) -> Vec<String> {
+        let targets = match command {
+            // server may come first
+            "WHOIS" => args.last().into_iter().collect::<Vec<_>>(),
+            // errors name either channel or nickname
+            "INVITE" | "KICK" => args.iter().take(2).collect(),
+            "WHOWAS" | "NAMES" | "MODE" | "TOPIC" | "PRIVMSG" | "NOTICE" | "JOIN" | "PART" | "NICK" => args.first().into_iter().collect(),
+            _ => Vec::new(),
+        };
+
+        targets.into_iter().flat_map(|x| x.split(',')).map(|x| x.to_owned()).collect()
+    }
+
+    // arg of reply naming the channel or nickname it's about, None if it names nothing
+    fn named(reply: Reply, args: &[String]) -> Option<&String> {
+        match reply {
+            Reply::RPL_NAMREPLY => args.get(2),
+            Reply::RPL_UMODEIS
+            | Reply::ERR_UMODEUNKNOWNFLAG
+            | Reply::ERR_USERSDONTMATCH
+            | Reply::ERR_NONICKNAMEGIVEN
+            | Reply::ERR_NORECIPIENT
+            | Reply::ERR_NOTEXTTOSEND
+            | Reply::ERR_NOPRIVILEGES => None,
+            _ => args.get(1),
+        }
+    }
+
+    // Some(ends request) if reply can answer command
+    fn replies(command: &str, reply: Reply) -> Option<bool> {
+        let result = match (command, reply) {
+            (
+                "WHOIS",
+                Reply::RPL_WHOISUSER
+                | Reply::RPL_WHOISSERVER
+                | Reply::RPL_WHOISOPERATOR
+                | Reply::RPL_WHOISIDLE
+                | Reply::RPL_WHOISCHANNELS
+                | Reply::RPL_WHOISSPECIAL
+                | Reply::RPL_WHOISACCOUNT
+                | Reply::RPL_WHOISACTUALLY
+                | Reply::RPL_WHOISHOST
+                | Reply::RPL_WHOISMODES
+                | Reply::RPL_WHOISSECURE
+                | Reply::RPL_WHOISCERTFP
+                | Reply::RPL_WHOISREGNICK
+                | Reply::RPL_AWAY
+                | Reply::ERR_NOSUCHNICK
+                | Reply::ERR_NOSUCHSERVER,
+            ) => false,
+            ("WHOIS", Reply::RPL_ENDOFWHOIS | Reply::ERR_NONICKNAMEGIVEN) => true,
+            ("WHOWAS", Reply::RPL_WHOWASUSER | Reply::RPL_WHOISSERVER | Reply::RPL_WHOISACCOUNT | Reply::ERR_WASNOSUCHNICK) => false,
+            ("WHOWAS", Reply::RPL_ENDOFWHOWAS | Reply::ERR_NONICKNAMEGIVEN) => true,
+            ("WHO", Reply::RPL_WHOREPLY | Reply::RPL_WHOSPCRPL) => false,
+            ("WHO", Reply::RPL_ENDOFWHO) => true,
+            ("LIST", Reply::RPL_LISTSTART | Reply::RPL_LIST) => false,
+            ("LIST", Reply::RPL_LISTEND) => true,
+            ("NAMES", Reply::RPL_NAMREPLY) => false,
+            ("NAMES", Reply::RPL_ENDOFNAMES) => true,
+            // channel mode query is followed by creation time on most servers
+            ("MODE", Reply::RPL_CHANNELMODEIS | Reply::RPL_BANLIST | Reply::RPL_EXCEPTLIST | Reply::RPL_INVEXLIST) => false,
+            (
+                "MODE",
+                Reply::RPL_CREATIONTIME
+                | Reply::RPL_UMODEIS
+                | Reply::RPL_ENDOFBANLIST
+                | Reply::RPL_ENDOFEXCEPTLIST
+                | Reply::RPL_ENDOFINVEXLIST
+                | Reply::ERR_NOSUCHCHANNEL
+                | Reply::ERR_NOTONCHANNEL
+                | Reply::ERR_UNKNOWNMODE
+                | Reply::ERR_NOCHANMODES
+                | Reply::ERR_CHANOPRIVSNEEDED
+                | Reply::ERR_KEYSET
+                | Reply::ERR_INVALIDMODEPARAM
+                | Reply::ERR_UMODEUNKNOWNFLAG
+                | Reply::ERR_USERSDONTMATCH,
+            ) => true,
+            ("TOPIC", Reply::RPL_TOPIC) => false,
+            (
+                "TOPIC",
+                Reply::RPL_NOTOPIC | Reply::RPL_TOPICWHOTIME | Reply::ERR_NOSUCHCHANNEL | Reply::ERR_NOTONCHANNEL | Reply::ERR_CHANOPRIVSNEEDED,
+            ) => true,
+            ("AWAY", Reply::RPL_UNAWAY | Reply::RPL_NOWAWAY) => true,
+            (
+                "INVITE",
+                Reply::RPL_INVITING
+                | Reply::ERR_NOSUCHNICK
+                | Reply::ERR_NOSUCHCHANNEL
+                | Reply::ERR_NOTONCHANNEL
+                | Reply::ERR_USERONCHANNEL
+                | Reply::ERR_CHANOPRIVSNEEDED,
+            ) => true,
+            // success is silent, and RPL_AWAY goes to everyone as WHOIS takes it too
+            (
+                "PRIVMSG" | "NOTICE",
+                Reply::ERR_NOSUCHNICK
+                | Reply::ERR_NOSUCHSERVER
+                | Reply::ERR_CANNOTSENDTOCHAN
+                | Reply::ERR_TOOMANYTARGETS
+                | Reply::ERR_NORECIPIENT
+                | Reply::ERR_NOTEXTTOSEND,
+            ) => true,
+            (
+                "JOIN",
+                Reply::ERR_NOSUCHCHANNEL
+                | Reply::ERR_TOOMANYCHANNELS
+                | Reply::ERR_CHANNELISFULL
+                | Reply::ERR_INVITEONLYCHAN
+                | Reply::ERR_BANNEDFROMCHAN
+                | Reply::ERR_BADCHANNELKEY
+                | Reply::ERR_BADCHANMASK
+                | Reply::ERR_UNAVAILRESOURCE,
+            ) => true,
+            ("PART", Reply::ERR_NOSUCHCHANNEL | Reply::ERR_NOTONCHANNEL) => true,
+            ("KICK", Reply::ERR_NOSUCHCHANNEL | Reply::ERR_USERNOTINCHANNEL | Reply::ERR_NOTONCHANNEL | Reply::ERR_CHANOPRIVSNEEDED) => true,
+            (
+                "NICK",
+                Reply::ERR_NONICKNAMEGIVEN
+                | Reply::ERR_ERRONEUSNICKNAME
+                | Reply::ERR_NICKNAMEINUSE
+                | Reply::ERR_NICKCOLLISION
+                | Reply::ERR_UNAVAILRESOURCE,
+            ) => true,
+            ("ISON", Reply::RPL_ISON) => true,
+            ("USERHOST", Reply::RPL_USERHOST) => true,
+            ("MOTD", Reply::RPL_MOTDSTART | Reply::RPL_MOTD) => false,
+            ("MOTD", Reply::RPL_ENDOFMOTD | Reply::ERR_NOMOTD) => true,
+            ("VERSION", Reply::RPL_VERSION) => true,
+            ("TIME", Reply::RPL_TIME) => true,
+            ("ADMIN", Reply::RPL_ADMINME | Reply::RPL_ADMINLOC1 | Reply::RPL_ADMINLOC2) => false,
+            ("ADMIN", Reply::RPL_ADMINEMAIL | Reply::ERR_NOADMININFO) => true,
+            ("INFO", Reply::RPL_INFO | Reply::RPL_INFOSTART) => false,
+            ("INFO", Reply::RPL_ENDOFINFO) => true,
+            ("LINKS", Reply::RPL_LINKS) => false,
+            ("LINKS", Reply::RPL_ENDOFLINKS) => true,
+            ("STATS", Reply::RPL_ENDOFSTATS) => true,
+            ("STATS", reply) if reply.is_stats() => false,
+            (_, Reply::ERR_NOPRIVILEGES) => true,
+            _ => return None,
+        };
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(raw: &str) -> Message {
+        Message::from_raw(raw.into())
+    }
+
+    #[test]
+    fn test_labeled() {
+        let mut correlator = Correlator::default();
+        let mut request = message("WHOIS test");
+
+        correlator.request(&mut request, Origin::Irc(1), true, false);
+        let label = request.tag("label").unwrap().to_owned();
+
+        let start = message(&format!("@label={} :server BATCH +abc labeled-response", label));
+        assert_eq!(
+            correlator.reply(&start, Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: false
+            })
+        );
+
+        let inner = message("@batch=abc :server 311 me test user host * :Real");
+        assert_eq!(
+            correlator.reply(&inner, Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: false
+            })
+        );
+
+        assert_eq!(
+            correlator.reply(&message(":server BATCH -abc"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: true
+            })
+        );
+        assert_eq!(correlator.reply(&inner, Casemapping::Rfc1459), None);
+    }
+
+    #[test]
+    fn test_numeric() {
+        let mut correlator = Correlator::default();
+
+        correlator.request(&mut message("WHOIS a"), Origin::Irc(1), false, false);
+        correlator.request(&mut message("LIST"), Origin::Grpc(2), false, false);
+        correlator.request(&mut message("WHOIS b"), Origin::Irc(3), false, false);
+
+        assert_eq!(
+            correlator.reply(&message(":server 322 me #a 1 :topic"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Grpc(2),
+                last: false,
+            })
+        );
+        assert_eq!(
+            correlator.reply(&message(":server 318 me a :End of /WHOIS list."), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: true
+            })
+        );
+        assert_eq!(
+            correlator.reply(&message(":server 311 me b user host * :Real"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(3),
+                last: false
+            })
+        );
+
+        // unsolicited
+        assert_eq!(
+            correlator.reply(&message(":server 005 me CASEMAPPING=ascii :are supported"), Casemapping::Rfc1459),
+            None
+        );
+    }
+
+    #[test]
+    fn test_topic() {
+        let mut correlator = Correlator::default();
+
+        // reply to later JOIN isn't taken by setting topic
+        correlator.request(&mut message("TOPIC #a :new topic"), Origin::Irc(1), false, false);
+        assert_eq!(correlator.reply(&message(":server 332 me #b :topic"), Casemapping::Rfc1459), None);
+
+        correlator.request(&mut message("TOPIC #a"), Origin::Irc(1), false, false);
+        assert_eq!(
+            correlator.reply(&message(":server 332 me #a :topic"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut correlator = Correlator::default();
+
+        correlator.request(&mut message("FOO"), Origin::Irc(1), false, false);
+
+        // untracked without labels
+        assert_eq!(
+            correlator.reply(&message(":server 421 me FOO :Unknown command"), Casemapping::Rfc1459),
+            None
+        );
+
+        correlator.request(&mut message("WHO #a"), Origin::Irc(1), false, false);
+
+        assert_eq!(
+            correlator.reply(&message(":server 461 me WHO :Not enough parameters"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(1),
+                last: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut correlator = Correlator::default();
+
+        correlator.request(&mut message("PRIVMSG #a :hello"), Origin::Irc(1), false, true);
+        correlator.request(&mut message("PRIVMSG #a :hello"), Origin::Irc(2), false, true);
+
+        let echo = message(":me!u@h PRIVMSG #a :hello");
+
+        assert_eq!(correlator.reply(&echo, Casemapping::Rfc1459), None);
+        assert_eq!(correlator.echo(&echo), Some(Origin::Irc(1)));
+        assert_eq!(correlator.echo(&echo), Some(Origin::Irc(2)));
+        assert_eq!(correlator.echo(&echo), None);
+    }
+
+    #[test]
+    fn test_target() {
+        let mut correlator = Correlator::default();
+
+        correlator.request(&mut message("PRIVMSG bob :hello"), Origin::Irc(1), false, false);
+        correlator.request(&mut message("WHOIS Carol[m]"), Origin::Irc(2), false, false);
+
+        // names another request's target
+        assert_eq!(
+            correlator.reply(&message(":server 401 me carol{m} :No such nick"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(2),
+                last: false
+            })
+        );
+
+        // only errors answer messages
+        correlator.request(&mut message("WHOIS bob"), Origin::Irc(3), false, false);
+        assert_eq!(
+            correlator.reply(&message(":server 301 me bob :away"), Casemapping::Rfc1459),
+            Some(Route {
+                origin: Origin::Irc(3),
+                last: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_echo_delivered() {
+        let mut correlator = Correlator::default();
+
+        correlator.request(&mut message("PRIVMSG bob :hello"), Origin::Irc(1), false, true);
+        assert_eq!(correlator.echo(&message(":me!u@h PRIVMSG bob :hello")), Some(Origin::Irc(1)));
+
+        // delivered message is no longer pending
+        assert_eq!(correlator.reply(&message(":server 401 me bob :No such nick"), Casemapping::Rfc1459), None);
+    }
+}
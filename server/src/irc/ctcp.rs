@@ -0,0 +1,148 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+
+use crate::config::Ctcp;
+
+const DELIMITER: char = '\x01';
+
+// queries answered on behalf of the user
+const SUPPORTED: &[&str] = &["ACTION", "CLIENTINFO", "PING", "SOURCE", "TIME", "VERSION"];
+
+// command and params of `\x01COMMAND params\x01`, closing delimiter is optional
+pub fn parse(content: &str) -> Option<(&str, &str)> {
+    let inner = content.strip_prefix(DELIMITER)?;
+    let inner = inner.strip_suffix(DELIMITER).unwrap_or(inner);
+
+    Some(inner.split_once(' ').unwrap_or((inner, "")))
+}
+
+pub fn encode(command: &str, params: &str) -> String {
+    if params.is_empty() {
+        format!("{}{}{}", DELIMITER, command, DELIMITER)
+    } else {
+        format!("{}{} {}{}", DELIMITER, command, params, DELIMITER)
+    }
+}
+
+pub fn parse_action(content: &str) -> Option<&str> {
+    parse(content).filter(|(command, _)| *command == "ACTION").map(|(_, params)| params)
+}
+
+pub fn action(content: &str) -> String {
+    format!("{}ACTION {}{}", DELIMITER, content, DELIMITER)
+}
+
+// answers queries while no client is attached, at most `limit` per `period`
+pub struct Responder {
+    config: Ctcp,
+    sent: VecDeque<Instant>,
+}
+
+impl Responder {
+    pub fn new(config: Ctcp) -> Self {
+        Self {
+            config,
+            sent: VecDeque::new(),
+        }
+    }
+
+    // reply text of query, None if unknown, disabled or rate limited
+    pub fn respond(&mut self, command: &str, params: &str) -> Option<String> {
+        let reply = match command {
+            "VERSION" => self.config.version.clone(),
+            "SOURCE" => self.config.source.clone(),
+            "PING" => params.to_owned(),
+            "TIME" => Utc::now().to_rfc2822(),
+            "CLIENTINFO" => SUPPORTED.join(" "),
+            _ => return None,
+        };
+
+        // empty response disables the query
+        if reply.is_empty() && command != "PING" {
+            return None;
+        }
+
+        if !self.allow(Instant::now()) {
+            return None;
+        }
+
+        Some(encode(command, &reply))
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        let period = Duration::from_secs(self.config.period);
+
+        while self.sent.front().map(|x| now.duration_since(*x) >= period).unwrap_or(false) {
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() >= self.config.limit {
+            return false;
+        }
+        self.sent.push_back(now);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> Ctcp {
+        Ctcp {
+            version: "bouncer".into(),
+            source: String::new(),
+            limit: 2,
+            period: 10,
+        }
+    }
+
+    #[test]
+    fn test_action() {
+        assert_eq!(parse_action("\x01ACTION waves\x01"), Some("waves"));
+        assert_eq!(parse_action("\x01ACTION waves"), Some("waves"));
+        assert_eq!(parse_action("\x01VERSION\x01"), None);
+        assert_eq!(parse_action("ACTION waves"), None);
+
+        assert_eq!(action("waves"), "\x01ACTION waves\x01");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("\x01PING 1234\x01"), Some(("PING", "1234")));
+        assert_eq!(parse("\x01VERSION\x01"), Some(("VERSION", "")));
+        assert_eq!(parse("hello"), None);
+
+        assert_eq!(encode("VERSION", ""), "\x01VERSION\x01");
+        assert_eq!(encode("PING", "1234"), "\x01PING 1234\x01");
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut responder = Responder::new(config());
+
+        assert_eq!(responder.respond("VERSION", "").as_deref(), Some("\x01VERSION bouncer\x01"));
+        // disabled
+        assert_eq!(responder.respond("SOURCE", ""), None);
+        assert_eq!(responder.respond("DCC", "SEND a"), None);
+        assert_eq!(responder.respond("PING", "1234").as_deref(), Some("\x01PING 1234\x01"));
+        // rate limited
+        assert_eq!(responder.respond("PING", "1234"), None);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut responder = Responder::new(config());
+        let now = Instant::now();
+
+        assert!(responder.allow(now));
+        assert!(responder.allow(now));
+        assert!(!responder.allow(now));
+        assert!(responder.allow(now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+
+use encoding_rs::{Encoding as Codepage, UTF_8};
+
+// line encoding of a connection, falls back to legacy codepage on invalid input if configured
+#[derive(Clone, Copy)]
+pub struct Encoding {
+    encoding: &'static Codepage,
+    fallback: Option<&'static Codepage>,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            fallback: None,
+        }
+    }
+}
+
+impl Encoding {
+    pub fn new(label: &str, fallback: Option<&str>) -> io::Result<Self> {
+        Ok(Self {
+            encoding: Self::lookup(label)?,
+            fallback: fallback.map(Self::lookup).transpose()?,
+        })
+    }
+
+    pub fn decode(&self, raw: &[u8]) -> String {
+        if let Some(fallback) = self.fallback {
+            if let Some(decoded) = self.encoding.decode_without_bom_handling_and_without_replacement(raw) {
+                return decoded.into_owned();
+            }
+
+            return fallback.decode_without_bom_handling(raw).0.into_owned();
+        }
+
+        self.encoding.decode_without_bom_handling(raw).0.into_owned()
+    }
+
+    // unmappable characters become numeric character references
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        self.encoding.encode(text).0.into_owned()
+    }
+
+    fn lookup(label: &str) -> io::Result<&'static Codepage> {
+        // common names which aren't WHATWG labels
+        let label = match label.to_ascii_lowercase().as_ref() {
+            "cp949" | "uhc" => "windows-949".to_owned(),
+            "latin-1" => "latin1".to_owned(),
+            x => x.to_owned(),
+        };
+
+        Codepage::for_label(label.as_bytes()).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown encoding {}", label)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // "안녕" in cp949
+    const CP949: &[u8] = &[0xbe, 0xc8, 0xb3, 0xe7];
+
+    #[test]
+    fn test_legacy() {
+        let encoding = Encoding::new("cp949", None).unwrap();
+
+        assert_eq!(encoding.decode(CP949), "안녕");
+        assert_eq!(encoding.encode("안녕"), CP949);
+    }
+
+    #[test]
+    fn test_fallback() {
+        let encoding = Encoding::new("utf-8", Some("cp949")).unwrap();
+
+        assert_eq!(encoding.decode("안녕".as_bytes()), "안녕");
+        assert_eq!(encoding.decode(CP949), "안녕");
+        assert_eq!(encoding.encode("안녕"), "안녕".as_bytes());
+    }
+
+    #[test]
+    fn test_iso_2022_jp() {
+        let encoding = Encoding::new("iso-2022-jp", None).unwrap();
+        let encoded = encoding.encode("こんにちは");
+
+        assert!(encoded.is_ascii());
+        assert_eq!(encoding.decode(&encoded), "こんにちは");
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert!(Encoding::new("no-such-encoding", None).is_err());
+    }
+}
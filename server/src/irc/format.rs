@@ -0,0 +1,435 @@
+use std::fmt::Write;
+
+const BOLD: char = '\x02';
+const ITALIC: char = '\x1d';
+const UNDERLINE: char = '\x1f';
+const STRIKETHROUGH: char = '\x1e';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0f';
+
+// `99` is the default color, which isn't in the palette
+const DEFAULT_COLOR: u8 = 99;
+
+// colors of mIRC palette, 16 and above are from the extended one
+const PALETTE: [u32; 99] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00, 0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff,
+    0x7f7f7f, 0xd2d2d2, 0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045, 0xb50000, 0xb56300,
+    0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b, 0xff0000, 0xff8c00, 0xffff00, 0xb2ff00,
+    0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098, 0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9,
+    0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc, 0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff,
+    0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3, 0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2,
+    0xffffff,
+];
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Color {
+    // index of mIRC palette, `\x03`
+    Palette(u8),
+    // `0xrrggbb`, `\x04`
+    Rgb(u32),
+}
+
+impl Color {
+    // `#rrggbb`
+    pub fn hex(&self) -> String {
+        format!("#{:06x}", self.rgb())
+    }
+
+    // palette color where there's one, so that it's sent as `\x03`
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 {
+            return None;
+        }
+        let rgb = u32::from_str_radix(hex, 16).ok()?;
+
+        Some(match PALETTE.iter().position(|x| *x == rgb) {
+            Some(index) => Color::Palette(index as u8),
+            None => Color::Rgb(rgb),
+        })
+    }
+
+    fn rgb(&self) -> u32 {
+        match *self {
+            Color::Palette(index) => PALETTE[index as usize],
+            Color::Rgb(rgb) => rgb,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    pub reverse: bool,
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+}
+
+impl Style {
+    fn toggles(&self) -> [(char, bool); 6] {
+        [
+            (BOLD, self.bold),
+            (ITALIC, self.italic),
+            (UNDERLINE, self.underline),
+            (STRIKETHROUGH, self.strikethrough),
+            (MONOSPACE, self.monospace),
+            (REVERSE, self.reverse),
+        ]
+    }
+
+    fn toggle(&mut self, code: char) {
+        let flag = match code {
+            BOLD => &mut self.bold,
+            ITALIC => &mut self.italic,
+            UNDERLINE => &mut self.underline,
+            STRIKETHROUGH => &mut self.strikethrough,
+            MONOSPACE => &mut self.monospace,
+            REVERSE => &mut self.reverse,
+            _ => return,
+        };
+
+        *flag ^= true;
+    }
+}
+
+// foreground and background given by a color code, where background may be left out
+type Colors = (Option<Color>, Option<Option<Color>>);
+
+// run of text in one style
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+// text with mIRC formatting codes into spans, adjacent ones of the same style merged
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut result = Vec::<Span>::new();
+    let mut style = Style::default();
+    let mut rest = text;
+
+    while let Some(first) = rest.chars().next() {
+        rest = &rest[first.len_utf8()..];
+
+        match first {
+            RESET => style = Style::default(),
+            COLOR => {
+                let (colors, len) = parse_colors(rest, 2, |x| x.parse().ok().filter(|x| *x != DEFAULT_COLOR).map(Color::Palette));
+                apply_colors(&mut style, colors);
+                rest = &rest[len..];
+            }
+            HEX_COLOR => {
+                let (colors, len) = parse_colors(rest, 6, |x| u32::from_str_radix(x, 16).ok().map(Color::Rgb));
+                apply_colors(&mut style, colors);
+                rest = &rest[len..];
+            }
+            BOLD | ITALIC | UNDERLINE | STRIKETHROUGH | MONOSPACE | REVERSE => style.toggle(first),
+            x => match result.last_mut() {
+                Some(span) if span.style == style => span.text.push(x),
+                _ => result.push(Span { text: x.to_string(), style }),
+            },
+        }
+    }
+
+    result
+}
+
+// spans back into text with as few codes as possible
+pub fn to_irc(spans: &[Span]) -> String {
+    let mut result = String::new();
+    let mut current = Style::default();
+
+    for span in spans.iter().filter(|x| !x.text.is_empty()) {
+        let style = span.style;
+
+        if style == Style::default() && current != style {
+            result.push(RESET);
+        } else {
+            if (style.foreground, style.background) != (current.foreground, current.background) {
+                result.push_str(&color_codes(&current, &style, &span.text));
+            }
+            for ((code, from), (_, to)) in current.toggles().iter().zip(style.toggles().iter()) {
+                if from != to {
+                    result.push(*code);
+                }
+            }
+        }
+
+        // digits right after bare color code would be read as colors
+        if result.ends_with(COLOR) && span.text.starts_with(|x: char| x.is_ascii_digit()) {
+            result.push_str(&format!("{}{}", BOLD, BOLD));
+        }
+
+        result.push_str(&span.text);
+        current = style;
+    }
+
+    result
+}
+
+// text without formatting, for logging or searching
+pub fn to_plain(spans: &[Span]) -> String {
+    spans.iter().map(|x| x.text.as_str()).collect()
+}
+
+// html with text escaped, and only formatting tags and color styles of our own
+pub fn to_html(spans: &[Span]) -> String {
+    let mut result = String::new();
+
+    for span in spans.iter().filter(|x| !x.text.is_empty()) {
+        let style = span.style;
+        let (foreground, background) = if style.reverse {
+            // default colors are taken as black on white
+            (
+                Some(style.background.unwrap_or(Color::Palette(0))),
+                Some(style.foreground.unwrap_or(Color::Palette(1))),
+            )
+        } else {
+            (style.foreground, style.background)
+        };
+
+        let mut css = String::new();
+        if let Some(color) = foreground {
+            write!(css, "color:{};", color.hex()).unwrap();
+        }
+        if let Some(color) = background {
+            write!(css, "background-color:{};", color.hex()).unwrap();
+        }
+
+        let tags = [
+            ("b", style.bold),
+            ("i", style.italic),
+            ("u", style.underline),
+            ("s", style.strikethrough),
+            ("code", style.monospace),
+        ];
+        let tags = tags.iter().filter(|(_, on)| *on).map(|(tag, _)| *tag).collect::<Vec<_>>();
+
+        if !css.is_empty() {
+            write!(result, "<span style=\"{}\">", css.trim_end_matches(';')).unwrap();
+        }
+        tags.iter().for_each(|x| write!(result, "<{}>", x).unwrap());
+        escape(&span.text, &mut result);
+        tags.iter().rev().for_each(|x| write!(result, "</{}>", x).unwrap());
+        if !css.is_empty() {
+            result.push_str("</span>");
+        }
+    }
+
+    result
+}
+
+// foreground and optional background after `\x03` or `\x04`, and bytes taken
+fn parse_colors(code: &str, digits: usize, color: impl Fn(&str) -> Option<Color>) -> (Option<Colors>, usize) {
+    let bytes = code.as_bytes();
+    let count = |from: usize| {
+        let count = bytes[from..]
+            .iter()
+            .take(digits)
+            .take_while(|x| x.is_ascii_hexdigit() && (digits > 2 || x.is_ascii_digit()))
+            .count();
+
+        // hex colors have exactly 6 digits
+        if digits > 2 && count < digits {
+            0
+        } else {
+            count
+        }
+    };
+
+    let foreground = count(0);
+    if foreground == 0 {
+        return (None, 0);
+    }
+    let mut len = foreground;
+    let mut background = None;
+
+    if bytes.get(len) == Some(&b',') {
+        let count = count(len + 1);
+        if count > 0 {
+            background = Some(color(&code[len + 1..len + 1 + count]));
+            len += 1 + count;
+        }
+    }
+
+    (Some((color(&code[..foreground]), background)), len)
+}
+
+// bare code resets both colors, and background is kept unless given
+fn apply_colors(style: &mut Style, colors: Option<Colors>) {
+    match colors {
+        Some((foreground, background)) => {
+            style.foreground = foreground;
+            if let Some(background) = background {
+                style.background = background;
+            }
+        }
+        None => {
+            style.foreground = None;
+            style.background = None;
+        }
+    }
+}
+
+fn color_codes(from: &Style, to: &Style, text: &str) -> String {
+    let (foreground, background) = match (to.foreground, to.background) {
+        (None, None) => return COLOR.to_string(),
+        (foreground, background) => (foreground, background),
+    };
+    let palette = |x: Option<Color>| match x {
+        Some(Color::Palette(index)) => Some(index),
+        Some(Color::Rgb(_)) => None,
+        None => Some(DEFAULT_COLOR),
+    };
+
+    match (palette(foreground), palette(background)) {
+        (Some(foreground), Some(background)) => {
+            let mut result = format!("{}{:02}", COLOR, foreground);
+            // `,` right after would be read as background
+            if to.background.is_some() || from.background.is_some() || text.starts_with(',') {
+                write!(result, ",{:02}", background).unwrap();
+            }
+
+            result
+        }
+        // hex has no default color, so background without foreground is dropped
+        _ => match foreground {
+            Some(foreground) => {
+                let mut result = String::new();
+                if from.background.is_some() && background.is_none() {
+                    result.push(COLOR);
+                }
+                write!(result, "{}{:06X}", HEX_COLOR, foreground.rgb()).unwrap();
+                if let Some(background) = background {
+                    write!(result, ",{:06X}", background.rgb()).unwrap();
+                }
+
+                result
+            }
+            None => COLOR.to_string(),
+        },
+    }
+}
+
+fn escape(text: &str, result: &mut String) {
+    for x in text.chars() {
+        match x {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            x => result.push(x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(text: &str, style: Style) -> Span {
+        Span { text: text.into(), style }
+    }
+
+    #[test]
+    fn test_parse() {
+        let bold = Style {
+            bold: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            parse("a\x02b\x02c"),
+            vec![span("a", Style::default()), span("b", bold), span("c", Style::default())]
+        );
+        assert_eq!(parse("\x02\x02ab"), vec![span("ab", Style::default())]);
+        assert_eq!(parse("\x02a\x0fb"), vec![span("a", bold), span("b", Style::default())]);
+    }
+
+    #[test]
+    fn test_parse_colors() {
+        let red = Style {
+            foreground: Some(Color::Palette(4)),
+            ..Default::default()
+        };
+        let red_on_blue = Style {
+            background: Some(Color::Palette(12)),
+            ..red
+        };
+
+        assert_eq!(parse("\x034a"), vec![span("a", red)]);
+        assert_eq!(parse("\x0304,12a\x034b\x03c"), vec![span("ab", red_on_blue), span("c", Style::default())]);
+        // comma without background is text, and so are digits after two
+        assert_eq!(parse("\x0304,a"), vec![span(",a", red)]);
+        assert_eq!(parse("\x03041"), vec![span("1", red)]);
+        // default color
+        assert_eq!(
+            parse("\x0304,12\x0399a"),
+            vec![span(
+                "a",
+                Style {
+                    foreground: None,
+                    ..red_on_blue
+                }
+            )]
+        );
+
+        let hex = Style {
+            foreground: Some(Color::Rgb(0xff8800)),
+            ..Default::default()
+        };
+        assert_eq!(parse("\x04FF8800a"), vec![span("a", hex)]);
+        // hex colors need all 6 digits
+        assert_eq!(parse("\x04FF88a"), vec![span("FF88a", Style::default())]);
+    }
+
+    #[test]
+    fn test_to_irc() {
+        for text in [
+            "a\x02b\x1dc\x0fd",
+            "\x0304,12a\x0399,99b",
+            "\x04FF8800,000000a\x03b",
+            "\x0304a\x02\x02,b",
+            "\x0304a\x02\x0212",
+            "a\x03\x0212",
+        ] {
+            assert_eq!(parse(&to_irc(&parse(text))), parse(text), "{:?}", text);
+        }
+
+        assert_eq!(to_irc(&parse("\x02\x0304a\x0fb")), "\x0304\x02a\x0fb");
+    }
+
+    #[test]
+    fn test_to_plain() {
+        assert_eq!(to_plain(&parse("\x02\x0304,12a\x0f b\x04FF8800c")), "a bc");
+    }
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!(
+            to_html(&parse("<a>\x02&\x0304b")),
+            "&lt;a&gt;<b>&amp;</b><span style=\"color:#ff0000\"><b>b</b></span>"
+        );
+        assert_eq!(
+            to_html(&parse("\x16a")),
+            "<span style=\"color:#ffffff;background-color:#000000\">a</span>"
+        );
+    }
+
+    #[test]
+    fn test_color_hex() {
+        assert_eq!(Color::Palette(4).hex(), "#ff0000");
+        assert_eq!(Color::from_hex("#ff0000"), Some(Color::Palette(4)));
+        assert_eq!(Color::from_hex("#123456"), Some(Color::Rgb(0x123456)));
+        assert_eq!(Color::from_hex("#12"), None);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Casemapping {
+    Ascii,
+    Rfc1459,
+    Rfc1459Strict,
+}
+
+impl Casemapping {
+    // unknown mappings like rfc7613 fold at least ascii the same way
+    pub fn from_token(value: &str) -> Self {
+        match value {
+            "rfc1459" => Self::Rfc1459,
+            "rfc1459-strict" | "strict-rfc1459" => Self::Rfc1459Strict,
+            _ => Self::Ascii,
+        }
+    }
+
+    pub fn fold(&self, name: &str) -> String {
+        name.chars()
+            .map(|x| match (self, x) {
+                (_, 'A'..='Z') => x.to_ascii_lowercase(),
+                (Self::Rfc1459 | Self::Rfc1459Strict, '[') => '{',
+                (Self::Rfc1459 | Self::Rfc1459Strict, ']') => '}',
+                (Self::Rfc1459 | Self::Rfc1459Strict, '\\') => '|',
+                (Self::Rfc1459, '~') => '^',
+                _ => x,
+            })
+            .collect()
+    }
+
+    pub fn eq_ignore_case(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len() && self.fold(a) == self.fold(b)
+    }
+}
+
+// parametered modes grouped as in CHANMODES, others are flags and prefix modes come from PREFIX
+#[derive(Clone, Debug)]
+pub struct ChanModes {
+    // always take argument and are not recorded
+    pub list: String,
+    // always take argument
+    pub always: String,
+    // take argument only on set
+    pub on_set: String,
+}
+
+// RPL_ISUPPORT tokens of one upstream connection
+#[derive(Clone, Debug)]
+pub struct ISupport {
+    pub casemapping: Casemapping,
+    // mode letters paired with their prefixes, ordered by rank
+    pub prefixes: Vec<(char, char)>,
+    pub chanmodes: ChanModes,
+    pub chantypes: Vec<char>,
+    // prefixes for messaging members of certain rank, e.g. `@#channel`
+    pub statusmsg: Vec<char>,
+    tokens: BTreeMap<String, Option<String>>,
+}
+
+impl Default for ISupport {
+    // defaults before RPL_ISUPPORT arrives, covering common ircds
+    fn default() -> Self {
+        Self {
+            casemapping: Casemapping::Rfc1459,
+            prefixes: vec![('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')],
+            chanmodes: ChanModes {
+                list: "beI".into(),
+                always: "k".into(),
+                on_set: "l".into(),
+            },
+            chantypes: vec!['#', '&'],
+            statusmsg: Vec::new(),
+            tokens: BTreeMap::new(),
+        }
+    }
+}
+
+impl ISupport {
+    // args of RPL_ISUPPORT without client and trailing text
+    pub fn apply(&mut self, tokens: &[String]) {
+        for token in tokens {
+            if let Some(key) = token.strip_prefix('-') {
+                self.tokens.remove(key);
+                self.reset(key);
+
+                continue;
+            }
+
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) => (key, Some(value.to_owned())),
+                None => (token.as_ref(), None),
+            };
+
+            match (key, value.as_deref()) {
+                ("CASEMAPPING", Some(value)) => self.casemapping = Casemapping::from_token(value),
+                // modes without a prefix of their own can't be told apart, so the token is dropped
+                ("PREFIX", Some(value)) => match value.strip_prefix('(').and_then(|x| x.split_once(')')) {
+                    Some((modes, prefixes)) if modes.chars().count() == prefixes.chars().count() => {
+                        self.prefixes = modes.chars().zip(prefixes.chars()).collect();
+                    }
+                    _ => continue,
+                },
+                ("CHANMODES", Some(value)) => {
+                    let mut groups = value.split(',').map(|x| x.to_owned());
+
+                    self.chanmodes = ChanModes {
+                        list: groups.next().unwrap_or_default(),
+                        always: groups.next().unwrap_or_default(),
+                        on_set: groups.next().unwrap_or_default(),
+                    };
+                }
+                // no value means no channels at all
+                ("CHANTYPES", value) => self.chantypes = value.unwrap_or_default().chars().collect(),
+                ("STATUSMSG", Some(value)) => self.statusmsg = value.chars().collect(),
+                _ => {}
+            }
+
+            self.tokens.insert(key.into(), value);
+        }
+    }
+
+    // raw tokens to advertise to downstream clients
+    pub fn tokens(&self) -> Vec<String> {
+        self.tokens
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={}", key, value),
+                None => key.clone(),
+            })
+            .collect()
+    }
+
+    pub fn fold(&self, name: &str) -> String {
+        self.casemapping.fold(name)
+    }
+
+    // prefix of member mode, e.g. `@` for `o`
+    pub fn prefix(&self, mode: char) -> Option<char> {
+        self.prefixes.iter().find(|(x, _)| *x == mode).map(|(_, x)| *x)
+    }
+
+    pub fn is_prefix(&self, prefix: char) -> bool {
+        self.prefixes.iter().any(|(_, x)| *x == prefix)
+    }
+
+    // prefixes of a member in order of rank
+    pub fn sort_prefixes(&self, prefixes: &str) -> String {
+        self.prefixes.iter().map(|(_, x)| *x).filter(|x| prefixes.contains(*x)).collect()
+    }
+
+    // channel or its STATUSMSG form, anything else is a nickname
+    pub fn is_channel(&self, target: &str) -> bool {
+        target
+            .trim_start_matches(|x| self.statusmsg.contains(&x))
+            .starts_with(|x| self.chantypes.contains(&x))
+    }
+
+    // max targets of command from TARGMAX, None if unlimited or unknown
+    pub fn targmax(&self, command: &str) -> Option<usize> {
+        self.tokens
+            .get("TARGMAX")?
+            .as_deref()?
+            .split(',')
+            .filter_map(|x| x.split_once(':'))
+            .find(|(key, _)| key.eq_ignore_ascii_case(command))
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.tokens.contains_key(key)
+    }
+
+    fn reset(&mut self, key: &str) {
+        let default = Self::default();
+
+        match key {
+            "CASEMAPPING" => self.casemapping = default.casemapping,
+            "PREFIX" => self.prefixes = default.prefixes,
+            "CHANMODES" => self.chanmodes = default.chanmodes,
+            "CHANTYPES" => self.chantypes = default.chantypes,
+            "STATUSMSG" => self.statusmsg = default.statusmsg,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(raw: &str) -> Vec<String> {
+        raw.split(' ').map(|x| x.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_casemapping() {
+        assert_eq!(Casemapping::Ascii.fold("#Rust[]~"), "#rust[]~");
+        assert_eq!(Casemapping::Rfc1459.fold("#Rust[]\\~"), "#rust{}|^");
+        assert_eq!(Casemapping::Rfc1459Strict.fold("#Rust[]\\~"), "#rust{}|~");
+        assert!(Casemapping::Rfc1459.eq_ignore_case("Nick[a]", "nick{A}"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut isupport = ISupport::default();
+
+        isupport.apply(&tokens(
+            "CASEMAPPING=ascii PREFIX=(ov)@+ CHANMODES=beI,k,l,imnpst NETWORK=Test\\x20Net EXCEPTS",
+        ));
+
+        assert_eq!(isupport.casemapping, Casemapping::Ascii);
+        assert_eq!(isupport.prefixes, vec![('o', '@'), ('v', '+')]);
+        assert_eq!(isupport.prefix('v'), Some('+'));
+        assert_eq!(isupport.chanmodes.on_set, "l");
+        assert!(isupport.tokens().contains(&"NETWORK=Test\\x20Net".to_owned()));
+        assert!(isupport.tokens().contains(&"EXCEPTS".to_owned()));
+
+        isupport.apply(&tokens("-CASEMAPPING -EXCEPTS"));
+
+        assert_eq!(isupport.casemapping, Casemapping::Rfc1459);
+        assert!(!isupport.tokens().contains(&"EXCEPTS".to_owned()));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut isupport = ISupport::default();
+
+        isupport.apply(&tokens("PREFIX=(qaohv)~&@%"));
+
+        assert_eq!(isupport.prefix('q'), Some('~'));
+        assert!(!isupport.tokens().iter().any(|x| x.starts_with("PREFIX")));
+
+        isupport.apply(&tokens("PREFIX=(ov)@+"));
+
+        assert_eq!(isupport.prefix('q'), None);
+        assert!(isupport.is_prefix('+'));
+        assert_eq!(isupport.sort_prefixes("+@"), "@+");
+    }
+
+    #[test]
+    fn test_is_channel() {
+        let mut isupport = ISupport::default();
+
+        assert!(isupport.is_channel("#rust"));
+        assert!(!isupport.is_channel("nick"));
+        assert!(!isupport.is_channel("@#rust"));
+
+        isupport.apply(&tokens("CHANTYPES=#! STATUSMSG=@+"));
+
+        assert!(isupport.is_channel("!abcderust"));
+        assert!(!isupport.is_channel("&local"));
+        assert!(isupport.is_channel("@#rust"));
+    }
+
+    #[test]
+    fn test_targmax() {
+        let mut isupport = ISupport::default();
+
+        assert_eq!(isupport.targmax("JOIN"), None);
+
+        isupport.apply(&tokens("TARGMAX=NAMES:1,JOIN:,PRIVMSG:4"));
+
+        assert_eq!(isupport.targmax("PRIVMSG"), Some(4));
+        assert_eq!(isupport.targmax("JOIN"), None);
+        assert_eq!(isupport.targmax("KICK"), None);
+    }
+}
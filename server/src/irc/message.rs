@@ -1,15 +1,5 @@
 use std::iter;
 
-// works as string enum
-#[allow(non_camel_case_types)]
-#[allow(non_snake_case)]
-pub mod Reply {
-    pub const RPL_NAMREPLY: &str = "353";
-    pub const RPL_ENDOFNAMES: &str = "366";
-    pub const RPL_ENDOFMOTD: &str = "376";
-    pub const ERR_NOMOTD: &str = "422";
-}
-
 #[derive(Eq, PartialEq, Clone)]
 pub enum Prefix {
     Server(String),
@@ -35,6 +25,7 @@ impl Prefix {
 
 #[derive(Clone)]
 pub struct Message {
+    pub tags: Vec<(String, String)>,
     pub prefix: Option<Prefix>,
     pub command: String,
     pub args: Vec<String>,
@@ -45,19 +36,30 @@ impl Message {
         let command = command.to_owned();
         let args = args.into_iter().map(|x| x.to_owned()).collect::<Vec<_>>();
 
-        Self { prefix, command, args }
+        Self {
+            tags: Vec::new(),
+            prefix,
+            command,
+            args,
+        }
     }
 
     pub fn from_raw(raw: String) -> Self {
         let mut split = raw.trim_matches(|x: char| x.is_control()).split(' ').peekable();
 
-        let prefix = if split.peek().unwrap().starts_with(':') {
-            Some(Prefix::from_raw(split.next().unwrap()[1..].into()))
+        let tags = if let Some(x) = split.peek().and_then(|x| x.strip_prefix('@')) {
+            let tags = x.split(';').filter(|x| !x.is_empty()).map(Self::parse_tag).collect();
+            split.next();
+
+            tags
         } else {
-            None
+            Vec::new()
         };
 
-        let command = split.next().unwrap().into();
+        let prefix = split.next_if(|x| x.starts_with(':')).map(|x| Prefix::from_raw(x[1..].into()));
+
+        // empty for malformed lines
+        let command = split.next().unwrap_or_default().into();
 
         let mut args = Vec::<String>::with_capacity(split.size_hint().0);
         while let Some(item) = split.next() {
@@ -69,13 +71,18 @@ impl Message {
             }
         }
 
-        Self { prefix, command, args }
+        Self { tags, prefix, command, args }
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|x| x.0 == key).map(|x| x.1.as_ref())
     }
 
     pub fn raw(&self) -> String {
         let mut args = Vec::with_capacity(self.args.len());
-        for arg in &self.args {
-            if !arg.contains(' ') {
+        for (i, arg) in self.args.iter().enumerate() {
+            let is_last = i == self.args.len() - 1;
+            if !arg.contains(' ') && !(is_last && (arg.is_empty() || arg.starts_with(':'))) {
                 args.push(arg.into());
             } else {
                 args.push(format!(":{}", arg));
@@ -85,12 +92,63 @@ impl Message {
 
         let args = args.join(" ");
 
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            let tags = self.tags.iter().map(|(key, value)| Self::raw_tag(key, value)).collect::<Vec<_>>();
+
+            format!("@{} ", tags.join(";"))
+        };
+
         if let Some(x) = &self.prefix {
-            format!(":{} {} {}\r\n", x.raw(), self.command, args)
+            format!("{}:{} {} {}\r\n", tags, x.raw(), self.command, args)
         } else {
-            format!("{} {}\r\n", self.command, args)
+            format!("{}{} {}\r\n", tags, self.command, args)
         }
     }
+
+    fn parse_tag(raw: &str) -> (String, String) {
+        let (key, value) = raw.split_once('=').unwrap_or((raw, ""));
+
+        let mut unescaped = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(x) = chars.next() {
+            if x == '\\' {
+                match chars.next() {
+                    Some(':') => unescaped.push(';'),
+                    Some('s') => unescaped.push(' '),
+                    Some('r') => unescaped.push('\r'),
+                    Some('n') => unescaped.push('\n'),
+                    Some(x) => unescaped.push(x),
+                    None => {}
+                }
+            } else {
+                unescaped.push(x);
+            }
+        }
+
+        (key.into(), unescaped)
+    }
+
+    fn raw_tag(key: &str, value: &str) -> String {
+        if value.is_empty() {
+            return key.into();
+        }
+
+        let mut escaped = String::with_capacity(value.len());
+        for x in value.chars() {
+            match x {
+                ';' => escaped.push_str("\\:"),
+                ' ' => escaped.push_str("\\s"),
+                '\\' => escaped.push_str("\\\\"),
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                x => escaped.push(x),
+            }
+        }
+
+        format!("{}={}", key, escaped)
+    }
 }
 
 impl std::fmt::Display for Message {
@@ -144,6 +202,32 @@ mod test {
         assert_eq!(message.args[1], "test test");
     }
 
+    #[test]
+    fn test_parse_tags() {
+        let message = Message::from_raw("@time=2021-11-01T00:00:00.000Z;msgid=a\\sb\\:c;bot :test!test@test PRIVMSG #test :test\r\n".into());
+
+        assert_eq!(message.tag("time"), Some("2021-11-01T00:00:00.000Z"));
+        assert_eq!(message.tag("msgid"), Some("a b;c"));
+        assert_eq!(message.tag("bot"), Some(""));
+        assert!(message.prefix == Some(Prefix::User("test!test@test".into())));
+        assert_eq!(message.command, "PRIVMSG");
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert_eq!(Message::from_raw("".into()).command, "");
+        assert_eq!(Message::from_raw(":prefix.only".into()).command, "");
+        assert_eq!(Message::from_raw("@tag=only".into()).command, "");
+    }
+
+    #[test]
+    fn test_raw_tags() {
+        let mut message = Message::new(None, "PRIVMSG", vec!["#test", "test"]);
+        message.tags.push(("label".into(), "a b".into()));
+
+        assert_eq!(message.raw(), "@label=a\\sb PRIVMSG #test test\r\n");
+    }
+
     #[test]
     fn test_raw_simple() {
         let message = Message::new(None, "PING", vec!["12341234"]);
@@ -158,6 +242,13 @@ mod test {
         assert_eq!(message.raw(), ":test@test PRIVMSG #test :test test\r\n");
     }
 
+    #[test]
+    fn test_raw_empty_trailing() {
+        let message = Message::new(None, "TOPIC", vec!["#test", ""]);
+
+        assert_eq!(message.raw(), "TOPIC #test :\r\n");
+    }
+
     #[test]
     fn test_raw_prefix() {
         let message = Message::new(Some(Prefix::from_raw("test@test".into())), "PING", vec!["12341234"]);
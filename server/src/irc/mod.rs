@@ -1,9 +1,26 @@
+mod autojoin;
+mod cap;
 mod client;
+mod codec;
+mod command;
+mod correlate;
+mod ctcp;
+mod encoding;
+pub mod format;
+mod isupport;
 mod message;
+mod nick;
+mod outbound;
+mod perform;
+mod reply;
 mod server;
+mod service;
+mod split;
+mod state;
+mod throttle;
 mod transport;
 
 pub use client::Client;
+pub use encoding::Encoding;
+pub use isupport::{Casemapping, ISupport};
 pub use server::Server;
-
-pub use message::Message;
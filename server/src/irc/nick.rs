@@ -0,0 +1,88 @@
+use super::isupport::Casemapping;
+
+// nicknames of one upstream connection, primary first and then alternates
+pub struct Nicks {
+    wanted: Vec<String>,
+    // candidates rejected during registration
+    rejected: usize,
+    pub registered: bool,
+    // primary is watched with MONITOR, otherwise polled with ISON
+    pub monitoring: bool,
+    // our ISON polls awaiting RPL_ISON
+    pub polls: usize,
+    // NICK to primary sent, its error is ours
+    pub regaining: bool,
+}
+
+impl Nicks {
+    // `wanted` must not be empty
+    pub fn new(wanted: Vec<String>) -> Self {
+        Self {
+            wanted,
+            rejected: 0,
+            registered: false,
+            monitoring: false,
+            polls: 0,
+            regaining: false,
+        }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.wanted[0]
+    }
+
+    // nickname a client changed to, kept from then on instead of the configured one
+    pub fn set_primary(&mut self, nickname: String) {
+        self.wanted[0] = nickname;
+        self.regaining = false;
+    }
+
+    // candidate after the last one was rejected, suffixed primary once alternates run out
+    pub fn next(&mut self) -> String {
+        self.rejected += 1;
+
+        if let Some(nickname) = self.wanted.get(self.rejected) {
+            return nickname.clone();
+        }
+
+        let attempt = self.rejected - self.wanted.len() + 1;
+        if attempt <= 3 {
+            format!("{}{}", self.primary(), "_".repeat(attempt))
+        } else {
+            // short enough for any NICKLEN, in case the server truncates the ones above
+            format!("{}{}", self.primary().chars().take(5).collect::<String>(), attempt)
+        }
+    }
+
+    // target of MONITOR or ISON replies, hostmask allowed
+    pub fn is_primary(&self, target: &str, casemapping: Casemapping) -> bool {
+        let nickname = target.split('!').next().unwrap_or_default();
+
+        casemapping.eq_ignore_case(nickname, self.primary())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next() {
+        let mut nicks = Nicks::new(vec!["bouncer".into(), "bouncer2".into()]);
+
+        assert_eq!(nicks.next(), "bouncer2");
+        assert_eq!(nicks.next(), "bouncer_");
+        assert_eq!(nicks.next(), "bouncer__");
+        assert_eq!(nicks.next(), "bouncer___");
+        assert_eq!(nicks.next(), "bounc4");
+    }
+
+    #[test]
+    fn test_is_primary() {
+        let nicks = Nicks::new(vec!["Bouncer[1]".into()]);
+
+        assert!(nicks.is_primary("bouncer{1}!user@host", Casemapping::Rfc1459));
+        assert!(!nicks.is_primary("bouncer{1}", Casemapping::Ascii));
+        assert!(!nicks.is_primary("other", Casemapping::Rfc1459));
+    }
+}
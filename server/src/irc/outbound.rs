@@ -0,0 +1,144 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use super::{message::Message, transport::Transport};
+use crate::config::{ClientQueue, QueuePolicy};
+
+struct Queue {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+// per-client queue filled without waiting on the socket, drained by `forward`
+pub struct Outbound {
+    config: ClientQueue,
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+impl Outbound {
+    pub fn new(config: ClientQueue) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                closed: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    // false if the client should be disconnected
+    pub fn push(&self, message: Message) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+
+        // already disconnecting
+        if queue.closed {
+            return true;
+        }
+
+        if queue.messages.len() >= self.config.size {
+            match self.config.policy {
+                QueuePolicy::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                QueuePolicy::Disconnect => return false,
+            }
+        }
+        queue.messages.push_back(message);
+        drop(queue);
+
+        self.notify.notify_one();
+
+        true
+    }
+
+    // bypasses the size limit, for last words before disconnecting
+    pub fn push_final(&self, message: Message) {
+        self.queue.lock().unwrap().messages.push_back(message);
+        self.close();
+    }
+
+    // forward drains what's left and stops
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    pub async fn forward(&self, transport: Transport) {
+        loop {
+            match self.pop() {
+                Ok(Some(message)) => {
+                    if transport.send_message(&message).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => self.notify.notified().await,
+                Err(()) => break,
+            }
+        }
+    }
+
+    // Err when closed and drained
+    pub fn pop(&self) -> Result<Option<Message>, ()> {
+        let mut queue = self.queue.lock().unwrap();
+
+        if let Some(message) = queue.messages.pop_front() {
+            return Ok(Some(message));
+        }
+
+        if queue.closed {
+            return Err(());
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outbound(policy: QueuePolicy) -> Outbound {
+        Outbound::new(ClientQueue { size: 2, policy })
+    }
+
+    fn message(text: &str) -> Message {
+        Message::new(None, "PRIVMSG", vec!["#test", text])
+    }
+
+    fn drain(outbound: &Outbound) -> Vec<String> {
+        let mut result = Vec::new();
+
+        while let Ok(Some(message)) = outbound.pop() {
+            result.push(message.args.last().unwrap().clone());
+        }
+
+        result
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let outbound = outbound(QueuePolicy::DropOldest);
+
+        for text in &["a", "b", "c"] {
+            assert!(outbound.push(message(text)));
+        }
+
+        assert_eq!(drain(&outbound), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let outbound = outbound(QueuePolicy::Disconnect);
+
+        assert!(outbound.push(message("a")));
+        assert!(outbound.push(message("b")));
+        assert!(!outbound.push(message("c")));
+
+        outbound.push_final(message("bye"));
+
+        assert_eq!(drain(&outbound), vec!["a", "b", "bye"]);
+    }
+}
@@ -0,0 +1,36 @@
+use super::message::Message;
+
+// raw commands sent after registration, with `{nick}` and `{network}` filled in
+pub fn messages(commands: &[String], nickname: &str, network: &str) -> Vec<Message> {
+    commands
+        .iter()
+        .map(|x| x.trim())
+        // a leading slash is what users are used to type
+        .map(|x| x.strip_prefix('/').unwrap_or(x))
+        .filter(|x| !x.is_empty())
+        .map(|x| Message::from_raw(x.replace("{nick}", nickname).replace("{network}", network)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let commands = vec![
+            "MODE {nick} +x".into(),
+            "".into(),
+            "/PRIVMSG NickServ :IDENTIFY {nick} secret".into(),
+            "OPER admin {network}".into(),
+        ];
+
+        let messages = messages(&commands, "bouncer", "libera");
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].args, vec!["bouncer", "+x"]);
+        assert_eq!(messages[1].command, "PRIVMSG");
+        assert_eq!(messages[1].args, vec!["NickServ", "IDENTIFY bouncer secret"]);
+        assert_eq!(messages[2].args, vec!["admin", "libera"]);
+    }
+}
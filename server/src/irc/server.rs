@@ -1,195 +1,757 @@
-use std::{collections::HashMap, iter, net::Ipv4Addr, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    iter,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
-use futures::{stream::BoxStream, FutureExt, StreamExt};
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
 use log::{debug, error};
 use tokio::{
     io::Result,
     net::TcpListener,
     sync::{
-        broadcast::{channel, Sender},
-        Mutex,
+        mpsc::{channel, Receiver, Sender},
+        watch, Mutex, Notify,
     },
     task,
 };
-use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::wrappers::TcpListenerStream;
 
 use super::{
-    message::{Message as IRCMessage, Prefix as IRCPrefix, Reply as IRCReply},
+    command::Command,
+    ctcp,
+    isupport::ISupport,
+    message::{Message as IRCMessage, Prefix as IRCPrefix},
+    outbound::Outbound,
+    reply::Reply as IRCReply,
+    service,
     transport::Transport,
 };
-use crate::message::Message;
-use crate::sink::Sink;
+use crate::config::ClientQueue;
+use crate::control::{Controller, NetworkState};
+use crate::message::{Direction, Envelope, Message, Origin};
+use crate::sink::{Session, Sink};
+
+const ISUPPORT_TOKENS_PER_LINE: usize = 12;
+// lines read ahead of the bouncer, clients wait for room instead of losing lines
+const INBOUND_QUEUE_SIZE: usize = 256;
+// offered to clients in CAP LS
+const CAPABILITIES: &[&str] = &["away-notify"];
+
+struct Connection {
+    outbound: Arc<Outbound>,
+    address: SocketAddr,
+    kick: Arc<Notify>,
+    // NICK goes upstream once registered
+    registered: bool,
+    // chosen at registration, only its messages go to the connection
+    network: Option<String>,
+    // picked before registration, the network's one is used after
+    nickname: String,
+    // enabled by CAP REQ
+    caps: BTreeSet<String>,
+    // registration waits for CAP END once negotiation started
+    negotiating: bool,
+    // USER that came during negotiation
+    username: Option<String>,
+}
 
 struct Transports {
-    data: HashMap<u32, Transport>,
+    data: HashMap<u32, Connection>,
     index: u32,
+    // number of connections, for network tasks to know if anyone is attached
+    attached: watch::Sender<usize>,
 }
 
 impl Transports {
-    pub fn new() -> Self {
+    pub fn new(attached: watch::Sender<usize>) -> Self {
         Self {
             data: HashMap::new(),
             index: 0,
+            attached,
         }
     }
 
-    pub fn insert(&mut self, transport: &Transport) -> u32 {
+    pub fn insert(&mut self, outbound: Arc<Outbound>, address: SocketAddr, kick: Arc<Notify>) -> u32 {
         let index = self.index;
         self.index += 1;
 
-        self.data.insert(index, transport.clone());
+        self.data.insert(
+            index,
+            Connection {
+                outbound,
+                address,
+                kick,
+                registered: false,
+                network: None,
+                nickname: String::new(),
+                caps: BTreeSet::new(),
+                negotiating: false,
+                username: None,
+            },
+        );
+        // no receiver is not an error
+        let _ = self.attached.send(self.data.len());
 
         index
     }
 
     pub fn remove(&mut self, index: u32) {
         self.data.remove(&index);
+        let _ = self.attached.send(self.data.len());
+    }
+
+    pub fn get(&self, index: u32) -> Option<&Connection> {
+        self.data.get(&index)
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Transport> {
-        self.data.iter_mut().map(|x| x.1)
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut Connection> {
+        self.data.get_mut(&index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u32, &Connection)> {
+        self.data.iter()
     }
 }
 
+#[derive(Default)]
 struct Context {
     nickname: String,
+    // to tell channels from nicknames
+    isupport: ISupport,
 }
 
 pub struct Server {
-    sender: Sender<(IRCMessage, Transport)>,
+    // lines from all connections, tagged with connection id
+    receiver: Mutex<Receiver<(u32, IRCMessage)>>,
     streams: Arc<Mutex<Transports>>,
-    context: Mutex<Context>,
+    // per network, as each has its own nickname
+    contexts: Mutex<HashMap<String, Context>>,
+    controller: Controller,
 }
 
 impl Server {
-    pub async fn new(port: u16) -> Result<Self> {
+    pub async fn new(port: u16, client_queue: ClientQueue, attached: watch::Sender<usize>, controller: Controller) -> Result<Self> {
         let listener = TcpListener::bind((Ipv4Addr::new(0, 0, 0, 0), port)).await?;
 
-        let (sender, _) = channel(16);
-        let streams = Arc::new(Mutex::new(Transports::new()));
+        let (sender, receiver) = channel(INBOUND_QUEUE_SIZE);
+        let streams = Arc::new(Mutex::new(Transports::new(attached)));
 
         let result = Self {
-            sender: sender.clone(),
+            receiver: Mutex::new(receiver),
             streams,
-            context: Mutex::new(Context { nickname: "".into() }),
+            contexts: Mutex::new(HashMap::new()),
+            controller,
         };
 
         let streams = result.streams.clone();
-        task::spawn(async {
-            Self::accept_loop(listener, sender, streams).await.unwrap();
+        task::spawn(async move {
+            Self::accept_loop(listener, client_queue, sender, streams).await;
         });
 
         Ok(result)
     }
 
-    async fn accept_loop(listener: TcpListener, sender: Sender<(IRCMessage, Transport)>, transports: Arc<Mutex<Transports>>) -> Result<()> {
+    async fn accept_loop(listener: TcpListener, client_queue: ClientQueue, sender: Sender<(u32, IRCMessage)>, transports: Arc<Mutex<Transports>>) {
         let mut incoming = TcpListenerStream::new(listener);
 
         while let Some(stream) = incoming.next().await {
-            let transport = Transport::new(stream?);
+            // one failed connection shouldn't stop the listener
+            let (stream, address) = match stream.and_then(|x| x.peer_addr().map(|address| (x, address))) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Accept failed: {}", err);
+
+                    continue;
+                }
+            };
+            let transport = Transport::new(stream);
             let sender = sender.clone();
 
             let transports = transports.clone();
             task::spawn(async move {
-                Self::read_loop(transport, sender, transports).await.unwrap();
+                Self::read_loop(transport, address, client_queue, sender, transports).await;
             });
         }
-
-        Ok(())
     }
 
-    async fn read_loop(transport: Transport, sender: Sender<(IRCMessage, Transport)>, transports: Arc<Mutex<Transports>>) -> Result<()> {
-        let index = transports.lock().await.insert(&transport);
+    async fn read_loop(
+        transport: Transport,
+        address: SocketAddr,
+        client_queue: ClientQueue,
+        sender: Sender<(u32, IRCMessage)>,
+        transports: Arc<Mutex<Transports>>,
+    ) {
+        let kick = Arc::new(Notify::new());
+        let outbound = Arc::new(Outbound::new(client_queue));
+        let index = transports.lock().await.insert(outbound.clone(), address, kick.clone());
+
+        let forward = {
+            let outbound = outbound.clone();
+            let transport = transport.clone();
 
-        let mut stream = transport.stream().await;
-        while let Some(message) = stream.next().await {
-            sender.send((message, transport.clone())).unwrap_or(0); // TODO error handling
+            task::spawn(async move { outbound.forward(transport).await })
+        };
+
+        let mut stream = transport.stream();
+        loop {
+            tokio::select! {
+                message = stream.next() => match message {
+                    Some(message) => {
+                        // bouncer is shutting down
+                        if sender.send((index, message)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                _ = kick.notified() => break,
+            }
         }
 
         transports.lock().await.remove(index);
 
-        Ok(())
+        // let queued messages go out before the writer closes
+        outbound.close();
+        forward.await.unwrap_or(());
     }
 
-    async fn send_response(&self, receiver: &Transport, message: IRCMessage) -> Result<()> {
+    // goes through outbound queue to keep order with broadcasts
+    async fn send_response(&self, id: u32, message: IRCMessage) {
         debug!("To Client: {}", message);
-        receiver.send_message(&message).await
+
+        let streams = self.streams.lock().await;
+
+        // connection may be gone already
+        if let Some(connection) = streams.get(id) {
+            if !connection.outbound.push(message) {
+                Self::disconnect(connection, "Outbound queue full");
+            }
+        }
     }
 
-    async fn handle_message(&self, sender: &Transport, message: IRCMessage) -> Result<Option<Message>> {
+    async fn handle_message(&self, id: u32, message: IRCMessage) -> Vec<Message> {
         debug!("From Client: {}", message);
 
-        Ok(match message.command.as_ref() {
-            "USER" => {
-                let response = IRCMessage::new(
+        let command = match Command::try_from(&message) {
+            Ok(command) => command,
+            Err(err) => {
+                let response = IRCMessage::from_command(
                     Some(Self::server_prefix()),
-                    IRCReply::ERR_NOMOTD,
-                    vec!["testtest", "MOTD File is missing"],
+                    Command::Numeric {
+                        reply: IRCReply::ERR_NEEDMOREPARAMS,
+                        args: vec![self.nickname(id).await, err.command.clone(), "Not enough parameters".into()],
+                    },
                 );
+                self.send_response(id, response).await;
 
-                self.send_response(sender, response).await?;
-
-                None
+                return Vec::new();
             }
-            "CAP" => None,
-            "NICK" => {
-                self.context.lock().await.nickname = message.args[1].clone();
+        };
+
+        match command {
+            Command::User { username, .. } => {
+                let negotiating = match self.streams.lock().await.get_mut(id) {
+                    Some(connection) if connection.negotiating => {
+                        connection.username = Some(username.clone());
+
+                        true
+                    }
+                    _ => false,
+                };
+
+                if !negotiating {
+                    self.register(id, &username).await;
+                }
 
-                None
+                Vec::new()
             }
-            "PING" => {
-                let response = IRCMessage::new(Some(Self::server_prefix()), "PONG", vec![message.args[0].as_ref()]);
+            Command::Cap { args } => {
+                self.handle_cap(id, &args).await;
 
-                self.send_response(sender, response).await?;
+                Vec::new()
+            }
+            // context follows once upstream confirms
+            Command::Nick { nickname } if self.is_registered(id).await => vec![Message::ChangeNick { nickname }],
+            Command::Nick { nickname } => {
+                if let Some(connection) = self.streams.lock().await.get_mut(id) {
+                    connection.nickname = nickname;
+                }
 
-                None
+                Vec::new()
             }
-            "PRIVMSG" => Some(Message::Chat {
-                channel: message.args[0].clone(),
-                content: message.args[1].clone(),
-                sender: message.prefix.as_ref().unwrap().raw().into(),
-            }),
-            "JOIN" => Some(Message::JoinChannel {
-                channel: message.args[0].clone(),
-            }),
-            _ => {
-                error!("Unhandled {}", message.command);
+            Command::Ping { token } => {
+                let response = IRCMessage::from_command(Some(Self::server_prefix()), Command::Pong { token });
 
-                None
+                self.send_response(id, response).await;
+
+                Vec::new()
             }
-        })
-    }
+            Command::Privmsg { target, text } if target.eq_ignore_ascii_case(service::NICKNAME) => {
+                self.handle_service(id, &text).await;
 
-    fn convert_message(&self, message: &Message) -> Vec<IRCMessage> {
-        match message {
-            Message::Chat { sender, channel, content } => vec![IRCMessage {
-                prefix: Some(IRCPrefix::from_raw(sender.into())),
+                Vec::new()
+            }
+            Command::Privmsg { target, text } if ctcp::parse(&text).map(|(x, _)| x != "ACTION").unwrap_or(false) => vec![Message::Raw {
+                sender: String::new(),
                 command: "PRIVMSG".into(),
-                args: vec![channel.into(), content.into()],
+                args: vec![target, text],
+            }],
+            Command::Privmsg { target, text } => {
+                let sender = self.nickname(id).await;
+                let is_channel = self.is_channel(id, &target).await;
+
+                vec![match (is_channel, ctcp::parse_action(&text)) {
+                    (true, Some(content)) => Message::Action {
+                        sender,
+                        channel: target,
+                        content: content.into(),
+                    },
+                    (true, None) => Message::Chat {
+                        sender,
+                        channel: target,
+                        content: text,
+                    },
+                    (false, Some(content)) => Message::PrivateAction {
+                        sender,
+                        peer: target,
+                        content: content.into(),
+                    },
+                    (false, None) => Message::PrivateChat {
+                        sender,
+                        peer: target,
+                        content: text,
+                    },
+                }]
+            }
+            Command::Notice { target, text } => vec![Message::Notice {
+                sender: self.nickname(id).await,
+                target,
+                content: text,
+            }],
+            // keys pair with channels in order, the rest have none
+            Command::Join { channels, keys } => {
+                let keys = keys.into_iter().map(Some).chain(iter::repeat(None));
+
+                channels
+                    .into_iter()
+                    .zip(keys)
+                    .map(|(channel, key)| Message::JoinChannel { channel, key })
+                    .collect()
+            }
+            Command::Part { channels, reason } => channels
+                .into_iter()
+                .map(|channel| Message::PartChannel {
+                    channel,
+                    reason: reason.clone(),
+                })
+                .collect(),
+            Command::Kick { channel, user, reason } => vec![Message::Kick {
+                sender: self.nickname(id).await,
+                channel,
+                user,
+                reason,
+            }],
+            Command::Topic { channel, topic: Some(topic) } => vec![Message::Topic {
+                sender: self.nickname(id).await,
+                channel,
+                topic,
+            }],
+            Command::Mode { target, modes } if !modes.is_empty() => vec![Message::Mode {
+                sender: self.nickname(id).await,
+                target,
+                modes,
+            }],
+            Command::Invite { nickname, channel } => vec![Message::Invite {
+                sender: self.nickname(id).await,
+                user: nickname,
+                channel,
             }],
-            Message::JoinedChannel { channel, sender } => vec![IRCMessage {
-                prefix: Some(IRCPrefix::from_raw(sender.into())),
-                command: "JOIN".into(),
-                args: vec![channel.into()],
+            Command::Away { message } => vec![Message::Away {
+                sender: self.nickname(id).await,
+                message,
             }],
-            Message::UsersList { channel, users } => vec![
-                IRCMessage {
-                    prefix: Some(Self::server_prefix()),
-                    command: IRCReply::RPL_NAMREPLY.into(),
-                    args: iter::once(channel.into()).chain(users.iter().cloned()).collect::<Vec<_>>(),
+            // detaching from bouncer shouldn't quit from origin
+            Command::Quit { .. } => Vec::new(),
+            // bouncer's own registration and keepalive
+            Command::Pass { .. } | Command::Pong { .. } => Vec::new(),
+            command => vec![Message::Raw {
+                sender: String::new(),
+                command: command.name(),
+                args: command.args(),
+            }],
+        }
+    }
+
+    // caps are the bouncer's own, registration goes on at CAP END if USER came already
+    async fn handle_cap(&self, id: u32, args: &[String]) {
+        let nickname = match self.nickname(id).await {
+            x if x.is_empty() => "*".into(),
+            x => x,
+        };
+        let subcommand = args.first().map(|x| x.to_ascii_uppercase()).unwrap_or_default();
+
+        let (response, username) = {
+            let mut streams = self.streams.lock().await;
+            let connection = match streams.get_mut(id) {
+                Some(connection) => connection,
+                None => return,
+            };
+
+            if !connection.registered && subcommand != "END" {
+                connection.negotiating = true;
+            }
+
+            match (subcommand.as_ref(), args.get(1)) {
+                ("LS", _) => (Some(("LS", CAPABILITIES.join(" "))), None),
+                ("LIST", _) => (Some(("LIST", connection.caps.iter().cloned().collect::<Vec<_>>().join(" "))), None),
+                ("REQ", Some(requested)) => {
+                    // all or nothing
+                    if requested.split_whitespace().all(|x| CAPABILITIES.contains(&x.trim_start_matches('-'))) {
+                        for cap in requested.split_whitespace() {
+                            match cap.strip_prefix('-') {
+                                Some(cap) => connection.caps.remove(cap),
+                                None => connection.caps.insert(cap.to_owned()),
+                            };
+                        }
+
+                        (Some(("ACK", requested.clone())), None)
+                    } else {
+                        (Some(("NAK", requested.clone())), None)
+                    }
+                }
+                ("END", _) => {
+                    connection.negotiating = false;
+
+                    (None, connection.username.take())
+                }
+                _ => {
+                    let response = Self::reply(
+                        IRCReply::ERR_INVALIDCAPCMD,
+                        vec![nickname, subcommand.clone(), "Invalid CAP command".into()],
+                    );
+                    if !connection.outbound.push(response) {
+                        Self::disconnect(connection, "Outbound queue full");
+                    }
+
+                    return;
+                }
+            }
+        };
+
+        if let Some((subcommand, caps)) = response {
+            let message = IRCMessage::from_command(
+                Some(Self::server_prefix()),
+                Command::Cap {
+                    args: vec![nickname, subcommand.into(), caps],
+                },
+            );
+            self.send_response(id, message).await;
+        }
+
+        if let Some(username) = username {
+            self.register(id, &username).await;
+        }
+    }
+
+    async fn handle_service(&self, id: u32, text: &str) {
+        let nickname = self.nickname(id).await;
+        let network = self.network(id).await.unwrap_or_default();
+
+        for line in service::handle(&self.controller, &network, text).await {
+            let message = IRCMessage::from_command(
+                Some(Self::prefix(service::HOSTMASK)),
+                Command::Privmsg {
+                    target: nickname.clone(),
+                    text: line,
+                },
+            );
+            self.send_response(id, message).await;
+        }
+    }
+
+    async fn is_registered(&self, id: u32) -> bool {
+        self.streams.lock().await.get(id).map(|x| x.registered).unwrap_or(false)
+    }
+
+    async fn network(&self, id: u32) -> Option<String> {
+        self.streams.lock().await.get(id).and_then(|x| x.network.clone())
+    }
+
+    // network named after `/` in username, first connected one or first of all otherwise,
+    // slack networks are bridged only if named
+    async fn select_network(&self, username: &str) -> Option<String> {
+        let networks = self.controller.list_networks().await.unwrap_or_default();
+
+        let selected = match username.split_once('/') {
+            Some((_, name)) => networks.iter().find(|(x, _)| x.name == name),
+            None => {
+                let mut irc = networks.iter().filter(|(x, _)| x.slack.is_none());
+
+                irc.clone().find(|(_, state)| *state == NetworkState::Connected).or_else(|| irc.next())
+            }
+        };
+
+        selected.map(|(x, _)| x.name.clone())
+    }
+
+    // binds client to its network and sends ISUPPORT and joined channels of it
+    async fn register(&self, id: u32, username: &str) {
+        let network = self.select_network(username).await;
+
+        if let Some(connection) = self.streams.lock().await.get_mut(id) {
+            if network.is_none() {
+                Self::disconnect(connection, "No such network");
+
+                return;
+            }
+
+            connection.registered = true;
+            connection.network = network.clone();
+        }
+
+        // nothing to replay while network is disconnected
+        let network = network.unwrap_or_default();
+        let snapshot = self.controller.snapshot(network.clone()).await.unwrap_or_default();
+        let mut nickname = self.streams.lock().await.get(id).map(|x| x.nickname.clone()).unwrap_or_default();
+
+        let upstream = {
+            let mut contexts = self.contexts.lock().await;
+            let context = contexts.entry(network).or_default();
+
+            context.isupport = ISupport::default();
+            context.isupport.apply(&snapshot.isupport);
+
+            // first client names us until upstream does
+            if !snapshot.nickname.is_empty() {
+                context.nickname = snapshot.nickname.clone();
+            } else if context.nickname.is_empty() {
+                context.nickname = nickname.clone();
+            }
+
+            context.nickname.clone()
+        };
+
+        // client takes upstream nickname, so that prefixes and numerics are addressed to it
+        if upstream != nickname {
+            let message = IRCMessage::from_command(Some(IRCPrefix::User(nickname)), Command::Nick { nickname: upstream.clone() });
+            self.send_response(id, message).await;
+
+            nickname = upstream;
+        }
+
+        for tokens in snapshot.isupport.chunks(ISUPPORT_TOKENS_PER_LINE) {
+            let args = iter::once(nickname.clone())
+                .chain(tokens.iter().cloned())
+                .chain(iter::once("are supported by this server".into()))
+                .collect();
+
+            self.send_response(id, Self::reply(IRCReply::RPL_ISUPPORT, args)).await;
+        }
+
+        let response = Self::reply(IRCReply::ERR_NOMOTD, vec![nickname.clone(), "MOTD File is missing".into()]);
+        self.send_response(id, response).await;
+
+        for channel in snapshot.channels {
+            let mut messages = vec![IRCMessage::from_command(
+                Some(IRCPrefix::User(nickname.clone())),
+                Command::Join {
+                    channels: vec![channel.name.clone()],
+                    keys: Vec::new(),
+                },
+            )];
+
+            if let Some(topic) = channel.topic {
+                messages.push(Self::reply(IRCReply::RPL_TOPIC, vec![nickname.clone(), channel.name.clone(), topic.text]));
+
+                if let (Some(setter), Some(time)) = (topic.setter, topic.time) {
+                    messages.push(Self::reply(
+                        IRCReply::RPL_TOPICWHOTIME,
+                        vec![nickname.clone(), channel.name.clone(), setter, time.timestamp().to_string()],
+                    ));
+                }
+            }
+
+            let names = channel
+                .members
+                .iter()
+                .map(|x| format!("{}{}", x.prefixes, x.nickname))
+                .collect::<Vec<_>>()
+                .join(" ");
+            messages.push(Self::reply(
+                IRCReply::RPL_NAMREPLY,
+                vec![nickname.clone(), "=".into(), channel.name.clone(), names],
+            ));
+            messages.push(Self::reply(
+                IRCReply::RPL_ENDOFNAMES,
+                vec![nickname.clone(), channel.name, "End of /NAMES list.".into()],
+            ));
+
+            for message in messages {
+                self.send_response(id, message).await;
+            }
+        }
+    }
+
+    fn convert_message(message: &Message, context: &Context) -> Vec<IRCMessage> {
+        let (sender, command) = match message.clone() {
+            Message::Chat { sender, channel, content } => (
+                sender,
+                Command::Privmsg {
+                    target: channel,
+                    text: content,
+                },
+            ),
+            Message::Action { sender, channel, content } => (
+                sender,
+                Command::Privmsg {
+                    target: channel,
+                    text: ctcp::action(&content),
                 },
-                IRCMessage {
-                    prefix: Some(Self::server_prefix()),
-                    command: IRCReply::RPL_ENDOFNAMES.into(),
-                    args: vec![channel.into(), "End of /NAMES list.".into()],
+            ),
+            Message::PrivateChat { sender, peer, content } => (
+                sender.clone(),
+                Command::Privmsg {
+                    target: Self::query_target(&sender, peer, context),
+                    text: content,
                 },
-            ],
+            ),
+            Message::PrivateAction { sender, peer, content } => (
+                sender.clone(),
+                Command::Privmsg {
+                    target: Self::query_target(&sender, peer, context),
+                    text: ctcp::action(&content),
+                },
+            ),
+            Message::Notice { sender, target, content } => (sender, Command::Notice { target, text: content }),
+            Message::Topic { sender, channel, topic } => (sender, Command::Topic { channel, topic: Some(topic) }),
+            Message::Mode { sender, target, modes } => (sender, Command::Mode { target, modes }),
+            Message::Kick {
+                sender,
+                channel,
+                user,
+                reason,
+            } => (sender, Command::Kick { channel, user, reason }),
+            Message::Invite { sender, user, channel } => (sender, Command::Invite { nickname: user, channel }),
+            // our own away as numerics, which clients understand without away-notify
+            Message::Away { sender, message } if sender.split('!').next() == Some(context.nickname.as_str()) => {
+                let reply = match message {
+                    Some(_) => (IRCReply::RPL_NOWAWAY, "You have been marked as being away"),
+                    None => (IRCReply::RPL_UNAWAY, "You are no longer marked as being away"),
+                };
+
+                return vec![Self::reply(reply.0, vec![context.nickname.clone(), reply.1.into()])];
+            }
+            // only to clients with away-notify
+            Message::Away { sender, message } => (sender, Command::Away { message }),
+            Message::JoinedChannel { sender, channel } => (
+                sender,
+                Command::Join {
+                    channels: vec![channel],
+                    keys: Vec::new(),
+                },
+            ),
+            Message::PartedChannel { sender, channel, reason } => (
+                sender,
+                Command::Part {
+                    channels: vec![channel],
+                    reason,
+                },
+            ),
+            Message::Quit { sender, reason } => (sender, Command::Quit { reason }),
+            Message::NickChanged { sender, nickname } => (sender, Command::Nick { nickname }),
+            Message::UsersList { channel, users } => {
+                let nickname = context.nickname.clone();
+
+                return vec![
+                    Self::reply(
+                        IRCReply::RPL_NAMREPLY,
+                        vec![nickname.clone(), "=".into(), channel.clone(), users.join(" ")],
+                    ),
+                    Self::reply(IRCReply::RPL_ENDOFNAMES, vec![nickname, channel, "End of /NAMES list.".into()]),
+                ];
+            }
+            // upstream ERROR closes origin connection, not ours
+            Message::Error { message } => (
+                String::new(),
+                Command::Notice {
+                    target: "*".into(),
+                    text: format!("Origin error: {}", message),
+                },
+            ),
+            Message::Numeric { sender, code, args } => (
+                sender,
+                match IRCReply::from_command(&code) {
+                    Some(reply) => Command::Numeric { reply, args },
+                    None => Command::Other { command: code, args },
+                },
+            ),
+            Message::Raw { sender, command, args } => (sender, Command::Other { command, args }),
+            Message::JoinChannel { .. } | Message::PartChannel { .. } | Message::ChangeNick { .. } => return Vec::new(),
+        };
+
+        vec![IRCMessage::from_command(Some(Self::prefix(&sender)), command)]
+    }
+
+    // message from peer is addressed to us, and our own to peer
+    fn query_target(sender: &str, peer: String, context: &Context) -> String {
+        let nickname = sender.split('!').next().unwrap_or_default();
+
+        if context.isupport.casemapping.eq_ignore_case(nickname, &peer) {
+            context.nickname.clone()
+        } else {
+            peer
+        }
+    }
+
+    fn reply(reply: IRCReply, args: Vec<String>) -> IRCMessage {
+        IRCMessage::from_command(Some(Self::server_prefix()), Command::Numeric { reply, args })
+    }
+
+    // on network of the connection, or the one picked before registration
+    async fn nickname(&self, id: u32) -> String {
+        let (network, nickname) = match self.streams.lock().await.get(id) {
+            Some(connection) => (connection.network.clone(), connection.nickname.clone()),
+            None => return String::new(),
+        };
 
-            _ => unreachable!(),
+        match network {
+            Some(network) => self.contexts.lock().await.get(&network).map(|x| x.nickname.clone()).unwrap_or(nickname),
+            None => nickname,
         }
     }
 
+    async fn is_channel(&self, id: u32, target: &str) -> bool {
+        let network = self.network(id).await.unwrap_or_default();
+
+        match self.contexts.lock().await.get(&network) {
+            Some(context) => context.isupport.is_channel(target),
+            None => ISupport::default().is_channel(target),
+        }
+    }
+
+    fn prefix(sender: &str) -> IRCPrefix {
+        if sender.is_empty() {
+            Self::server_prefix()
+        } else {
+            IRCPrefix::from_raw(sender.into())
+        }
+    }
+
+    fn disconnect(connection: &Connection, reason: &str) {
+        let message = IRCMessage::new(None, "ERROR", vec![&format!("Closing Link: {}", reason)]);
+        debug!("To Client: {}", message);
+
+        connection.outbound.push_final(message);
+        connection.kick.notify_one();
+    }
+
     fn server_prefix() -> IRCPrefix {
         IRCPrefix::Server("irc.proxy".into())
     }
@@ -197,32 +759,377 @@ impl Server {
 
 #[async_trait]
 impl Sink for Server {
-    fn stream(&self) -> BoxStream<Message> {
-        BroadcastStream::new(self.sender.subscribe())
-            .filter_map(move |x| {
-                async move {
-                    let (message, sender) = x.unwrap(); // TODO error handling
-                    self.handle_message(&sender, message).await.unwrap()
-                }
-                .boxed()
-            })
+    fn stream(&self) -> BoxStream<'_, Envelope> {
+        stream::unfold(self, |server| async move {
+            let line = server.receiver.lock().await.recv().await;
+
+            line.map(|x| (x, server))
+        })
+        .then(move |(id, message)| {
+            async move {
+                let messages = self.handle_message(id, message).await;
+
+                (id, self.network(id).await.unwrap_or_default(), messages)
+            }
             .boxed()
+        })
+        .flat_map(|(id, network, messages)| {
+            stream::iter(messages.into_iter().map(move |x| {
+                let mut envelope = Envelope::new(&network, Direction::Outgoing, x);
+                envelope.origin = Some(Origin::Irc(id));
+
+                envelope
+            }))
+        })
+        .boxed()
     }
 
-    async fn broadcast(&self, message: &Message) -> Result<()> {
-        let messages = self.convert_message(message);
+    async fn broadcast(&self, envelope: &Envelope) -> Result<()> {
+        // replies go to their requester only, and echoes to everyone but their sender
+        let (target, skip) = match (envelope.direction, envelope.origin) {
+            (Direction::Incoming, Some(Origin::Irc(id))) => (Some(id), None),
+            (Direction::Incoming, Some(_)) => return Ok(()),
+            (Direction::Outgoing, Some(Origin::Irc(id))) => (None, Some(id)),
+            (_, _) => (None, None),
+        };
+
+        let messages = {
+            let mut contexts = self.contexts.lock().await;
+            let context = contexts.entry(envelope.network.clone()).or_default();
+
+            match &envelope.message {
+                // our nickname changed on upstream
+                Message::NickChanged { sender, nickname } => {
+                    if sender.split('!').next() == Some(&context.nickname) {
+                        context.nickname = nickname.clone();
+                    }
+
+                    Self::convert_message(&envelope.message, context)
+                }
+                // upstream registered again, clients are already registered but may have another nickname
+                Message::Numeric { code, args, .. } if code == "001" => match args.first() {
+                    Some(nickname) if context.nickname.is_empty() => {
+                        context.nickname = nickname.clone();
+
+                        Vec::new()
+                    }
+                    Some(nickname) if *nickname != context.nickname => {
+                        let message = IRCMessage::from_command(
+                            Some(IRCPrefix::User(context.nickname.clone())),
+                            Command::Nick { nickname: nickname.clone() },
+                        );
+                        context.nickname = nickname.clone();
+
+                        vec![message]
+                    }
+                    _ => Vec::new(),
+                },
+                _ => Self::convert_message(&envelope.message, context),
+            }
+        };
         for message in &messages {
             debug!("Broadcast: {}", message);
         }
 
-        let mut streams = self.streams.lock().await;
+        let cap = messages.iter().any(|x| x.command == "AWAY").then_some("away-notify");
+        let streams = self.streams.lock().await;
+
+        // enqueue only, a slow client must not hold up the others
+        for (id, connection) in streams.iter().filter(|(id, x)| {
+            x.network.as_ref() == Some(&envelope.network)
+                && target.map(|x| x == **id).unwrap_or(true)
+                && skip != Some(**id)
+                && cap.map(|cap| x.caps.contains(cap)).unwrap_or(true)
+        }) {
+            if !messages.iter().all(|x| connection.outbound.push(x.clone())) {
+                error!("Client {} ({}) can't keep up, disconnecting", id, connection.address);
 
-        for stream in streams.iter_mut() {
-            for message in &messages {
-                stream.send_message(message).await?;
+                Self::disconnect(connection, "Outbound queue full");
             }
         }
 
         Ok(())
     }
+
+    async fn sessions(&self) -> Vec<Session> {
+        self.streams
+            .lock()
+            .await
+            .iter()
+            .map(|(id, connection)| Session {
+                id: *id,
+                address: connection.address.to_string(),
+            })
+            .collect()
+    }
+
+    async fn kick(&self, id: u32, reason: &str) -> bool {
+        let streams = self.streams.lock().await;
+
+        if let Some(connection) = streams.get(id) {
+            Self::disconnect(connection, reason);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::Network;
+    use crate::control::Control;
+    use crate::message::Snapshot;
+
+    // slack network `s` and networks `a`, connected, and `b`, disconnected
+    fn controller() -> Controller {
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        task::spawn(async move {
+            let network = |raw: &str| toml::from_str::<Network>(raw).unwrap();
+
+            while let Some(control) = receiver.recv().await {
+                match control {
+                    Control::ListNetworks { reply } => {
+                        let _ = reply.send(vec![
+                            (
+                                network("name = \"s\"\nslack = { app_token = \"x\", bot_token = \"y\" }"),
+                                NetworkState::Connected,
+                            ),
+                            (network("name = \"a\""), NetworkState::Connected),
+                            (network("name = \"b\""), NetworkState::Disconnected),
+                        ]);
+                    }
+                    Control::Snapshot { network, reply } => {
+                        let _ = reply.send(Ok(Snapshot {
+                            network,
+                            ..Default::default()
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Controller::new(sender)
+    }
+
+    async fn server() -> Server {
+        let (attached, _) = watch::channel(0);
+
+        Server::new(0, ClientQueue::default(), attached, controller()).await.unwrap()
+    }
+
+    async fn connect(server: &Server) -> (u32, Arc<Outbound>) {
+        let outbound = Arc::new(Outbound::new(ClientQueue::default()));
+        let id = server
+            .streams
+            .lock()
+            .await
+            .insert(outbound.clone(), "127.0.0.1:6667".parse().unwrap(), Arc::new(Notify::new()));
+
+        (id, outbound)
+    }
+
+    fn drain(outbound: &Outbound) -> Vec<String> {
+        let mut result = Vec::new();
+
+        while let Ok(Some(message)) = outbound.pop() {
+            result.push(message.raw());
+        }
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_join_part() {
+        let server = server().await;
+
+        let messages = server.handle_message(0, IRCMessage::from_raw("JOIN #a,#b,#c key1,key2".into())).await;
+        let joins = messages
+            .into_iter()
+            .map(|x| match x {
+                Message::JoinChannel { channel, key } => (channel, key),
+                _ => panic!("not a join"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            joins,
+            vec![
+                ("#a".into(), Some("key1".into())),
+                ("#b".into(), Some("key2".into())),
+                ("#c".into(), None)
+            ]
+        );
+
+        let messages = server.handle_message(0, IRCMessage::from_raw("PART #a,#b :bye".into())).await;
+        let parts = messages
+            .into_iter()
+            .map(|x| match x {
+                Message::PartChannel { channel, reason } => (channel, reason),
+                _ => panic!("not a part"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parts, vec![("#a".into(), Some("bye".into())), ("#b".into(), Some("bye".into()))]);
+    }
+
+    #[test]
+    fn test_users_list() {
+        let context = Context {
+            nickname: "me".into(),
+            isupport: ISupport::default(),
+        };
+        let message = Message::UsersList {
+            channel: "#test".into(),
+            users: vec!["@op".into(), "user".into()],
+        };
+
+        let lines = Server::convert_message(&message, &context).iter().map(|x| x.raw()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                ":irc.proxy 353 me = #test :@op user\r\n",
+                ":irc.proxy 366 me #test :End of /NAMES list.\r\n"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nick() {
+        let server = server().await;
+        let (id, _) = connect(&server).await;
+
+        // registering client picks its own nickname
+        let messages = server.handle_message(id, IRCMessage::from_raw("NICK first".into())).await;
+        assert!(messages.is_empty());
+        assert_eq!(server.nickname(id).await, "first");
+
+        server.handle_message(id, IRCMessage::from_raw("USER user 0 * :real".into())).await;
+
+        let messages = server.handle_message(id, IRCMessage::from_raw("NICK second".into())).await;
+        assert!(matches!(messages.as_slice(), [Message::ChangeNick { nickname }] if nickname == "second"));
+        assert_eq!(server.nickname(id).await, "first");
+
+        let envelope = Envelope::new(
+            "a",
+            Direction::Incoming,
+            Message::NickChanged {
+                sender: "first!user@host".into(),
+                nickname: "second".into(),
+            },
+        );
+        server.broadcast(&envelope).await.unwrap();
+        assert_eq!(server.nickname(id).await, "second");
+    }
+
+    #[tokio::test]
+    async fn test_network() {
+        let server = server().await;
+        let (first, first_outbound) = connect(&server).await;
+        let (second, second_outbound) = connect(&server).await;
+        let (third, third_outbound) = connect(&server).await;
+
+        server.handle_message(first, IRCMessage::from_raw("USER user 0 * :real".into())).await;
+        server.handle_message(second, IRCMessage::from_raw("USER user/b 0 * :real".into())).await;
+        server.handle_message(third, IRCMessage::from_raw("USER user/c 0 * :real".into())).await;
+
+        assert_eq!(server.network(first).await.as_deref(), Some("a"));
+        assert_eq!(server.network(second).await.as_deref(), Some("b"));
+        assert_eq!(server.network(third).await, None);
+        assert!(drain(&third_outbound).last().unwrap().starts_with("ERROR"));
+        drain(&first_outbound);
+        drain(&second_outbound);
+
+        let envelope = Envelope::new(
+            "b",
+            Direction::Incoming,
+            Message::Chat {
+                sender: "nick!user@host".into(),
+                channel: "#test".into(),
+                content: "hello".into(),
+            },
+        );
+        server.broadcast(&envelope).await.unwrap();
+
+        assert!(drain(&first_outbound).is_empty());
+        assert_eq!(drain(&second_outbound), vec![":nick!user@host PRIVMSG #test hello\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_slack() {
+        let server = server().await;
+        let (irc, _) = connect(&server).await;
+        let (slack, _) = connect(&server).await;
+
+        server.handle_message(irc, IRCMessage::from_raw("NICK me".into())).await;
+        server.handle_message(irc, IRCMessage::from_raw("USER user 0 * :real".into())).await;
+        server.handle_message(slack, IRCMessage::from_raw("NICK me".into())).await;
+        server.handle_message(slack, IRCMessage::from_raw("USER user/s 0 * :real".into())).await;
+
+        // slack only if named
+        assert_eq!(server.network(irc).await.as_deref(), Some("a"));
+        assert_eq!(server.network(slack).await.as_deref(), Some("s"));
+
+        let envelope = Envelope::new(
+            "s",
+            Direction::Incoming,
+            Message::NickChanged {
+                sender: "me".into(),
+                nickname: "U123".into(),
+            },
+        );
+        server.broadcast(&envelope).await.unwrap();
+
+        // nickname on one network doesn't follow another
+        assert_eq!(server.nickname(irc).await, "me");
+        assert_eq!(server.nickname(slack).await, "U123");
+    }
+
+    #[tokio::test]
+    async fn test_cap() {
+        let server = server().await;
+        let (plain, plain_outbound) = connect(&server).await;
+        let (notified, notified_outbound) = connect(&server).await;
+
+        server.handle_message(plain, IRCMessage::from_raw("USER user 0 * :real".into())).await;
+
+        // registration waits for CAP END
+        server.handle_message(notified, IRCMessage::from_raw("CAP LS 302".into())).await;
+        server.handle_message(notified, IRCMessage::from_raw("NICK me".into())).await;
+        server.handle_message(notified, IRCMessage::from_raw("USER user 0 * :real".into())).await;
+        assert_eq!(server.network(notified).await, None);
+
+        server
+            .handle_message(notified, IRCMessage::from_raw("CAP REQ :away-notify batch".into()))
+            .await;
+        server.handle_message(notified, IRCMessage::from_raw("CAP REQ away-notify".into())).await;
+        server.handle_message(notified, IRCMessage::from_raw("CAP END".into())).await;
+        assert_eq!(server.network(notified).await.as_deref(), Some("a"));
+        assert_eq!(
+            drain(&notified_outbound)[..3],
+            [
+                ":irc.proxy CAP * LS away-notify\r\n",
+                ":irc.proxy CAP me NAK :away-notify batch\r\n",
+                ":irc.proxy CAP me ACK away-notify\r\n"
+            ]
+        );
+        drain(&plain_outbound);
+
+        let envelope = Envelope::new(
+            "a",
+            Direction::Incoming,
+            Message::Away {
+                sender: "other!user@host".into(),
+                message: Some("gone".into()),
+            },
+        );
+        server.broadcast(&envelope).await.unwrap();
+
+        assert!(drain(&plain_outbound).is_empty());
+        assert_eq!(drain(&notified_outbound), vec![":other!user@host AWAY gone\r\n"]);
+    }
 }
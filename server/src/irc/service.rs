@@ -0,0 +1,56 @@
+use crate::config::AutoJoin;
+use crate::control::{self, Controller};
+
+// pseudo user taking bouncer commands from clients
+pub const NICKNAME: &str = "*bouncer";
+pub const HOSTMASK: &str = "*bouncer!bouncer@bouncer";
+
+const HELP: &str = "Commands: autojoin [list], autojoin add <channel> [key], autojoin del <channel>, queue";
+
+// lines to reply with, commands apply to the network of the connection
+pub async fn handle(controller: &Controller, network: &str, text: &str) -> Vec<String> {
+    let args = text.split_whitespace().collect::<Vec<_>>();
+
+    let result = match args.as_slice() {
+        ["autojoin"] | ["autojoin", "list"] => controller.list_autojoin(network.to_owned()).await.map(|channels| {
+            if channels.is_empty() {
+                return vec!["Autojoin list is empty".into()];
+            }
+
+            channels
+                .into_iter()
+                .map(|x| match x.key {
+                    Some(key) => format!("{} (key {})", x.channel, key),
+                    None => x.channel,
+                })
+                .collect()
+        }),
+        ["autojoin", "add", channel, rest @ ..] if rest.len() <= 1 => {
+            let autojoin = AutoJoin {
+                channel: (*channel).to_owned(),
+                key: rest.first().map(|x| (*x).to_owned()),
+            };
+
+            controller
+                .add_autojoin(network.to_owned(), autojoin)
+                .await
+                .map(|_| vec![format!("Added {} to autojoin", channel)])
+        }
+        ["autojoin", "del", channel] => controller
+            .remove_autojoin(network.to_owned(), (*channel).to_owned())
+            .await
+            .map(|_| vec![format!("Removed {} from autojoin", channel)]),
+        ["queue"] => controller
+            .snapshot(network.to_owned())
+            .await
+            .map(|x| vec![format!("{} lines are waiting to be sent upstream", x.queued)]),
+        _ => Ok(vec![HELP.into()]),
+    };
+
+    result.unwrap_or_else(|err| {
+        vec![match err {
+            control::Error::NotFound => "No such network or channel".into(),
+            err => format!("Failed: {:?}", err),
+        }]
+    })
+}
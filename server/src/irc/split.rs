@@ -0,0 +1,249 @@
+// relayed lines are limited to 512 bytes without tags
+const LINE_LIMIT: usize = 512;
+// `user@host` we don't know yet, USERLEN and HOSTLEN of common ircds
+const USERHOST_RESERVE: usize = 1 + 10 + 1 + 63;
+
+// toggles carried over to continuation lines, reset is `\x0f`
+const TOGGLES: [char; 6] = ['\x02', '\x1d', '\x1f', '\x1e', '\x11', '\x16'];
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0f';
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Line {
+    pub text: String,
+    // continues previous line, for `draft/multiline-concat`
+    pub concat: bool,
+}
+
+// text bytes that fit in `:hostmask COMMAND target :text` as relayed by upstream
+pub fn budget(hostmask: &str, command: &str, target: &str) -> usize {
+    let mut prefix = 1 + hostmask.len() + 1;
+    if !hostmask.contains('!') {
+        prefix += USERHOST_RESERVE;
+    }
+
+    LINE_LIMIT.saturating_sub(prefix + command.len() + 1 + target.len() + 2 + 2)
+}
+
+// lines of text, with ones over `budget` bytes split at spaces if possible and at characters otherwise
+pub fn split(text: &str, budget: usize) -> Vec<Line> {
+    text.split('\n')
+        .map(|x| x.strip_suffix('\r').unwrap_or(x))
+        .flat_map(|x| split_line(x, budget))
+        .collect()
+}
+
+// lines grouped into batches of at most `max_bytes` of text and `max_lines` lines
+pub fn batches(lines: Vec<Line>, max_bytes: usize, max_lines: Option<usize>) -> Vec<Vec<Line>> {
+    let mut result = Vec::<Vec<Line>>::new();
+    let mut bytes = 0;
+
+    for line in lines {
+        let full = match result.last() {
+            Some(batch) => bytes + line.text.len() + 1 > max_bytes || max_lines.map(|x| batch.len() >= x).unwrap_or(false),
+            None => true,
+        };
+
+        if full {
+            result.push(Vec::new());
+            bytes = 0;
+        }
+
+        bytes += line.text.len() + 1;
+        result.last_mut().unwrap().push(line);
+    }
+
+    result
+}
+
+// `max-bytes` and `max-lines` from value of `draft/multiline`
+pub fn multiline_limits(value: &str) -> Option<(usize, Option<usize>)> {
+    let limit = |key: &str| {
+        value
+            .split(',')
+            .filter_map(|x| x.split_once('='))
+            .find(|(x, _)| *x == key)
+            .and_then(|(_, x)| x.parse().ok())
+    };
+
+    Some((limit("max-bytes")?, limit("max-lines")))
+}
+
+#[derive(Clone, Default)]
+struct Format {
+    toggles: [bool; TOGGLES.len()],
+    color: Option<String>,
+    hex_color: Option<String>,
+}
+
+impl Format {
+    fn apply(&mut self, atom: &str) {
+        match atom.chars().next() {
+            Some(RESET) => *self = Self::default(),
+            // bare color code resets color
+            Some(COLOR) => self.color = Some(atom.to_owned()).filter(|x| x.len() > 1),
+            Some(HEX_COLOR) => self.hex_color = Some(atom.to_owned()).filter(|x| x.len() > 1),
+            Some(x) => {
+                if let Some(index) = TOGGLES.iter().position(|y| *y == x) {
+                    self.toggles[index] ^= true;
+                }
+            }
+            None => {}
+        }
+    }
+
+    // codes restoring this format at the start of a line
+    fn codes(&self) -> String {
+        let toggles = TOGGLES.iter().zip(&self.toggles).filter(|(_, on)| **on).map(|(x, _)| *x);
+
+        self.color.iter().chain(&self.hex_color).map(|x| x.as_str()).collect::<String>() + &toggles.collect::<String>()
+    }
+}
+
+fn split_line(line: &str, budget: usize) -> Vec<Line> {
+    let atoms = atoms(line);
+    let mut result = Vec::new();
+
+    let mut format = Format::default();
+    let mut current = String::new();
+    // codes carried over at the start of current line
+    let mut carried = 0;
+    // last space in current line: length before it, format there and index after it
+    let mut space = None;
+
+    let mut index = 0;
+    while index < atoms.len() {
+        let atom = atoms[index];
+
+        if atom == " " && current.len() > carried {
+            space = Some((current.len(), format.clone(), index + 1));
+        }
+
+        if current.len() + atom.len() > budget && current.len() > carried {
+            if let Some((len, space_format, next)) = space.take() {
+                current.truncate(len);
+                format = space_format;
+                index = next;
+            }
+
+            result.push(std::mem::take(&mut current));
+            current = format.codes();
+            carried = current.len();
+
+            continue;
+        }
+
+        current.push_str(atom);
+        format.apply(atom);
+        index += 1;
+    }
+    result.push(current);
+
+    result.into_iter().enumerate().map(|(i, text)| Line { text, concat: i > 0 }).collect()
+}
+
+// characters and formatting codes, which must not be split
+fn atoms(line: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut rest = line;
+
+    while let Some(first) = rest.chars().next() {
+        let len = match first {
+            COLOR => code_len(rest, |x| x.is_ascii_digit(), 2),
+            HEX_COLOR => code_len(rest, |x| x.is_ascii_hexdigit(), 6),
+            x => x.len_utf8(),
+        };
+
+        result.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    result
+}
+
+// `\x03` with optional foreground and `,background`
+fn code_len(code: &str, digit: fn(&char) -> bool, max: usize) -> usize {
+    let bytes = code.as_bytes();
+    let count = |from: usize| bytes[from..].iter().take(max).take_while(|x| digit(&(**x as char))).count();
+
+    let mut len = 1;
+    let foreground = count(len);
+    len += foreground;
+
+    if foreground > 0 && bytes.get(len) == Some(&b',') {
+        let background = count(len + 1);
+        if background > 0 {
+            len += 1 + background;
+        }
+    }
+
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|x| x.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_budget() {
+        let known = budget("nick!user@host", "PRIVMSG", "#rust");
+
+        assert_eq!(known, 512 - ":nick!user@host PRIVMSG #rust :\r\n".len());
+        assert_eq!(
+            budget("nick", "PRIVMSG", "#rust"),
+            512 - ":nick PRIVMSG #rust :\r\n".len() - USERHOST_RESERVE
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        let lines = split("hello world foo", 11);
+
+        assert_eq!(texts(&lines), vec!["hello world", "foo"]);
+        assert!(!lines[0].concat && lines[1].concat);
+
+        assert_eq!(texts(&split("abcdefgh", 3)), vec!["abc", "def", "gh"]);
+        assert_eq!(texts(&split("one\r\ntwo\nthree", 100)), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn test_split_utf8() {
+        let lines = split("가나다라", 7);
+
+        assert_eq!(texts(&lines), vec!["가나", "다라"]);
+    }
+
+    #[test]
+    fn test_split_format() {
+        // color code isn't split, and carried over with bold
+        let lines = split("\x02\x0304,12abc def", 12);
+
+        assert_eq!(texts(&lines), vec!["\x02\x0304,12abc", "\x0304,12\x02def"]);
+
+        // reset in between
+        let lines = split("\x02a\x0f b", 4);
+
+        assert_eq!(texts(&lines), vec!["\x02a\x0f", "b"]);
+    }
+
+    #[test]
+    fn test_multiline_limits() {
+        assert_eq!(multiline_limits("max-bytes=4096,max-lines=24"), Some((4096, Some(24))));
+        assert_eq!(multiline_limits("max-bytes=4096"), Some((4096, None)));
+        assert_eq!(multiline_limits("max-lines=24"), None);
+    }
+
+    #[test]
+    fn test_batches() {
+        let lines = split("aaaa\nbbbb\ncccc", 100);
+
+        assert_eq!(batches(lines.clone(), 10, None).len(), 2);
+        assert_eq!(batches(lines.clone(), 100, Some(1)).len(), 3);
+        assert_eq!(batches(lines, 100, None).len(), 1);
+    }
+}
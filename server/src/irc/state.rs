@@ -0,0 +1,744 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::{
+    command::Command,
+    isupport::{Casemapping, ISupport},
+    message::{Message as IRCMessage, Prefix as IRCPrefix},
+    reply::Reply as IRCReply,
+};
+use crate::message::{ChannelSnapshot, DeltaKind, MemberSnapshot, MembershipDelta, Snapshot, TopicSnapshot};
+
+// channel key and keys of nicknames the command may change, all members if None
+type Scope = (String, Option<Vec<String>>);
+// channel name and members by key
+type Members = (String, BTreeMap<String, MemberSnapshot>);
+
+#[derive(Clone)]
+pub struct Topic {
+    pub text: String,
+    pub setter: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone)]
+pub struct Member {
+    pub nickname: String,
+    pub prefixes: String,
+}
+
+#[derive(Default)]
+pub struct Channel {
+    pub name: String,
+    pub topic: Option<Topic>,
+    pub modes: BTreeMap<char, Option<String>>,
+    // keyed by casefolded nickname
+    pub members: BTreeMap<String, Member>,
+    // RPL_NAMREPLY in progress
+    names: Option<BTreeMap<String, Member>>,
+}
+
+#[derive(Default)]
+pub struct User {
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub away: Option<String>,
+}
+
+impl User {
+    pub fn hostmask(&self, nickname: &str) -> Option<String> {
+        Some(format!("{}!{}@{}", nickname, self.user.as_ref()?, self.host.as_ref()?))
+    }
+}
+
+// channel and user state of one upstream connection, maps are keyed by casefolded names
+#[derive(Default)]
+pub struct State {
+    pub nickname: String,
+    isupport: ISupport,
+    channels: HashMap<String, Channel>,
+    users: HashMap<String, User>,
+}
+
+impl State {
+    pub fn new(nickname: &str) -> Self {
+        Self {
+            nickname: nickname.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&self.isupport.fold(name))
+    }
+
+    pub fn user(&self, nickname: &str) -> Option<&User> {
+        self.users.get(&self.isupport.fold(nickname))
+    }
+
+    // returns changes to members of joined channels
+    pub fn apply(&mut self, message: &IRCMessage) -> Vec<MembershipDelta> {
+        // invalid messages are reported by client
+        let command = match Command::try_from(message) {
+            Ok(command) => command,
+            Err(_) => return Vec::new(),
+        };
+        let prefix = message.prefix.as_ref();
+
+        let scopes = self.scopes(&command, prefix);
+        let before = scopes
+            .iter()
+            .map(|(channel, nicknames)| self.members(channel, nicknames.as_deref()))
+            .collect::<Vec<_>>();
+        let reset = matches!(
+            command,
+            Command::Numeric {
+                reply: IRCReply::RPL_ENDOFNAMES,
+                ..
+            }
+        );
+
+        self.apply_command(command, prefix);
+
+        scopes
+            .iter()
+            .zip(before)
+            .flat_map(|((channel, nicknames), before)| self.deltas(channel, nicknames.as_deref(), before, reset))
+            .collect()
+    }
+
+    fn apply_command(&mut self, command: Command, prefix: Option<&IRCPrefix>) {
+        match command {
+            Command::Join { channels, .. } => {
+                let nickname = self.update_user(prefix);
+                let key = self.isupport.fold(&nickname);
+
+                for name in channels {
+                    let channel_key = self.isupport.fold(&name);
+
+                    if self.is_me(&nickname) {
+                        self.channels.insert(channel_key.clone(), Channel { name, ..Default::default() });
+                    }
+                    if let Some(channel) = self.channels.get_mut(&channel_key) {
+                        channel.members.insert(
+                            key.clone(),
+                            Member {
+                                nickname: nickname.clone(),
+                                prefixes: String::new(),
+                            },
+                        );
+                    }
+                }
+            }
+            Command::Part { channels, .. } => {
+                let nickname = Self::nickname(prefix);
+
+                for channel in channels {
+                    self.remove_member(&channel, &nickname);
+                }
+            }
+            Command::Kick { channel, user, .. } => self.remove_member(&channel, &user),
+            Command::Quit { .. } => {
+                let key = self.isupport.fold(&Self::nickname(prefix));
+
+                for channel in self.channels.values_mut() {
+                    channel.members.remove(&key);
+                }
+                self.users.remove(&key);
+            }
+            Command::Nick { nickname: new } => {
+                let old = Self::nickname(prefix);
+                let old_key = self.isupport.fold(&old);
+                let new_key = self.isupport.fold(&new);
+
+                if self.is_me(&old) {
+                    self.nickname = new.clone();
+                }
+                for channel in self.channels.values_mut() {
+                    if let Some(mut member) = channel.members.remove(&old_key) {
+                        member.nickname = new.clone();
+                        channel.members.insert(new_key.clone(), member);
+                    }
+                }
+                if let Some(user) = self.users.remove(&old_key) {
+                    self.users.insert(new_key, user);
+                }
+            }
+            Command::Mode { target, modes } => {
+                if let [modes, mode_args @ ..] = modes.as_slice() {
+                    self.apply_mode(&target, modes, mode_args);
+                }
+            }
+            Command::Topic { channel, topic: Some(text) } => {
+                let setter = prefix.map(|x| x.raw().to_owned());
+
+                if let Some(channel) = self.channel_mut(&channel) {
+                    channel.topic = if text.is_empty() {
+                        None
+                    } else {
+                        Some(Topic {
+                            text,
+                            setter,
+                            time: Some(Utc::now()),
+                        })
+                    }
+                }
+            }
+            Command::Away { message } => {
+                let nickname = self.update_user(prefix);
+
+                if let Some(user) = self.user_mut(&nickname) {
+                    user.away = message;
+                }
+            }
+            Command::Chghost { user, host } => {
+                let nickname = self.update_user(prefix);
+
+                if let Some(x) = self.user_mut(&nickname) {
+                    x.user = Some(user);
+                    x.host = Some(host);
+                }
+            }
+            Command::Numeric { reply, args } => self.apply_numeric(reply, &args),
+            _ => {}
+        }
+    }
+
+    fn apply_numeric(&mut self, reply: IRCReply, args: &[String]) {
+        match (reply, args) {
+            (IRCReply::RPL_WELCOME, [nickname, ..]) => self.nickname = nickname.clone(),
+            (IRCReply::RPL_ISUPPORT, [_, tokens @ .., _]) => {
+                let casemapping = self.isupport.casemapping;
+                self.isupport.apply(tokens);
+
+                if self.isupport.casemapping != casemapping {
+                    self.refold(casemapping);
+                }
+            }
+            (IRCReply::RPL_CHANNELMODEIS, [_, channel, modes, mode_args @ ..]) => {
+                if let Some(x) = self.channel_mut(channel) {
+                    x.modes.clear();
+                }
+
+                self.apply_mode(channel, modes, mode_args);
+            }
+            (IRCReply::RPL_NOTOPIC, [_, channel, ..]) => {
+                if let Some(channel) = self.channel_mut(channel) {
+                    channel.topic = None;
+                }
+            }
+            (IRCReply::RPL_TOPIC, [_, channel, text]) => {
+                if let Some(channel) = self.channel_mut(channel) {
+                    channel.topic = Some(Topic {
+                        text: text.clone(),
+                        setter: None,
+                        time: None,
+                    });
+                }
+            }
+            (IRCReply::RPL_TOPICWHOTIME, [_, channel, setter, time, ..]) => {
+                if let Some(topic) = self.channel_mut(channel).and_then(|x| x.topic.as_mut()) {
+                    topic.setter = Some(setter.clone());
+                    topic.time = time.parse::<i64>().ok().and_then(|x| Utc.timestamp_opt(x, 0).single());
+                }
+            }
+            (IRCReply::RPL_NAMREPLY, [_, _symbol, channel, names]) => {
+                let members = names
+                    .split(' ')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| {
+                        let member = self.parse_name(x);
+
+                        (self.isupport.fold(&member.nickname), member)
+                    })
+                    .collect::<Vec<_>>();
+
+                if let Some(channel) = self.channel_mut(channel) {
+                    channel.names.get_or_insert_with(BTreeMap::new).extend(members);
+                }
+            }
+            (IRCReply::RPL_ENDOFNAMES, [_, channel, ..]) => {
+                if let Some(channel) = self.channel_mut(channel) {
+                    channel.members = channel.names.take().unwrap_or_default();
+                }
+            }
+            (IRCReply::RPL_AWAY, [_, nickname, text]) => {
+                if let Some(user) = self.user_mut(nickname) {
+                    user.away = Some(text.clone());
+                }
+            }
+            (IRCReply::RPL_UNAWAY, _) => {
+                let key = self.isupport.fold(&self.nickname);
+                self.users.entry(key).or_default().away = None;
+            }
+            (IRCReply::RPL_NOWAWAY, _) => {
+                let key = self.isupport.fold(&self.nickname);
+                self.users.entry(key).or_default().away = Some(String::new());
+            }
+            (IRCReply::RPL_WHOREPLY, [_, _channel, user, host, _server, nickname, flags, ..]) => {
+                if let Some(x) = self.user_mut(nickname) {
+                    x.user = Some(user.clone());
+                    x.host = Some(host.clone());
+
+                    if flags.starts_with('G') {
+                        x.away.get_or_insert_with(String::new);
+                    } else {
+                        x.away = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // channels whose members the command may change
+    fn scopes(&self, command: &Command, prefix: Option<&IRCPrefix>) -> Vec<Scope> {
+        let fold = |x: &str| self.isupport.fold(x);
+        // channels the user is on
+        let user = |nicknames: Vec<String>| {
+            let keys = nicknames.iter().map(|x| fold(x)).collect::<Vec<_>>();
+
+            self.channels
+                .iter()
+                .filter(|(_, x)| keys.iter().any(|y| x.members.contains_key(y)))
+                .map(|(key, _)| (key.clone(), Some(keys.clone())))
+                .collect()
+        };
+        let nickname = Self::nickname(prefix);
+
+        match command {
+            Command::Join { channels, .. } | Command::Part { channels, .. } => {
+                channels.iter().map(|x| (fold(x), Some(vec![fold(&nickname)]))).collect()
+            }
+            Command::Kick { channel, user, .. } => vec![(fold(channel), Some(vec![fold(user)]))],
+            Command::Mode { target, .. } => vec![(fold(target), None)],
+            Command::Nick { nickname: new } => user(vec![nickname, new.clone()]),
+            Command::Quit { .. } | Command::Away { .. } | Command::Chghost { .. } => user(vec![nickname]),
+            Command::Numeric { reply, args } => match (*reply, args.as_slice()) {
+                (IRCReply::RPL_ENDOFNAMES, [_, channel, ..]) => vec![(fold(channel), None)],
+                (IRCReply::RPL_AWAY, [_, nickname, ..]) | (IRCReply::RPL_WHOREPLY, [_, _, _, _, _, nickname, ..]) => user(vec![nickname.clone()]),
+                (IRCReply::RPL_UNAWAY | IRCReply::RPL_NOWAWAY, _) => user(vec![self.nickname.clone()]),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn members(&self, key: &str, nicknames: Option<&[String]>) -> Option<Members> {
+        let channel = self.channels.get(key)?;
+        let members = match nicknames {
+            Some(keys) => keys.iter().filter_map(|x| channel.members.get_key_value(x)).collect::<Vec<_>>(),
+            None => channel.members.iter().collect(),
+        };
+
+        Some((
+            channel.name.clone(),
+            members.into_iter().map(|(key, x)| (key.clone(), self.member_snapshot(x))).collect(),
+        ))
+    }
+
+    fn deltas(&self, key: &str, nicknames: Option<&[String]>, before: Option<Members>, reset: bool) -> Vec<MembershipDelta> {
+        let delta = |channel: &str, kind, members: Vec<MemberSnapshot>| MembershipDelta {
+            channel: channel.to_owned(),
+            kind,
+            members,
+        };
+
+        let (channel, before, after) = match (before, self.members(key, nicknames)) {
+            (Some((_, before)), Some((channel, after))) if !reset => (channel, before, after),
+            // joined or names complete
+            (_, Some((channel, _))) => {
+                let members = self.members(key, None).map(|(_, x)| x.into_values().collect()).unwrap_or_default();
+
+                return vec![delta(&channel, DeltaKind::Reset, members)];
+            }
+            (Some((channel, _)), None) => return vec![delta(&channel, DeltaKind::Reset, Vec::new())],
+            (None, None) => return Vec::new(),
+        };
+
+        let left: Vec<_> = before
+            .iter()
+            .filter(|(key, _)| !after.contains_key(*key))
+            .map(|(_, x)| x.clone())
+            .collect();
+        let joined: Vec<_> = after
+            .iter()
+            .filter(|(key, _)| !before.contains_key(*key))
+            .map(|(_, x)| x.clone())
+            .collect();
+        let updated: Vec<_> = after
+            .iter()
+            .filter(|(key, x)| before.get(*key).map(|y| y != *x).unwrap_or(false))
+            .map(|(_, x)| x.clone())
+            .collect();
+
+        vec![(DeltaKind::Left, left), (DeltaKind::Joined, joined), (DeltaKind::Updated, updated)]
+            .into_iter()
+            .filter(|(_, members)| !members.is_empty())
+            .map(|(kind, members)| delta(&channel, kind, members))
+            .collect()
+    }
+
+    fn member_snapshot(&self, member: &Member) -> MemberSnapshot {
+        let user = self.user(&member.nickname);
+
+        MemberSnapshot {
+            nickname: member.nickname.clone(),
+            prefixes: member.prefixes.clone(),
+            hostmask: user.and_then(|x| x.hostmask(&member.nickname)),
+            away: user.and_then(|x| x.away.clone()),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut channels = self
+            .channels
+            .values()
+            .map(|channel| ChannelSnapshot {
+                name: channel.name.clone(),
+                topic: channel.topic.as_ref().map(|x| TopicSnapshot {
+                    text: x.text.clone(),
+                    setter: x.setter.clone(),
+                    time: x.time,
+                }),
+                modes: Self::format_modes(&channel.modes),
+                members: channel.members.values().map(|x| self.member_snapshot(x)).collect(),
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Snapshot {
+            // known to client only
+            network: String::new(),
+            nickname: self.nickname.clone(),
+            isupport: self.isupport.tokens(),
+            channels,
+            // known to transport only
+            queued: 0,
+        }
+    }
+
+    // our nickname with user and host if known
+    pub fn hostmask(&self) -> String {
+        self.user(&self.nickname)
+            .and_then(|x| x.hostmask(&self.nickname))
+            .unwrap_or_else(|| self.nickname.clone())
+    }
+
+    pub fn is_channel(&self, target: &str) -> bool {
+        self.isupport.is_channel(target)
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        self.isupport.casemapping
+    }
+
+    pub fn targmax(&self, command: &str) -> Option<usize> {
+        self.isupport.targmax(command)
+    }
+
+    pub fn supports(&self, token: &str) -> bool {
+        self.isupport.has(token)
+    }
+
+    pub fn is_me(&self, nickname: &str) -> bool {
+        self.isupport.casemapping.eq_ignore_case(nickname, &self.nickname)
+    }
+
+    fn channel_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(&self.isupport.fold(name))
+    }
+
+    fn user_mut(&mut self, nickname: &str) -> Option<&mut User> {
+        self.users.get_mut(&self.isupport.fold(nickname))
+    }
+
+    // rebuilds keys after CASEMAPPING changed
+    fn refold(&mut self, old: Casemapping) {
+        let new = self.isupport.casemapping;
+
+        // users don't keep display nickname, so take it from channel members
+        let keys = self
+            .channels
+            .values()
+            .flat_map(|x| x.members.values())
+            .map(|x| (old.fold(&x.nickname), new.fold(&x.nickname)))
+            .collect::<HashMap<_, _>>();
+
+        self.channels = self
+            .channels
+            .drain()
+            .map(|(_, mut channel)| {
+                channel.members = channel.members.into_values().map(|x| (new.fold(&x.nickname), x)).collect();
+
+                (new.fold(&channel.name), channel)
+            })
+            .collect();
+        self.users = self
+            .users
+            .drain()
+            .map(|(key, user)| (keys.get(&key).cloned().unwrap_or_else(|| new.fold(&key)), user))
+            .collect();
+    }
+
+    fn apply_mode(&mut self, target: &str, modes: &str, args: &[String]) {
+        let isupport = self.isupport.clone();
+        let channel = if let Some(channel) = self.channel_mut(target) {
+            channel
+        } else {
+            return;
+        };
+
+        let mut args = args.iter();
+        let mut set = true;
+
+        for mode in modes.chars() {
+            match (mode, isupport.prefix(mode)) {
+                ('+', _) => set = true,
+                ('-', _) => set = false,
+                (_, Some(prefix)) => {
+                    if let Some(member) = args.next().and_then(|x| channel.members.get_mut(&isupport.fold(x))) {
+                        member.prefixes.retain(|y| y != prefix);
+                        if set {
+                            member.prefixes.push(prefix);
+                            member.prefixes = isupport.sort_prefixes(&member.prefixes);
+                        }
+                    }
+                }
+                (x, None) if isupport.chanmodes.list.contains(x) => {
+                    args.next();
+                }
+                (x, None) => {
+                    let arg = if isupport.chanmodes.always.contains(x) || (set && isupport.chanmodes.on_set.contains(x)) {
+                        args.next().cloned()
+                    } else {
+                        None
+                    };
+
+                    if set {
+                        channel.modes.insert(x, arg);
+                    } else {
+                        channel.modes.remove(&x);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_member(&mut self, channel: &str, nickname: &str) {
+        let key = self.isupport.fold(nickname);
+
+        if self.is_me(nickname) {
+            self.channels.remove(&self.isupport.fold(channel));
+        } else if let Some(channel) = self.channel_mut(channel) {
+            channel.members.remove(&key);
+        }
+
+        // forget users we don't share any channel with
+        if !self.is_me(nickname) && !self.channels.values().any(|x| x.members.contains_key(&key)) {
+            self.users.remove(&key);
+        }
+    }
+
+    // records hostmask from prefix, returns nickname
+    fn update_user(&mut self, prefix: Option<&IRCPrefix>) -> String {
+        let raw = prefix.map(|x| x.raw()).unwrap_or_default();
+        let (nickname, user, host) = Self::split_hostmask(raw);
+
+        let entry = self.users.entry(self.isupport.fold(nickname)).or_default();
+        if let (Some(user), Some(host)) = (user, host) {
+            entry.user = Some(user.into());
+            entry.host = Some(host.into());
+        }
+
+        nickname.into()
+    }
+
+    // parses multi-prefix and userhost-in-names entries of RPL_NAMREPLY
+    fn parse_name(&mut self, name: &str) -> Member {
+        let hostmask = name.trim_start_matches(|x| self.isupport.is_prefix(x));
+        let prefixes = name[..name.len() - hostmask.len()].to_owned();
+
+        let (nickname, user, host) = Self::split_hostmask(hostmask);
+        let entry = self.users.entry(self.isupport.fold(nickname)).or_default();
+        if let (Some(user), Some(host)) = (user, host) {
+            entry.user = Some(user.into());
+            entry.host = Some(host.into());
+        }
+
+        Member {
+            nickname: nickname.into(),
+            prefixes,
+        }
+    }
+
+    fn nickname(prefix: Option<&IRCPrefix>) -> String {
+        Self::split_hostmask(prefix.map(|x| x.raw()).unwrap_or_default()).0.into()
+    }
+
+    fn split_hostmask(raw: &str) -> (&str, Option<&str>, Option<&str>) {
+        let (rest, host) = match raw.split_once('@') {
+            Some((rest, host)) => (rest, Some(host)),
+            None => (raw, None),
+        };
+        let (nickname, user) = match rest.split_once('!') {
+            Some((nickname, user)) => (nickname, Some(user)),
+            None => (rest, None),
+        };
+
+        (nickname, user, host)
+    }
+
+    fn format_modes(modes: &BTreeMap<char, Option<String>>) -> String {
+        let flags = modes.keys().collect::<String>();
+        let args = modes.values().flatten().cloned().collect::<Vec<_>>();
+
+        if args.is_empty() {
+            format!("+{}", flags)
+        } else {
+            format!("+{} {}", flags, args.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(state: &mut State, raw: &str) {
+        state.apply(&IRCMessage::from_raw(raw.into()));
+    }
+
+    fn deltas(state: &mut State, raw: &str) -> Vec<(DeltaKind, Vec<String>)> {
+        state
+            .apply(&IRCMessage::from_raw(raw.into()))
+            .into_iter()
+            .map(|x| (x.kind, x.members.into_iter().map(|y| format!("{}{}", y.prefixes, y.nickname)).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_join_and_names() {
+        let mut state = State::new("me");
+
+        apply(&mut state, ":me!u@h JOIN #a");
+        apply(&mut state, ":me!u@h JOIN #b");
+        apply(&mut state, ":server.com 353 me = #a :@me +other");
+        apply(&mut state, ":server.com 353 me = #b :me third");
+        apply(&mut state, ":server.com 353 me = #a :fourth");
+        apply(&mut state, ":server.com 366 me #b :End of /NAMES list.");
+        apply(&mut state, ":server.com 366 me #a :End of /NAMES list.");
+
+        let a = state.channel("#a").unwrap();
+        assert_eq!(a.members.len(), 3);
+        assert_eq!(a.members["me"].prefixes, "@");
+        assert_eq!(a.members["other"].prefixes, "+");
+
+        let b = state.channel("#b").unwrap();
+        assert_eq!(b.members.len(), 2);
+        assert!(b.members.contains_key("third"));
+        assert_eq!(state.user("me").unwrap().hostmask("me").unwrap(), "me!u@h");
+    }
+
+    #[test]
+    fn test_topic_and_mode() {
+        let mut state = State::new("me");
+
+        apply(&mut state, ":me!u@h JOIN #a");
+        apply(&mut state, ":server.com 332 me #a :hello world");
+        apply(&mut state, ":server.com 333 me #a setter!u@h 1600000000");
+        apply(&mut state, ":server.com 324 me #a +nl 10");
+        apply(&mut state, ":op!u@h MODE #a -l+k key");
+
+        let topic = state.channel("#a").unwrap().topic.clone().unwrap();
+        assert_eq!(topic.text, "hello world");
+        assert_eq!(topic.setter.unwrap(), "setter!u@h");
+        assert_eq!(topic.time.unwrap().timestamp(), 1600000000);
+
+        assert_eq!(state.snapshot().channels[0].modes, "+kn key");
+    }
+
+    #[test]
+    fn test_leave() {
+        let mut state = State::new("me");
+
+        apply(&mut state, ":me!u@h JOIN #a");
+        apply(&mut state, ":a!u@h JOIN #a");
+        apply(&mut state, ":b!u@h JOIN #a");
+        apply(&mut state, ":c!u@h JOIN #a");
+        apply(&mut state, ":a!u@h PART #a");
+        apply(&mut state, ":b!u@h QUIT :bye");
+        apply(&mut state, ":c!u@h NICK d");
+        apply(&mut state, ":op!u@h KICK #a d :bye");
+
+        assert_eq!(state.channel("#a").unwrap().members.len(), 1);
+        assert!(state.user("a").is_none());
+
+        apply(&mut state, ":me!u@h PART #a");
+        assert!(state.channel("#a").is_none());
+    }
+
+    #[test]
+    fn test_casemapping() {
+        let mut state = State::new("me");
+
+        apply(
+            &mut state,
+            ":server.com 005 me CASEMAPPING=rfc1459 PREFIX=(ov)@+ :are supported by this server",
+        );
+        apply(&mut state, ":Me!u@h JOIN #Rust[]");
+        apply(&mut state, ":Other!u@h JOIN #rust{}");
+        apply(&mut state, ":op!u@h MODE #RUST[] +o OTHER");
+
+        let channel = state.channel("#rust{}").unwrap();
+        assert_eq!(channel.name, "#Rust[]");
+        assert_eq!(channel.members["other"].nickname, "Other");
+        assert_eq!(channel.members["other"].prefixes, "@");
+
+        apply(&mut state, ":server.com 005 me CASEMAPPING=ascii :are supported by this server");
+        assert!(state.channel("#rust{}").is_none());
+        assert!(state.channel("#rust[]").is_some());
+        assert!(state.snapshot().isupport.contains(&"CASEMAPPING=ascii".to_owned()));
+    }
+
+    #[test]
+    fn test_mismatched_prefix() {
+        let mut state = State::new("me");
+
+        apply(&mut state, ":server.com 005 me PREFIX=(qaohv)~&@% :are supported by this server");
+        apply(&mut state, ":me!u@h JOIN #a");
+        apply(&mut state, ":op!u@h MODE #a +qv me me");
+
+        assert_eq!(state.channel("#a").unwrap().members["me"].prefixes, "~+");
+    }
+
+    #[test]
+    fn test_deltas() {
+        let mut state = State::new("me");
+        let delta = |kind, members: &[&str]| (kind, members.iter().map(|x| (*x).to_owned()).collect::<Vec<_>>());
+
+        assert_eq!(deltas(&mut state, ":me!u@h JOIN #a"), vec![delta(DeltaKind::Reset, &["me"])]);
+        assert!(deltas(&mut state, ":server.com 353 me = #a :@me +other").is_empty());
+        assert_eq!(
+            deltas(&mut state, ":server.com 366 me #a :End of /NAMES list."),
+            vec![delta(DeltaKind::Reset, &["@me", "+other"])]
+        );
+
+        assert_eq!(deltas(&mut state, ":a!u@h JOIN #a"), vec![delta(DeltaKind::Joined, &["a"])]);
+        assert_eq!(deltas(&mut state, ":op!u@h MODE #a +o a"), vec![delta(DeltaKind::Updated, &["@a"])]);
+        assert_eq!(
+            deltas(&mut state, ":a!u@h NICK b"),
+            vec![delta(DeltaKind::Left, &["@a"]), delta(DeltaKind::Joined, &["@b"])]
+        );
+        assert_eq!(deltas(&mut state, ":b!u@h AWAY :gone"), vec![delta(DeltaKind::Updated, &["@b"])]);
+        assert_eq!(deltas(&mut state, ":other!u@h QUIT :bye"), vec![delta(DeltaKind::Left, &["+other"])]);
+        assert!(deltas(&mut state, ":b!u@h PRIVMSG #a :hello").is_empty());
+
+        // channel is gone with us
+        assert_eq!(deltas(&mut state, ":me!u@h PART #a"), vec![delta(DeltaKind::Reset, &[])]);
+    }
+}
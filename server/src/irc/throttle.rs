@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::message::Message;
+use crate::config::Throttle;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum Priority {
+    // keepalive and leaving, sent even without tokens
+    High,
+    Normal,
+    // bulk JOIN like autojoin, after everything else
+    Low,
+}
+
+impl Priority {
+    fn of(message: &Message) -> Self {
+        match message.command.to_ascii_uppercase().as_ref() {
+            "PONG" | "QUIT" => Self::High,
+            // single JOIN keeps its order with messages to the channel
+            "JOIN" if message.args.first().map(|x| x.contains(',')).unwrap_or(false) => Self::Low,
+            _ => Self::Normal,
+        }
+    }
+}
+
+// token bucket refilled at `rate` lines per second up to `burst`
+pub struct Bucket {
+    burst: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(config: &Throttle, now: Instant) -> Self {
+        Self {
+            burst: config.burst as f64,
+            rate: config.rate,
+            tokens: config.burst as f64,
+            last: now,
+        }
+    }
+
+    // until a token is available, zero if there is one
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        // zero rate disables throttling
+        if self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    pub fn take(&mut self, now: Instant) {
+        self.refill(now);
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+// outbound lines by priority, FIFO within each
+#[derive(Default)]
+pub struct Queue {
+    lanes: [VecDeque<Message>; 3],
+}
+
+impl Queue {
+    pub fn push(&mut self, message: Message) {
+        let lane = Priority::of(&message) as usize;
+
+        self.lanes[lane].push_back(message);
+    }
+
+    pub fn pop(&mut self) -> Option<Message> {
+        self.lanes.iter_mut().find_map(|x| x.pop_front())
+    }
+
+    // whether the next line may skip the bucket
+    pub fn urgent(&self) -> bool {
+        !self.lanes[Priority::High as usize].is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(|x| x.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&Throttle { burst: 2, rate: 0.5 }, now);
+
+        bucket.take(now);
+        bucket.take(now);
+
+        assert_eq!(bucket.wait(now), Duration::from_secs(2));
+        assert_eq!(bucket.wait(now + Duration::from_secs(1)), Duration::from_secs(1));
+        assert_eq!(bucket.wait(now + Duration::from_secs(2)), Duration::ZERO);
+
+        // refill stops at burst
+        bucket.take(now + Duration::from_secs(100));
+        bucket.take(now + Duration::from_secs(100));
+
+        assert!(bucket.wait(now + Duration::from_secs(100)) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&Throttle { burst: 1, rate: 0.0 }, now);
+
+        bucket.take(now);
+
+        assert_eq!(bucket.wait(now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut queue = Queue::default();
+
+        queue.push(Message::new(None, "JOIN", vec!["#a,#b"]));
+        queue.push(Message::new(None, "JOIN", vec!["#c"]));
+        queue.push(Message::new(None, "PRIVMSG", vec!["#c", "hello"]));
+        queue.push(Message::new(None, "PONG", vec!["token"]));
+
+        assert!(queue.urgent());
+        assert_eq!(queue.len(), 4);
+
+        let order = std::iter::from_fn(|| queue.pop()).map(|x| x.command).collect::<Vec<_>>();
+
+        assert_eq!(order, vec!["PONG", "JOIN", "PRIVMSG", "JOIN"]);
+        assert!(queue.is_empty());
+    }
+}
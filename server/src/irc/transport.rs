@@ -1,45 +1,187 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use futures::StreamExt;
+use futures::{
+    stream::{self, BoxStream},
+    SinkExt, StreamExt,
+};
+use log::{error, warn};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Result},
+    io::{Error, ErrorKind, Result},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
-    sync::Mutex,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+    task,
+    time::sleep,
 };
-use tokio_stream::{wrappers::LinesStream, Stream};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
-use super::message::Message;
+use super::{
+    codec::{LineCodec, Overflow, MAX_LINE_LENGTH},
+    encoding::Encoding,
+    message::Message,
+    throttle::{Bucket, Queue},
+};
+use crate::config::Throttle;
+
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+// lines held back by throttle before senders have to wait
+const THROTTLE_QUEUE_SIZE: usize = 4096;
+// throttle queue depth worth a warning
+const THROTTLE_QUEUE_WARNING: usize = 64;
 
-// TODO remove clone
+// cloned handles share one connection, which is closed when all of them are dropped
 #[derive(Clone)]
 pub struct Transport {
-    read: Arc<Mutex<Option<OwnedReadHalf>>>,
-    write: Arc<Mutex<OwnedWriteHalf>>,
+    read: Arc<Mutex<FramedRead<OwnedReadHalf, LineCodec>>>,
+    write: Sender<Message>,
+    // lines waiting to be written
+    queued: Arc<AtomicUsize>,
 }
 
 impl Transport {
+    // downstream clients aren't trusted with overlong lines
     pub fn new(stream: TcpStream) -> Self {
+        Self::with_codec(stream, Encoding::default(), Overflow::Disconnect, None)
+    }
+
+    // upstream, which kills us for flooding
+    pub fn with_encoding(stream: TcpStream, encoding: Encoding, throttle: Throttle) -> Self {
+        Self::with_codec(stream, encoding, Overflow::Truncate, Some(throttle))
+    }
+
+    fn with_codec(stream: TcpStream, encoding: Encoding, overflow: Overflow, throttle: Option<Throttle>) -> Self {
         let (read, write) = stream.into_split();
+        let (sender, receiver) = channel(OUTBOUND_QUEUE_SIZE);
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let write = FramedWrite::new(write, LineCodec::new(encoding, MAX_LINE_LENGTH, overflow));
+        match throttle {
+            Some(throttle) => task::spawn(Self::throttled_write_loop(write, receiver, throttle, queued.clone())),
+            None => task::spawn(Self::write_loop(write, receiver, queued.clone())),
+        };
 
         Self {
-            read: Arc::new(Mutex::new(Option::Some(read))),
-            write: Arc::new(Mutex::new(write)),
+            read: Arc::new(Mutex::new(FramedRead::new(read, LineCodec::new(encoding, MAX_LINE_LENGTH, overflow)))),
+            write: sender,
+            queued,
         }
     }
 
-    pub async fn stream(&self) -> impl Stream<Item = Message> + '_ {
-        let read = self.read.lock().await.take().unwrap();
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    // ends on close or read error, concurrent streams share incoming lines
+    pub fn stream(&self) -> BoxStream<'_, Message> {
+        stream::unfold(&self.read, |read| async move {
+            match read.lock().await.next().await {
+                Some(Ok(message)) => Some((message, read)),
+                Some(Err(err)) => {
+                    error!("Read error: {}", err);
 
-        LinesStream::new(BufReader::new(read).lines()).map(move |x| Message::from_raw(x.unwrap()))
+                    None
+                }
+                None => None,
+            }
+        })
+        .boxed()
     }
 
+    // waits while outbound queue is full, fails once connection is closed
     pub async fn send_message(&self, message: &Message) -> Result<()> {
-        let mut write = self.write.lock().await;
-        write.write(message.raw().as_bytes()).await?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+
+        self.write.send(message.clone()).await.map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+
+            Error::new(ErrorKind::BrokenPipe, "Connection closed")
+        })
+    }
+
+    async fn write_loop(mut write: FramedWrite<OwnedWriteHalf, LineCodec>, mut receiver: Receiver<Message>, queued: Arc<AtomicUsize>) {
+        while let Some(message) = receiver.recv().await {
+            // flush once per burst of queued messages
+            let mut result = write.feed(message).await;
+            queued.fetch_sub(1, Ordering::Relaxed);
+
+            while let (Ok(_), Ok(message)) = (&result, receiver.try_recv()) {
+                result = write.feed(message).await;
+                queued.fetch_sub(1, Ordering::Relaxed);
+            }
+
+            if let Err(err) = result.and(write.flush().await) {
+                error!("Write error: {}", err);
+
+                break;
+            }
+        }
+
+        // no more messages, shut down write half cleanly
+        let _ = write.close().await;
+    }
+
+    // lines are taken off the channel right away, so that PONG doesn't wait behind a paste
+    async fn throttled_write_loop(
+        mut write: FramedWrite<OwnedWriteHalf, LineCodec>,
+        mut receiver: Receiver<Message>,
+        throttle: Throttle,
+        queued: Arc<AtomicUsize>,
+    ) {
+        let mut queue = Queue::default();
+        let mut bucket = Bucket::new(&throttle, Instant::now());
+        let mut closed = false;
+
+        loop {
+            let wait = if queue.urgent() {
+                Some(Duration::ZERO)
+            } else if queue.is_empty() {
+                None
+            } else {
+                Some(bucket.wait(Instant::now()))
+            };
+
+            // drained after all handles are gone
+            if closed && wait.is_none() {
+                break;
+            }
+
+            tokio::select! {
+                message = receiver.recv(), if !closed && queue.len() < THROTTLE_QUEUE_SIZE => match message {
+                    Some(message) => {
+                        queue.push(message);
+
+                        if queue.len() == THROTTLE_QUEUE_WARNING {
+                            warn!("{} lines are waiting to be sent upstream", queue.len());
+                        }
+                    }
+                    None => closed = true,
+                },
+                _ = sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                    if let Some(message) = queue.pop() {
+                        bucket.take(Instant::now());
+                        queued.fetch_sub(1, Ordering::Relaxed);
+
+                        if let Err(err) = write.send(message).await {
+                            error!("Write error: {}", err);
+
+                            break;
+                        }
+                    }
+                }
+            }
+        }
 
-        Ok(())
+        let _ = write.close().await;
     }
 }
@@ -1,9 +1,12 @@
 mod bouncer;
+mod config;
+mod control;
 mod grpc;
 mod history;
 mod irc;
 mod message;
 mod sink;
+mod slack;
 mod source;
 
 use std::error::Error;
@@ -11,6 +14,7 @@ use std::error::Error;
 use clap::{App, Arg};
 
 use bouncer::Bouncer;
+use config::{Config, Network};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -20,16 +24,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let matches = App::new("bouncer")
         .version("1.0")
-        .arg(Arg::with_name("host").required(true))
-        .arg(Arg::with_name("port").required(true))
-        .arg(Arg::with_name("server_port").required(true))
+        .arg(Arg::with_name("config").long("config").takes_value(true))
+        .arg(Arg::with_name("host").required_unless("config"))
+        .arg(Arg::with_name("port").required_unless("config"))
+        .arg(Arg::with_name("server_port").required_unless("config"))
         .get_matches();
 
-    let host = matches.value_of("host").unwrap().to_owned();
-    let port = matches.value_of("port").unwrap().parse::<u16>().unwrap();
-    let server_port = matches.value_of("server_port").unwrap().parse::<u16>().unwrap();
+    let config_path = matches.value_of("config").map(|x| x.to_owned());
+    let config = if let Some(config_path) = &config_path {
+        Config::load(config_path)?
+    } else {
+        let host = matches.value_of("host").unwrap().to_owned();
+        let port = matches.value_of("port").unwrap().parse::<u16>()?;
+        let server_port = matches.value_of("server_port").unwrap().parse::<u16>()?;
 
-    Bouncer::run(host, port, server_port).await?;
+        let mut config = Config::new(server_port);
+        config.networks.push(Network::new(host.clone(), host, port));
+
+        config
+    };
+
+    Bouncer::run(config, config_path).await?;
 
     Ok(())
 }
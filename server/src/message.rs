@@ -1,13 +1,211 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum Message {
     // Both directions
-    Chat { sender: String, channel: String, content: String },
+    Chat {
+        sender: String,
+        channel: String,
+        content: String,
+    },
+    Action {
+        sender: String,
+        channel: String,
+        content: String,
+    },
+    // query with peer, which is the other side whoever sent it
+    PrivateChat {
+        sender: String,
+        peer: String,
+        content: String,
+    },
+    PrivateAction {
+        sender: String,
+        peer: String,
+        content: String,
+    },
+    Notice {
+        sender: String,
+        target: String,
+        content: String,
+    },
+    Topic {
+        sender: String,
+        channel: String,
+        topic: String,
+    },
+    Mode {
+        sender: String,
+        target: String,
+        modes: Vec<String>,
+    },
+    Kick {
+        sender: String,
+        channel: String,
+        user: String,
+        reason: Option<String>,
+    },
+    Invite {
+        sender: String,
+        user: String,
+        channel: String,
+    },
+    Away {
+        sender: String,
+        message: Option<String>,
+    },
     // Source to Sink
-    JoinedChannel { sender: String, channel: String },
-    UsersList { channel: String, users: Vec<String> },
+    JoinedChannel {
+        sender: String,
+        channel: String,
+    },
+    PartedChannel {
+        sender: String,
+        channel: String,
+        reason: Option<String>,
+    },
+    Quit {
+        sender: String,
+        reason: Option<String>,
+    },
+    NickChanged {
+        sender: String,
+        nickname: String,
+    },
+    UsersList {
+        channel: String,
+        users: Vec<String>,
+    },
+    Error {
+        message: String,
+    },
+    Numeric {
+        sender: String,
+        code: String,
+        args: Vec<String>,
+    },
+    // Both directions, commands relayed as is
+    Raw {
+        sender: String,
+        command: String,
+        args: Vec<String>,
+    },
     // Sink to Source
-    JoinChannel { channel: String },
+    JoinChannel {
+        channel: String,
+        key: Option<String>,
+    },
+    PartChannel {
+        channel: String,
+        reason: Option<String>,
+    },
+    ChangeNick {
+        nickname: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    Incoming,
+    // sent by user
+    Outgoing,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum Origin {
+    // session id of irc server
+    Irc(u32),
+    // call id of grpc server
+    Grpc(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Envelope {
+    // empty for default network
+    pub network: String,
+    pub time: DateTime<Utc>,
+    pub id: String,
+    pub direction: Direction,
+    // downstream connection the message came from, or the only one the reply goes to
+    pub origin: Option<Origin>,
+    // last reply to the request of origin
+    pub reply_end: bool,
+    pub message: Message,
+    // member changes the message made to source state
+    #[serde(skip)]
+    pub deltas: Vec<MembershipDelta>,
+}
+
+impl Envelope {
+    pub fn new(network: &str, direction: Direction, message: Message) -> Self {
+        Self {
+            network: network.into(),
+            time: Utc::now(),
+            id: Self::generate_id(),
+            direction,
+            origin: None,
+            reply_end: false,
+            message,
+            deltas: Vec::new(),
+        }
+    }
+
+    pub fn generate_id() -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
+}
+
+#[derive(Clone)]
+pub struct TopicSnapshot {
+    pub text: String,
+    pub setter: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct MemberSnapshot {
+    pub nickname: String,
+    pub prefixes: String,
+    pub hostmask: Option<String>,
+    pub away: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ChannelSnapshot {
+    pub name: String,
+    pub topic: Option<TopicSnapshot>,
+    pub modes: String,
+    pub members: Vec<MemberSnapshot>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum DeltaKind {
+    // members listed are all of the channel
+    Reset,
+    Joined,
+    Left,
+    Updated,
+}
+
+#[derive(Clone, Debug)]
+pub struct MembershipDelta {
+    pub channel: String,
+    pub kind: DeltaKind,
+    pub members: Vec<MemberSnapshot>,
+}
+
+// current state of source, for newly attached sinks
+#[derive(Clone, Default)]
+pub struct Snapshot {
+    pub network: String,
+    // current nickname on upstream
+    pub nickname: String,
+    // RPL_ISUPPORT tokens of upstream
+    pub isupport: Vec<String>,
+    pub channels: Vec<ChannelSnapshot>,
+    // lines waiting to be sent to origin
+    pub queued: usize,
 }
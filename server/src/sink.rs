@@ -4,10 +4,24 @@ use async_trait::async_trait;
 use futures::stream::BoxStream;
 use tokio::io::Result;
 
-use crate::message::Message;
+use crate::message::Envelope;
+
+#[derive(Clone)]
+pub struct Session {
+    pub id: u32,
+    pub address: String,
+}
 
 #[async_trait]
 pub trait Sink: Sync + Send {
-    fn stream(&self) -> BoxStream<Message>;
-    async fn broadcast(&self, message: &Message) -> Result<()>;
+    fn stream(&self) -> BoxStream<'_, Envelope>;
+    async fn broadcast(&self, envelope: &Envelope) -> Result<()>;
+
+    async fn sessions(&self) -> Vec<Session> {
+        Vec::new()
+    }
+
+    async fn kick(&self, _id: u32, _reason: &str) -> bool {
+        false
+    }
 }
@@ -0,0 +1,113 @@
+use reqwest::Client as HttpClient;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tokio::io::{Error, ErrorKind, Result};
+
+// items of paginated methods requested at once
+const PAGE_SIZE: &str = "200";
+
+#[derive(Deserialize)]
+pub struct Auth {
+    pub user_id: String,
+    pub user: String,
+}
+
+// socket mode connection to open
+#[derive(Deserialize)]
+pub struct Connection {
+    pub url: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Topic {
+    pub value: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Conversation {
+    pub id: String,
+    // absent for direct messages
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_im: bool,
+    #[serde(default)]
+    pub is_member: bool,
+    // the other side of direct message
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub topic: Option<Topic>,
+}
+
+// slack web api authenticated with one token
+pub struct Api {
+    http: HttpClient,
+    url: String,
+    token: String,
+}
+
+impl Api {
+    pub fn new(url: &str, token: &str) -> Self {
+        Self {
+            http: HttpClient::new(),
+            url: url.trim_end_matches('/').into(),
+            token: token.into(),
+        }
+    }
+
+    // arguments are sent as form, which every method accepts
+    pub async fn call<T: DeserializeOwned>(&self, method: &str, args: &[(&str, &str)]) -> Result<T> {
+        let response = self
+            .http
+            .post(format!("{}/{}", self.url, method))
+            .bearer_auth(&self.token)
+            .form(args)
+            .send()
+            .await
+            .map_err(|x| Error::new(ErrorKind::ConnectionRefused, x))?;
+        let body = response.json::<Value>().await.map_err(|x| Error::new(ErrorKind::InvalidData, x))?;
+
+        if body["ok"].as_bool() != Some(true) {
+            let error = body["error"].as_str().unwrap_or("unknown_error");
+
+            return Err(Error::other(format!("{} failed: {}", method, error)));
+        }
+
+        serde_json::from_value(body).map_err(|x| Error::new(ErrorKind::InvalidData, x))
+    }
+
+    // items under `key` of every page
+    pub async fn list<T: DeserializeOwned>(&self, method: &str, key: &str, args: &[(&str, &str)]) -> Result<Vec<T>> {
+        let mut result = Vec::new();
+        let mut cursor = String::new();
+
+        loop {
+            let mut page = {
+                let mut args = args.to_vec();
+                args.push(("limit", PAGE_SIZE));
+                if !cursor.is_empty() {
+                    args.push(("cursor", &cursor));
+                }
+
+                self.call::<Value>(method, &args).await?
+            };
+
+            let items = serde_json::from_value::<Vec<T>>(page[key].take()).map_err(|x| Error::new(ErrorKind::InvalidData, x))?;
+            result.extend(items);
+
+            cursor = page["response_metadata"]["next_cursor"].as_str().unwrap_or_default().into();
+            if cursor.is_empty() {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+}
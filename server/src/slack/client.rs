@@ -0,0 +1,604 @@
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, SinkExt, StreamExt,
+};
+use log::{debug, error, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{Error, ErrorKind, Result},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream};
+
+use super::{
+    api::{self, Api, Auth, Connection, User},
+    state::{Conversation, State},
+    text,
+};
+use crate::config::Slack;
+use crate::irc::format;
+use crate::message::{Direction, Envelope, Message, Origin, Snapshot};
+use crate::source::Source;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// conversations mapped to irc-like channels, from socket mode
+const CONVERSATION_TYPES: &str = "public_channel,private_channel,mpim,im";
+// characters of parent message quoted in thread replies and reactions
+const QUOTE_LENGTH: usize = 30;
+
+#[derive(Deserialize)]
+struct Opened {
+    channel: api::Conversation,
+}
+
+pub struct Client {
+    network: String,
+    // bot token for web api, app token for socket mode
+    api: Api,
+    app: Api,
+    socket: Mutex<Socket>,
+    state: Mutex<State>,
+}
+
+impl Client {
+    pub async fn new(network: &str, slack: &Slack) -> Result<Self> {
+        let api = Api::new(&slack.api_url, &slack.bot_token);
+        let app = Api::new(&slack.api_url, &slack.app_token);
+
+        let auth = api.call::<Auth>("auth.test", &[]).await?;
+        let mut state = State::new(auth.user_id);
+        debug!("Logged in to slack as {}", auth.user);
+
+        state.set_users(api.list::<User>("users.list", "members", &[]).await?);
+
+        let conversations = api
+            .list::<api::Conversation>(
+                "conversations.list",
+                "channels",
+                &[("types", CONVERSATION_TYPES), ("exclude_archived", "true")],
+            )
+            .await?;
+        for conversation in conversations {
+            let mut conversation = Conversation::from(conversation);
+            if conversation.is_member {
+                conversation.members = api
+                    .list::<String>("conversations.members", "members", &[("channel", &conversation.id)])
+                    .await?
+                    .into_iter()
+                    .collect();
+            }
+
+            state.set_conversation(conversation);
+        }
+
+        let socket = Self::connect(&app).await?;
+
+        Ok(Self {
+            network: network.into(),
+            api,
+            app,
+            socket: Mutex::new(socket),
+            state: Mutex::new(state),
+        })
+    }
+
+    async fn connect(app: &Api) -> Result<Socket> {
+        let connection = app.call::<Connection>("apps.connections.open", &[]).await?;
+        let (socket, _) = connect_async(&connection.url)
+            .await
+            .map_err(|x| Error::new(ErrorKind::ConnectionRefused, x))?;
+
+        Ok(socket)
+    }
+
+    // next event from socket mode, acknowledged and reconnected as slack asks
+    async fn next_event(&self) -> Option<Value> {
+        let mut socket = self.socket.lock().await;
+
+        loop {
+            let text = match socket.next().await? {
+                Ok(WsMessage::Text(text)) => text,
+                Ok(WsMessage::Close(_)) => return None,
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("Slack socket error: {}", err);
+
+                    return None;
+                }
+            };
+
+            let mut envelope = match serde_json::from_str::<Value>(&text) {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    warn!("Invalid slack envelope: {}", err);
+
+                    continue;
+                }
+            };
+
+            // unacknowledged envelopes are sent again
+            if let Some(id) = envelope["envelope_id"].as_str() {
+                let ack = json!({ "envelope_id": id }).to_string();
+                if let Err(err) = socket.send(WsMessage::Text(ack)).await {
+                    error!("Slack socket error: {}", err);
+
+                    return None;
+                }
+            }
+
+            match envelope["type"].as_str() {
+                Some("events_api") => return Some(envelope["payload"]["event"].take()),
+                // sent before slack closes the connection, e.g. to refresh it
+                Some("disconnect") => match Self::connect(&self.app).await {
+                    Ok(new) => *socket = new,
+                    Err(err) => {
+                        error!("Slack reconnect failed: {}", err);
+
+                        return None;
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    async fn handle_event(&self, event: &Value) -> Vec<(Message, Direction)> {
+        let mut state = self.state.lock().await;
+        let string = |x: &Value| x.as_str().unwrap_or_default().to_owned();
+
+        let user = string(&event["user"]);
+        let direction = if user == state.user_id {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        };
+        let sender = state.user_name(&user).to_owned();
+
+        let messages = match event["type"].as_str().unwrap_or_default() {
+            "message" => Self::convert_message(&mut state, event).into_iter().collect(),
+            "reaction_added" | "reaction_removed" => {
+                let item = &event["item"];
+                let (channel, ts) = (string(&item["channel"]), string(&item["ts"]));
+
+                let verb = if event["type"] == "reaction_added" {
+                    "reacted"
+                } else {
+                    "removed reaction"
+                };
+                let quote = state
+                    .recall(&channel, &ts)
+                    .map(|x| format!(" to \"{}\"", Self::quote(x)))
+                    .unwrap_or_default();
+                let content = format!("{} :{}:{}", verb, string(&event["reaction"]), quote);
+
+                state.chat(&channel, &sender, content, true).into_iter().collect()
+            }
+            "member_joined_channel" => {
+                let channel = string(&event["channel"]);
+                state.joined(&channel, &user);
+
+                if user == state.user_id {
+                    drop(state);
+
+                    return self.handle_joined(&channel).await;
+                }
+
+                let channel = state.channel_name(&channel);
+                channel.map(|channel| Message::JoinedChannel { sender, channel }).into_iter().collect()
+            }
+            "member_left_channel" => {
+                let channel = string(&event["channel"]);
+                state.parted(&channel, &user);
+
+                let channel = state.channel_name(&channel);
+                channel
+                    .map(|channel| Message::PartedChannel {
+                        sender,
+                        channel,
+                        reason: None,
+                    })
+                    .into_iter()
+                    .collect()
+            }
+            "user_change" | "team_join" => {
+                let user = match serde_json::from_value::<User>(event["user"].clone()) {
+                    Ok(user) => user,
+                    Err(_) => return Vec::new(),
+                };
+                let nickname = user.name.clone();
+
+                state
+                    .set_user(user)
+                    .map(|sender| Message::NickChanged { sender, nickname })
+                    .into_iter()
+                    .collect()
+            }
+            "channel_created" | "channel_rename" | "group_rename" => {
+                let channel = &event["channel"];
+                let id = string(&channel["id"]);
+
+                match state.conversation(&id) {
+                    Some(_) => state.rename(&id, channel["name"].as_str().unwrap_or_default()),
+                    None => state.set_conversation(Conversation::from(api::Conversation {
+                        id,
+                        name: string(&channel["name"]),
+                        is_im: false,
+                        is_member: false,
+                        user: None,
+                        topic: None,
+                    })),
+                }
+
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+
+        messages.into_iter().map(|x| (x, direction)).collect()
+    }
+
+    // join, topic and names like irc server sends, members are fetched as the event doesn't come with them
+    async fn handle_joined(&self, channel: &str) -> Vec<(Message, Direction)> {
+        match self.api.list::<String>("conversations.members", "members", &[("channel", channel)]).await {
+            Ok(members) => {
+                let mut state = self.state.lock().await;
+                members.iter().for_each(|x| state.joined(channel, x));
+            }
+            Err(err) => error!("Fetching members of {} failed: {}", channel, err),
+        }
+
+        let state = self.state.lock().await;
+        state.join_messages(channel).into_iter().map(|x| (x, Direction::Outgoing)).collect()
+    }
+
+    fn convert_message(state: &mut State, event: &Value) -> Option<Message> {
+        let string = |x: &Value| x.as_str().unwrap_or_default().to_owned();
+        let channel = string(&event["channel"]);
+
+        // bots without user post with their own name
+        let sender = |message: &Value| match message["user"].as_str() {
+            Some(user) => state.user_name(user).to_owned(),
+            None => message["username"].as_str().unwrap_or("slack").to_owned(),
+        };
+
+        match event["subtype"].as_str() {
+            None | Some("me_message") | Some("thread_broadcast") | Some("bot_message") | Some("file_share") => {
+                let sender = sender(event);
+                let ts = string(&event["ts"]);
+                let mut content = text::from_slack(event["text"].as_str().unwrap_or_default(), state);
+
+                for file in event["files"].as_array().into_iter().flatten() {
+                    let link = file["permalink"].as_str().or_else(|| file["url_private"].as_str()).unwrap_or_default();
+                    content = format!("{} {}", content, link).trim().to_owned();
+                }
+
+                // replies, not parents, carry thread_ts other than their own ts
+                let thread = event["thread_ts"].as_str().filter(|x| *x != ts);
+                if let Some(thread) = thread {
+                    let quote = state
+                        .recall(&channel, thread)
+                        .map(|x| format!(": {}", Self::quote(x)))
+                        .unwrap_or_default();
+                    content = format!("[thread{}] {}", quote, content);
+                }
+
+                state.remember(&channel, &ts, &Self::plain(&content));
+                state.chat(&channel, &sender, content, event["subtype"] == "me_message")
+            }
+            Some("message_changed") => {
+                let (message, previous) = (&event["message"], &event["previous_message"]);
+                // unfurling links changes message without its text
+                if message["text"] == previous["text"] {
+                    return None;
+                }
+
+                let sender = sender(message);
+                let content = text::from_slack(message["text"].as_str().unwrap_or_default(), state);
+                state.remember(&channel, message["ts"].as_str().unwrap_or_default(), &Self::plain(&content));
+
+                state.chat(&channel, &sender, format!("[edited] {}", content), false)
+            }
+            Some("channel_topic") | Some("group_topic") => {
+                let sender = sender(event);
+                let topic = text::from_slack(event["topic"].as_str().unwrap_or_default(), state);
+                state.set_topic(&channel, &topic);
+
+                Some(Message::Topic {
+                    sender,
+                    channel: state.channel_name(&channel)?,
+                    topic,
+                })
+            }
+            // joins and parts come as their own events
+            _ => None,
+        }
+    }
+
+    fn plain(content: &str) -> String {
+        format::to_plain(&format::parse(content))
+    }
+
+    fn quote(text: &str) -> String {
+        match text.char_indices().nth(QUOTE_LENGTH) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.into(),
+        }
+    }
+
+    fn envelope(&self, event: &Value, message: Message, direction: Direction) -> Envelope {
+        let mut envelope = Envelope::new(&self.network, direction, message);
+
+        // `1634567890.000200`
+        let ts = event["event_ts"]
+            .as_str()
+            .or_else(|| event["ts"].as_str())
+            .and_then(|x| x.parse::<f64>().ok());
+        if let Some(time) = ts.and_then(|x| Utc.timestamp_millis_opt((x * 1000.0) as i64).single()) {
+            envelope.time = time;
+        }
+
+        envelope
+    }
+
+    // conversation id of channel or user, direct message is opened if needed
+    async fn conversation_id(&self, target: &str) -> Result<String> {
+        let (id, user_id) = {
+            let state = self.state.lock().await;
+
+            match state.channel_id(target) {
+                Some(id) => (Some(id.to_owned()), None),
+                None => {
+                    let user_id = state
+                        .user_id(target)
+                        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No such channel or user {}", target)))?;
+
+                    (state.direct_id(user_id).map(|x| x.to_owned()), Some(user_id.to_owned()))
+                }
+            }
+        };
+
+        match (id, user_id) {
+            (Some(id), _) => Ok(id),
+            (None, Some(user_id)) => {
+                let opened = self.api.call::<Opened>("conversations.open", &[("users", &user_id)]).await?;
+                let id = opened.channel.id.clone();
+
+                let mut conversation = Conversation::from(opened.channel);
+                conversation.peer = Some(user_id);
+                self.state.lock().await.set_conversation(conversation);
+
+                Ok(id)
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    async fn post(&self, method: &str, target: &str, content: &str) -> Result<()> {
+        let channel = self.conversation_id(target).await?;
+        let text = text::to_slack(content, &*self.state.lock().await);
+
+        self.api.call::<Value>(method, &[("channel", &channel), ("text", &text)]).await?;
+
+        Ok(())
+    }
+
+    async fn call_channel(&self, method: &str, channel: &str, args: &[(&str, &str)]) -> Result<()> {
+        let id = self.conversation_id(channel).await?;
+        let args = [&[("channel", id.as_str())], args].concat();
+
+        self.api.call::<Value>(method, &args).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Source for Client {
+    async fn stream<'a>(&'a self) -> BoxStream<'a, Envelope> {
+        // channels we're in, as if joined after registration
+        let joined = {
+            let state = self.state.lock().await;
+
+            state
+                .joined_ids()
+                .iter()
+                .flat_map(|x| state.join_messages(x))
+                .map(|x| {
+                    let mut envelope = Envelope::new(&self.network, Direction::Outgoing, x);
+                    envelope.deltas = state.deltas(&envelope.message);
+
+                    envelope
+                })
+                .collect::<Vec<_>>()
+        };
+        let joined = stream::iter(joined);
+
+        let events = stream::unfold((), move |_| self.next_event().map(|x| x.map(|x| (x, ())))).flat_map(move |event| {
+            async move {
+                let messages = self.handle_event(&event).await;
+                let state = self.state.lock().await;
+
+                stream::iter(
+                    messages
+                        .into_iter()
+                        .map(|(message, direction)| {
+                            let mut envelope = self.envelope(&event, message, direction);
+                            envelope.deltas = state.deltas(&envelope.message);
+
+                            envelope
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+            .flatten_stream()
+        });
+
+        joined.chain(events).boxed()
+    }
+
+    // slack echoes messages as events, so there's no local echo
+    async fn send_message(&self, message: &Message, _: Option<Origin>) -> Result<Option<Envelope>> {
+        let result = match message {
+            Message::Chat { channel, content, .. } => self.post("chat.postMessage", channel, content).await,
+            Message::Action { channel, content, .. } => self.post("chat.meMessage", channel, content).await,
+            Message::PrivateChat { peer, content, .. } => self.post("chat.postMessage", peer, content).await,
+            Message::PrivateAction { peer, content, .. } => self.post("chat.meMessage", peer, content).await,
+            Message::Topic { channel, topic, .. } => self.call_channel("conversations.setTopic", channel, &[("topic", topic)]).await,
+            Message::JoinChannel { channel, .. } => self.call_channel("conversations.join", channel, &[]).await,
+            Message::PartChannel { channel, .. } => self.call_channel("conversations.leave", channel, &[]).await,
+            _ => {
+                error!("Message can't be sent to slack");
+
+                return Ok(None);
+            }
+        };
+
+        // failed call is told to clients rather than dropping the network
+        Ok(result.err().map(|err| {
+            error!("Slack call failed: {}", err);
+
+            Envelope::new(&self.network, Direction::Incoming, Message::Error { message: err.to_string() })
+        }))
+    }
+
+    async fn snapshot(&self) -> Snapshot {
+        self.state.lock().await.snapshot(&self.network)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::slack::mock::Mock;
+
+    async fn next(stream: &mut BoxStream<'_, Envelope>) -> Message {
+        timeout(Duration::from_secs(5), stream.next()).await.unwrap().unwrap().message
+    }
+
+    fn chat(message: Message) -> (String, String, String) {
+        match message {
+            Message::Chat { sender, channel, content } | Message::Action { sender, channel, content } => (sender, channel, content),
+            Message::PrivateChat { sender, peer, content } | Message::PrivateAction { sender, peer, content } => (sender, peer, content),
+            _ => panic!("Not a chat"),
+        }
+    }
+
+    fn owned(x: (&str, &str, &str)) -> (String, String, String) {
+        (x.0.into(), x.1.into(), x.2.into())
+    }
+
+    fn form(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(x, y)| (x.to_string(), y.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_slack() {
+        let mock = Mock::start().await;
+        let client = Client::new("work", &mock.config()).await.unwrap();
+        let mut stream = client.stream().await;
+
+        // conversations.list is paged, and we're in #general only
+        match next(&mut stream).await {
+            Message::JoinedChannel { sender, channel } => assert_eq!((sender.as_str(), channel.as_str()), ("bouncer", "#general")),
+            _ => panic!("Not a join"),
+        }
+        assert!(matches!(next(&mut stream).await, Message::Topic { topic, .. } if topic == "Welcome"));
+        assert!(matches!(next(&mut stream).await, Message::UsersList { users, .. } if users == vec!["bouncer", "alice"]));
+
+        mock.event(json!({ "type": "message", "channel": "C1", "user": "U1", "text": "*hi* <@U0>", "ts": "1.0" }))
+            .await;
+        assert_eq!(chat(next(&mut stream).await), owned(("alice", "#general", "\x02hi\x0f @bouncer")));
+
+        mock.event(json!({ "type": "message", "channel": "C1", "user": "U2", "text": "reply", "ts": "2.0", "thread_ts": "1.0" }))
+            .await;
+        assert_eq!(chat(next(&mut stream).await), owned(("bob", "#general", "[thread: hi @bouncer] reply")));
+
+        mock.event(json!({
+            "type": "message",
+            "subtype": "message_changed",
+            "channel": "C1",
+            "message": { "user": "U1", "text": "hello", "ts": "1.0" },
+            "previous_message": { "user": "U1", "text": "*hi* <@U0>", "ts": "1.0" },
+        }))
+        .await;
+        assert_eq!(chat(next(&mut stream).await), owned(("alice", "#general", "[edited] hello")));
+
+        mock.event(
+            json!({ "type": "reaction_added", "user": "U2", "reaction": "tada", "item": { "type": "message", "channel": "C1", "ts": "1.0" } }),
+        )
+        .await;
+        assert_eq!(chat(next(&mut stream).await), owned(("bob", "#general", "reacted :tada: to \"hello\"")));
+
+        // everything was acknowledged
+        assert_eq!(mock.acks(4).await, 4);
+
+        // slack refreshes socket mode connection now and then
+        mock.disconnect().await;
+
+        mock.event(json!({ "type": "message", "channel": "D1", "user": "U1", "text": "psst", "ts": "3.0" }))
+            .await;
+        match next(&mut stream).await {
+            Message::PrivateChat { sender, peer, content } => {
+                assert_eq!((sender.as_str(), peer.as_str(), content.as_str()), ("alice", "alice", "psst"))
+            }
+            _ => panic!("Not a private chat"),
+        }
+
+        assert_eq!(mock.acks(5).await, 5);
+
+        client
+            .send_message(
+                &Message::Chat {
+                    sender: String::new(),
+                    channel: "#general".into(),
+                    content: "\x02hey\x02 @alice".into(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(mock.call("chat.postMessage").await, form(&[("channel", "C1"), ("text", "*hey* <@U1>")]));
+
+        // direct message is opened first
+        client
+            .send_message(
+                &Message::PrivateChat {
+                    sender: String::new(),
+                    peer: "bob".into(),
+                    content: "hi".into(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(mock.call("conversations.open").await, form(&[("users", "U2")]));
+        assert_eq!(mock.call("chat.postMessage").await, form(&[("channel", "D2"), ("text", "hi")]));
+
+        // errors are told to clients
+        let error = client
+            .send_message(
+                &Message::Chat {
+                    sender: String::new(),
+                    channel: "#nowhere".into(),
+                    content: "hi".into(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(error.map(|x| x.message), Some(Message::Error { .. })));
+
+        let snapshot = client.snapshot().await;
+        assert_eq!(snapshot.nickname, "bouncer");
+        assert_eq!(snapshot.channels.len(), 1);
+    }
+}
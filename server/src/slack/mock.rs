@@ -0,0 +1,194 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    header::AUTHORIZATION,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::{mpsc, Mutex},
+    task::spawn,
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
+
+use crate::config::Slack;
+
+const APP_TOKEN: &str = "xapp-test";
+const BOT_TOKEN: &str = "xoxb-test";
+
+type Calls = Arc<Mutex<Vec<(String, Vec<(String, String)>)>>>;
+
+// slack web api and socket mode server of a small workspace
+pub struct Mock {
+    api_url: String,
+    // method and form of each call
+    calls: Calls,
+    events: mpsc::Sender<Value>,
+    acks: Arc<AtomicUsize>,
+}
+
+impl Mock {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_url = format!("ws://{}/", listener.local_addr().unwrap());
+        let (events, receiver) = mpsc::channel(16);
+        let acks = Arc::new(AtomicUsize::new(0));
+        spawn(Self::socket_loop(listener, receiver, acks.clone()));
+
+        let calls = Calls::default();
+        let service = {
+            let calls = calls.clone();
+
+            make_service_fn(move |_| {
+                let (calls, socket_url) = (calls.clone(), socket_url.clone());
+
+                async move { Ok::<_, Infallible>(service_fn(move |request| Self::handle(request, calls.clone(), socket_url.clone()))) }
+            })
+        };
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let api_url = format!("http://{}/api/", server.local_addr());
+        spawn(server);
+
+        Self {
+            api_url,
+            calls,
+            events,
+            acks,
+        }
+    }
+
+    pub fn config(&self) -> Slack {
+        Slack {
+            app_token: APP_TOKEN.into(),
+            bot_token: BOT_TOKEN.into(),
+            api_url: self.api_url.clone(),
+        }
+    }
+
+    // sent as events api envelope
+    pub async fn event(&self, event: Value) {
+        self.events.send(event).await.unwrap();
+    }
+
+    // closes socket mode connection after asking client to reconnect
+    pub async fn disconnect(&self) {
+        self.events
+            .send(json!({ "type": "disconnect", "reason": "refresh_requested" }))
+            .await
+            .unwrap();
+    }
+
+    // envelopes acknowledged, waiting a while for expected ones
+    pub async fn acks(&self, expected: usize) -> usize {
+        let _ = timeout(Duration::from_secs(5), async {
+            while self.acks.load(Ordering::SeqCst) < expected {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+
+        self.acks.load(Ordering::SeqCst)
+    }
+
+    // form of the oldest call to method not taken yet
+    pub async fn call(&self, method: &str) -> Vec<(String, String)> {
+        let mut calls = self.calls.lock().await;
+        let index = calls.iter().position(|(x, _)| x == method).unwrap();
+
+        calls.remove(index).1
+    }
+
+    async fn handle(request: Request<Body>, calls: Calls, socket_url: String) -> Result<Response<Body>, Infallible> {
+        let method = request.uri().path().trim_start_matches("/api/").to_owned();
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        let form = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap();
+
+        let expected = if method == "apps.connections.open" { APP_TOKEN } else { BOT_TOKEN };
+        let cursor = form.iter().any(|(x, _)| x == "cursor");
+
+        let response = match method.as_str() {
+            _ if token != format!("Bearer {}", expected) => json!({ "ok": false, "error": "invalid_auth" }),
+            "auth.test" => json!({ "ok": true, "user_id": "U0", "user": "bouncer" }),
+            "apps.connections.open" => json!({ "ok": true, "url": socket_url }),
+            "users.list" => json!({
+                "ok": true,
+                "members": [{ "id": "U0", "name": "bouncer" }, { "id": "U1", "name": "alice" }, { "id": "U2", "name": "bob" }],
+            }),
+            "conversations.list" if !cursor => json!({
+                "ok": true,
+                "channels": [{ "id": "C1", "name": "general", "is_member": true, "topic": { "value": "Welcome" } }],
+                "response_metadata": { "next_cursor": "2" },
+            }),
+            "conversations.list" => json!({
+                "ok": true,
+                "channels": [{ "id": "C2", "name": "random", "is_member": false }, { "id": "D1", "is_im": true, "user": "U1" }],
+                "response_metadata": { "next_cursor": "" },
+            }),
+            "conversations.members" => json!({ "ok": true, "members": ["U0", "U1"] }),
+            "conversations.open" => json!({ "ok": true, "channel": { "id": "D2" } }),
+            "chat.postMessage" | "chat.meMessage" | "conversations.join" | "conversations.leave" | "conversations.setTopic" => json!({ "ok": true }),
+            _ => json!({ "ok": false, "error": "unknown_method" }),
+        };
+        calls.lock().await.push((method, form));
+
+        Ok(Response::new(Body::from(response.to_string())))
+    }
+
+    // one connection at a time, events wait for the next connection after disconnect
+    async fn socket_loop(listener: TcpListener, mut events: mpsc::Receiver<Value>, acks: Arc<AtomicUsize>) {
+        let mut envelope_id = 0;
+
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut socket = accept_async(stream).await.unwrap();
+            socket.send(WsMessage::Text(json!({ "type": "hello" }).to_string())).await.unwrap();
+
+            loop {
+                select! {
+                    event = events.recv() => {
+                        let event = match event {
+                            Some(event) if event["type"] == "disconnect" => {
+                                let _ = socket.send(WsMessage::Text(event.to_string())).await;
+
+                                break;
+                            }
+                            Some(event) => event,
+                            None => return,
+                        };
+
+                        envelope_id += 1;
+                        let envelope = json!({ "envelope_id": envelope_id.to_string(), "type": "events_api", "payload": { "event": event } });
+                        socket.send(WsMessage::Text(envelope.to_string())).await.unwrap();
+                    }
+                    message = socket.next() => match message {
+                        Some(Ok(WsMessage::Text(text))) => {
+                            if serde_json::from_str::<Value>(&text).map(|x| x["envelope_id"].is_string()).unwrap_or(false) {
+                                acks.fetch_add(1, Ordering::SeqCst);
+                            }
+                        }
+                        Some(Ok(_)) => {}
+                        _ => break,
+                    },
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,8 @@
+mod api;
+mod client;
+#[cfg(test)]
+mod mock;
+mod state;
+mod text;
+
+pub use client::Client;
@@ -0,0 +1,291 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use super::api::{self, User};
+use crate::message::{ChannelSnapshot, DeltaKind, MemberSnapshot, MembershipDelta, Message, Snapshot, TopicSnapshot};
+
+// messages remembered for threads and reactions to quote
+const RECENT_SIZE: usize = 1024;
+
+pub struct Conversation {
+    pub id: String,
+    pub name: String,
+    // user on the other side of direct message
+    pub peer: Option<String>,
+    pub is_member: bool,
+    pub topic: String,
+    // user ids
+    pub members: BTreeSet<String>,
+}
+
+impl From<api::Conversation> for Conversation {
+    fn from(conversation: api::Conversation) -> Self {
+        Self {
+            id: conversation.id,
+            name: conversation.name,
+            peer: if conversation.is_im { conversation.user } else { None },
+            is_member: conversation.is_member,
+            topic: conversation.topic.unwrap_or_default().value,
+            members: BTreeSet::new(),
+        }
+    }
+}
+
+// workspace as seen by the bouncer, channels are named `#name` and users by their name
+#[derive(Default)]
+pub struct State {
+    // our own user id
+    pub user_id: String,
+    // id to name
+    users: HashMap<String, String>,
+    conversations: HashMap<String, Conversation>,
+    // (conversation, ts) to text, oldest first
+    recent: VecDeque<((String, String), String)>,
+}
+
+impl State {
+    pub fn new(user_id: String) -> Self {
+        Self {
+            user_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn nickname(&self) -> &str {
+        self.user_name(&self.user_id)
+    }
+
+    // id itself for users we don't know
+    pub fn user_name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.users.get(id).map(|x| x.as_str()).unwrap_or(id)
+    }
+
+    pub fn user_id(&self, name: &str) -> Option<&str> {
+        self.users.iter().find(|(_, x)| x.as_str() == name).map(|(id, _)| id.as_str())
+    }
+
+    pub fn conversation(&self, id: &str) -> Option<&Conversation> {
+        self.conversations.get(id)
+    }
+
+    // `#name` of channel, none for direct messages
+    pub fn channel_name(&self, id: &str) -> Option<String> {
+        self.conversations.get(id).filter(|x| x.peer.is_none()).map(|x| format!("#{}", x.name))
+    }
+
+    pub fn channel_id(&self, channel: &str) -> Option<&str> {
+        let name = channel.strip_prefix('#')?;
+
+        self.conversations
+            .values()
+            .find(|x| x.peer.is_none() && x.name == name)
+            .map(|x| x.id.as_str())
+    }
+
+    // direct message with user, if opened already
+    pub fn direct_id(&self, user_id: &str) -> Option<&str> {
+        self.conversations
+            .values()
+            .find(|x| x.peer.as_deref() == Some(user_id))
+            .map(|x| x.id.as_str())
+    }
+
+    pub fn set_users(&mut self, users: Vec<User>) {
+        self.users = users.into_iter().map(|x| (x.id, x.name)).collect();
+    }
+
+    // old name if renamed
+    pub fn set_user(&mut self, user: User) -> Option<String> {
+        let name = user.name;
+
+        self.users.insert(user.id, name.clone()).filter(|x| *x != name)
+    }
+
+    pub fn set_conversation(&mut self, conversation: Conversation) {
+        self.conversations.insert(conversation.id.clone(), conversation);
+    }
+
+    pub fn rename(&mut self, id: &str, name: &str) {
+        if let Some(conversation) = self.conversations.get_mut(id) {
+            conversation.name = name.into();
+        }
+    }
+
+    pub fn set_topic(&mut self, id: &str, topic: &str) {
+        if let Some(conversation) = self.conversations.get_mut(id) {
+            conversation.topic = topic.into();
+        }
+    }
+
+    pub fn joined(&mut self, id: &str, user_id: &str) {
+        let me = user_id == self.user_id;
+
+        if let Some(conversation) = self.conversations.get_mut(id) {
+            conversation.members.insert(user_id.into());
+            conversation.is_member |= me;
+        }
+    }
+
+    pub fn parted(&mut self, id: &str, user_id: &str) {
+        let me = user_id == self.user_id;
+
+        if let Some(conversation) = self.conversations.get_mut(id) {
+            conversation.members.remove(user_id);
+            conversation.is_member &= !me;
+        }
+    }
+
+    pub fn remember(&mut self, id: &str, ts: &str, text: &str) {
+        let key = (id.to_owned(), ts.to_owned());
+
+        match self.recent.iter_mut().find(|(x, _)| *x == key) {
+            Some((_, x)) => *x = text.into(),
+            None => {
+                if self.recent.len() == RECENT_SIZE {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back((key, text.into()));
+            }
+        }
+    }
+
+    pub fn recall(&self, id: &str, ts: &str) -> Option<&str> {
+        self.recent.iter().rev().find(|((x, y), _)| x == id && y == ts).map(|(_, x)| x.as_str())
+    }
+
+    // chat or action in conversation, as private one for direct messages
+    pub fn chat(&self, id: &str, sender: &str, content: String, action: bool) -> Option<Message> {
+        let conversation = self.conversations.get(id)?;
+        let sender = sender.to_owned();
+
+        Some(match &conversation.peer {
+            Some(peer) => {
+                let peer = self.user_name(peer).to_owned();
+
+                if action {
+                    Message::PrivateAction { sender, peer, content }
+                } else {
+                    Message::PrivateChat { sender, peer, content }
+                }
+            }
+            None => {
+                let channel = format!("#{}", conversation.name);
+
+                if action {
+                    Message::Action { sender, channel, content }
+                } else {
+                    Message::Chat { sender, channel, content }
+                }
+            }
+        })
+    }
+
+    // join, topic and names of channel, as if joined on irc
+    pub fn join_messages(&self, id: &str) -> Vec<Message> {
+        let (channel, conversation) = match (self.channel_name(id), self.conversations.get(id)) {
+            (Some(channel), Some(conversation)) => (channel, conversation),
+            _ => return Vec::new(),
+        };
+        let sender = self.nickname().to_owned();
+
+        let mut result = vec![Message::JoinedChannel {
+            sender: sender.clone(),
+            channel: channel.clone(),
+        }];
+        if !conversation.topic.is_empty() {
+            result.push(Message::Topic {
+                sender,
+                channel: channel.clone(),
+                topic: conversation.topic.clone(),
+            });
+        }
+        result.push(Message::UsersList {
+            channel,
+            users: conversation.members.iter().map(|x| self.user_name(x).to_owned()).collect(),
+        });
+
+        result
+    }
+
+    // channels we're in, sorted by name
+    pub fn joined_ids(&self) -> Vec<String> {
+        let mut result = self
+            .conversations
+            .values()
+            .filter(|x| x.is_member && x.peer.is_none())
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+
+        result.into_iter().map(|x| x.id.clone()).collect()
+    }
+
+    // member changes told by message, once applied to state
+    pub fn deltas(&self, message: &Message) -> Vec<MembershipDelta> {
+        let delta = |channel: &str, kind, members| MembershipDelta {
+            channel: channel.to_owned(),
+            kind,
+            members,
+        };
+
+        match message {
+            Message::JoinedChannel { sender, channel } => vec![delta(channel, DeltaKind::Joined, vec![Self::member(sender)])],
+            Message::PartedChannel { sender, channel, .. } => vec![delta(channel, DeltaKind::Left, vec![Self::member(sender)])],
+            Message::UsersList { channel, users } => vec![delta(channel, DeltaKind::Reset, users.iter().map(|x| Self::member(x)).collect())],
+            Message::NickChanged { sender, nickname } => {
+                let id = self.user_id(nickname).unwrap_or_default();
+
+                self.joined_ids()
+                    .iter()
+                    .filter(|x| self.conversations[*x].members.contains(id))
+                    .filter_map(|x| self.channel_name(x))
+                    .flat_map(|channel| {
+                        vec![
+                            delta(&channel, DeltaKind::Left, vec![Self::member(sender)]),
+                            delta(&channel, DeltaKind::Joined, vec![Self::member(nickname)]),
+                        ]
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // slack has no modes, hostmasks or away messages
+    fn member(name: &str) -> MemberSnapshot {
+        MemberSnapshot {
+            nickname: name.to_owned(),
+            prefixes: String::new(),
+            hostmask: None,
+            away: None,
+        }
+    }
+
+    pub fn snapshot(&self, network: &str) -> Snapshot {
+        let channels = self
+            .joined_ids()
+            .iter()
+            .filter_map(|id| {
+                let conversation = self.conversations.get(id)?;
+
+                Some(ChannelSnapshot {
+                    name: self.channel_name(id)?,
+                    topic: Some(TopicSnapshot {
+                        text: conversation.topic.clone(),
+                        setter: None,
+                        time: None,
+                    })
+                    .filter(|x| !x.text.is_empty()),
+                    modes: String::new(),
+                    members: conversation.members.iter().map(|x| Self::member(self.user_name(x))).collect(),
+                })
+            })
+            .collect();
+
+        Snapshot {
+            network: network.into(),
+            nickname: self.nickname().to_owned(),
+            isupport: vec!["CHANTYPES=#".into(), "CASEMAPPING=ascii".into(), format!("NETWORK={}", network)],
+            channels,
+            queued: 0,
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::state::State;
+use crate::irc::format::{self, Span, Style};
+
+// flag of style a marker sets
+type Flag = fn(&mut Style) -> &mut bool;
+
+// mrkdwn markers, in the order they're nested when written
+const MARKERS: [(char, Flag); 3] = [('*', |x| &mut x.bold), ('_', |x| &mut x.italic), ('~', |x| &mut x.strikethrough)];
+
+// slack mrkdwn into text with irc formatting codes, with mentions and links resolved
+pub fn from_slack(text: &str, state: &State) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut spans = Vec::new();
+    parse(&chars, Style::default(), state, &mut spans);
+
+    for span in &mut spans {
+        span.text = unescape(&span.text);
+    }
+
+    format::to_irc(&spans)
+}
+
+// text with irc formatting codes into slack mrkdwn, `@name` and `#channel` into mentions
+pub fn to_slack(text: &str, state: &State) -> String {
+    // colors can't be shown, so spans differing in color only are merged
+    let mut runs = Vec::<Span>::new();
+    for span in format::parse(text) {
+        let style = Style {
+            reverse: false,
+            foreground: None,
+            background: None,
+            ..span.style
+        };
+
+        match runs.last_mut() {
+            Some(run) if run.style == style => run.text.push_str(&span.text),
+            _ => runs.push(Span { text: span.text, style }),
+        }
+    }
+
+    let mut result = String::new();
+    for (i, run) in runs.iter().enumerate() {
+        let mut text = escape(&run.text);
+        if !run.style.monospace {
+            text = mentions(&text, i == 0, state);
+        }
+
+        // markers have to be next to the text they enclose
+        let start = text.len() - text.trim_start().len();
+        let end = text.trim_end().len();
+        if start >= end {
+            result.push_str(&text);
+
+            continue;
+        }
+
+        let mut style = run.style;
+        let mut markers = String::new();
+        if style.monospace {
+            markers.push('`');
+        }
+        for (marker, flag) in MARKERS.iter() {
+            if *flag(&mut style) {
+                markers.push(*marker);
+            }
+        }
+
+        result.push_str(&text[..start]);
+        result.push_str(&markers);
+        result.push_str(&text[start..end]);
+        result.extend(markers.chars().rev());
+        result.push_str(&text[end..]);
+    }
+
+    result
+}
+
+fn parse(chars: &[char], style: Style, state: &State, result: &mut Vec<Span>) {
+    let mut i = 0;
+    while i < chars.len() {
+        let x = chars[i];
+
+        if x == '<' {
+            if let Some(end) = (i + 1..chars.len()).find(|&j| chars[j] == '>') {
+                push(result, &entity(&chars[i + 1..end].iter().collect::<String>(), state), style);
+                i = end + 1;
+
+                continue;
+            }
+        }
+
+        // code, with nothing formatted inside
+        if x == '`' {
+            let len = if chars[i..].starts_with(&['`'; 3]) { 3 } else { 1 };
+            let marker = &chars[i..i + len];
+
+            if let Some(end) = (i + len + 1..chars.len()).find(|&j| chars[j..].starts_with(marker)) {
+                push(
+                    result,
+                    &chars[i + len..end].iter().collect::<String>(),
+                    Style { monospace: true, ..style },
+                );
+                i = end + len;
+
+                continue;
+            }
+        }
+
+        // markers start after a word and end before one, on the same line
+        if let Some((_, flag)) = MARKERS.iter().find(|(marker, _)| *marker == x) {
+            let opens = (i == 0 || !chars[i - 1].is_alphanumeric()) && chars.get(i + 1).map(|y| !y.is_whitespace()).unwrap_or(false);
+            let end = (i + 2..chars.len())
+                .take_while(|&j| chars[j] != '\n')
+                .find(|&j| chars[j] == x && !chars[j - 1].is_whitespace() && chars.get(j + 1).map(|y| !y.is_alphanumeric()).unwrap_or(true));
+
+            if let (true, Some(end)) = (opens, end) {
+                let mut inner = style;
+                *flag(&mut inner) = true;
+                parse(&chars[i + 1..end], inner, state, result);
+                i = end + 1;
+
+                continue;
+            }
+        }
+
+        push(result, &x.to_string(), style);
+        i += 1;
+    }
+}
+
+fn push(result: &mut Vec<Span>, text: &str, style: Style) {
+    match result.last_mut() {
+        Some(span) if span.style == style => span.text.push_str(text),
+        _ => result.push(Span { text: text.into(), style }),
+    }
+}
+
+// `<@U1>`, `<#C1|general>`, `<!here>` or `<url|label>`
+fn entity(entity: &str, state: &State) -> String {
+    let (target, label) = match entity.split_once('|') {
+        Some((target, label)) => (target, Some(label)),
+        None => (entity, None),
+    };
+
+    if let Some(id) = target.strip_prefix('@') {
+        format!("@{}", state.user_name(id))
+    } else if let Some(id) = target.strip_prefix('#') {
+        state
+            .channel_name(id)
+            .or_else(|| label.map(|x| format!("#{}", x)))
+            .unwrap_or_else(|| target.into())
+    } else if let Some(special) = target.strip_prefix('!') {
+        // user groups come with their handle as label
+        label.map(|x| x.to_owned()).unwrap_or_else(|| format!("@{}", special))
+    } else {
+        let target = target.strip_prefix("mailto:").unwrap_or(target);
+
+        match label {
+            Some(label) if label != target => format!("{} ({})", label, target),
+            _ => target.into(),
+        }
+    }
+}
+
+// `@name` of known users and `#channel` we know into mentions, and `name:` at the start as irc clients address
+fn mentions(text: &str, first: bool, state: &State) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    if first {
+        let word = rest.find([':', ',']).map(|x| &rest[..x]);
+        if let Some(id) = word.and_then(|x| state.user_id(x)) {
+            result.push_str(&format!("<@{}>", id));
+            rest = &rest[word.unwrap().len()..];
+        }
+    }
+
+    while let Some(x) = rest.chars().next() {
+        let boundary = result.chars().last().map(|y| !y.is_alphanumeric()).unwrap_or(true);
+        let name = || {
+            let len = rest[1..]
+                .find(|y: char| !(y.is_alphanumeric() || "._-".contains(y)))
+                .map(|y| y + 1)
+                .unwrap_or(rest.len());
+
+            rest[..len].trim_end_matches(['.', '-'])
+        };
+
+        let mention = match x {
+            '@' if boundary => Some(name()).and_then(|name| Some((name, format!("<@{}>", state.user_id(&name[1..])?)))),
+            '#' if boundary => Some(name()).and_then(|name| Some((name, format!("<#{}>", state.channel_id(name)?)))),
+            _ => None,
+        };
+
+        match mention {
+            Some((name, mention)) => {
+                result.push_str(&mention);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                result.push(x);
+                rest = &rest[x.len_utf8()..];
+            }
+        }
+    }
+
+    result
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::slack::{api::User, state::Conversation};
+
+    fn state() -> State {
+        let mut state = State::default();
+        state.set_users(vec![
+            User {
+                id: "U1".into(),
+                name: "alice".into(),
+            },
+            User {
+                id: "U2".into(),
+                name: "bob".into(),
+            },
+        ]);
+        state.set_conversation(Conversation {
+            id: "C1".into(),
+            name: "general".into(),
+            peer: None,
+            is_member: true,
+            topic: String::new(),
+            members: Default::default(),
+        });
+
+        state
+    }
+
+    #[test]
+    fn test_from_slack() {
+        let state = state();
+
+        assert_eq!(from_slack("*bold* _italic_ ~strike~", &state), "\x02bold\x0f \x1ditalic\x0f \x1estrike");
+        assert_eq!(from_slack("*_both_*", &state), "\x02\x1dboth");
+        assert_eq!(from_slack("`*code*` ```block```", &state), "\x11*code*\x0f \x11block");
+        // markers inside words or around spaces aren't formatting
+        assert_eq!(from_slack("snake_case_name 2 * 3 * 4", &state), "snake_case_name 2 * 3 * 4");
+        assert_eq!(from_slack("hi <@U1> in <#C1|general> <!here>", &state), "hi @alice in #general @here");
+        assert_eq!(
+            from_slack("<https://a.com/x_y_z?a=1&amp;b=2|site> <https://b.com>", &state),
+            "site (https://a.com/x_y_z?a=1&b=2) https://b.com"
+        );
+        assert_eq!(from_slack("a &lt;b&gt; &amp; c", &state), "a <b> & c");
+    }
+
+    #[test]
+    fn test_to_slack() {
+        let state = state();
+
+        assert_eq!(to_slack("\x02bold\x02 \x1d\x02both \x0f\x0304red", &state), "*bold* *_both_* red");
+        assert_eq!(to_slack("\x11a <b> & c\x11", &state), "`a &lt;b&gt; &amp; c`");
+        assert_eq!(to_slack("hi @alice and @carol in #general.", &state), "hi <@U1> and @carol in <#C1>.");
+        assert_eq!(to_slack("bob: hello", &state), "<@U2>: hello");
+        assert_eq!(to_slack("mail a@alice", &state), "mail a@alice");
+    }
+}
@@ -4,10 +4,12 @@ use async_trait::async_trait;
 use futures::stream::BoxStream;
 use tokio::io::Result;
 
-use crate::message::Message;
+use crate::message::{Envelope, Message, Origin, Snapshot};
 
 #[async_trait]
 pub trait Source: Sync + Send {
-    async fn stream(&self) -> BoxStream<Message>;
-    async fn send_message(&self, message: &Message) -> Result<()>;
+    async fn stream<'a>(&'a self) -> BoxStream<'a, Envelope>;
+    // replies are routed back to origin if given, returns local echo if upstream won't echo
+    async fn send_message(&self, message: &Message, origin: Option<Origin>) -> Result<Option<Envelope>>;
+    async fn snapshot(&self) -> Snapshot;
 }